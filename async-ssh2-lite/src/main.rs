@@ -1,72 +1,142 @@
 //! Local port forwarding (`ssh -L`) built on `async-ssh2-lite` (libssh2).
 //!
-//! Each accepted TCP connection is spliced byte-for-byte onto a
+//! Each accepted TCP or Unix-socket connection is spliced byte-for-byte onto a
 //! `direct-tcpip` SSH channel in *both* directions concurrently. There is no
 //! request parsing, no "a short read means the request ended" heuristic and no
 //! EOF-after-the-first-response hack, so HTTP keep-alive, pipelining, request
 //! bodies larger than one read and arbitrarily large responses all work.
 //!
+//! With `--exec-fallback`, servers that prohibit `direct-tcpip` are reached by
+//! running `nc` (or `socat`, or bash) on the server for each connection.
+//!
 //! Caveat inherent to libssh2: every channel shares one session lock, so many
 //! concurrent transfers are serialized at the transport layer. That is a
-//! throughput limit, not a correctness one.
+//! throughput limit, not a correctness one; `--sessions` spreads connections
+//! over several sessions to lift it.
 
 use std::{
     io::Error,
-    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    net::{IpAddr, SocketAddr},
+    ops::Deref,
     path::Path,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
-use async_ssh2_lite::AsyncSession;
-use common_port_forward::{expand_home_dir, get_args, setup_tracing};
+use async_ssh2_lite::{
+    ssh2::{DisconnectCode, ExtendedData, MethodType},
+    AsyncChannel, AsyncSession, SessionConfiguration,
+};
+use common_port_forward::{
+    activation::{self, Activated, Inherited},
+    daemon::{self, Readiness},
+    exec_fallback::{self, VIA_EXEC},
+    expand_home_dir,
+    forward::{self, Direction, Endpoint, ForwardSpec},
+    get_args,
+    limits::{self, ForwardLimiter, Limiter, Slot},
+    metrics,
+    proxy_protocol::ProxyProtocol,
+    rate_limit::Throttled,
+    setup_tracing,
+    shutdown::{self, Signals},
+    stats::{ConnectionGuard, ErrorKind, Metered, PendingGuard, Stats},
+    transport::Transport,
+    unix_socket::{self, SocketFile},
+    Arguments,
+};
 use tokio::{
-    io::{copy, AsyncWriteExt as _},
-    net::{TcpListener, TcpStream},
+    io::{copy, AsyncRead, AsyncWrite, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
     select,
+    signal::unix::{signal, SignalKind},
+    sync::Notify,
+    task::JoinSet,
+    time::sleep,
 };
-use tracing::{debug, error, instrument, Instrument};
+use tracing::{debug, error, info, instrument, warn, Instrument};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 use uuid::Uuid;
 
-#[derive(Debug)]
+/// Seconds between SSH keepalives, matching the ssh2-rs backend.
+const KEEPALIVE_INTERVAL: u32 = 30;
+/// Pause after a failed `accept` (e.g. `EMFILE`) before trying again, so a
+/// persistent failure does not spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug)]
 struct SSHKeyPair<'a> {
     public_key: Option<&'a Path>,
     private_key: Option<&'a Path>,
 }
 
-fn make_socket_address<A: ToSocketAddrs>(address: A) -> SocketAddr {
-    address.to_socket_addrs().unwrap().next().unwrap()
-}
-
 /// Splice one accepted local connection onto a fresh `direct-tcpip` channel.
 ///
-/// Both directions run concurrently on the same task (`try_join!`), which keeps
-/// all libssh2 calls for this connection on one thread while still allowing
-/// full-duplex traffic. When the local side reaches EOF we send channel EOF so
-/// the remote peer sees the half-close; when the remote side reaches EOF we
-/// shut down the write half of the local socket.
-#[instrument(skip(session, stream), err)]
+/// `slot` holds the connection's place under `--max-connections` and friends,
+/// and `session` its share of that session's load, until it closes. `pending`
+/// counts it for `--drain-timeout` until its channel is open.
+#[instrument(skip(spec, session, options, pending, stream, slot), fields(forward = %spec), err)]
 async fn handle_req(
-    remote_port: u16,
-    session: Arc<AsyncSession<TcpStream>>,
-    stream: TcpStream,
+    spec: Arc<ForwardSpec>,
+    session: Lease,
+    options: Arc<ChannelOptions>,
+    pending: PendingGuard,
+    stream: Local,
     unique_id: String,
+    mut slot: Slot,
 ) -> std::io::Result<()> {
-    // Connect to the literal loopback address rather than "localhost". sshd
-    // resolves this name itself, and `localhost` resolves to ::1 before
-    // 127.0.0.1 on many hosts, so a target bound only to IPv4 is reached only
-    // via sshd's fallback from the refused ::1 attempt. Naming the address we
-    // actually mean removes that dependency. (Hardening, not a fix for any
-    // observed failure -- sshd's fallback does work here.)
-    let mut channel = session
-        .channel_direct_tcpip("127.0.0.1", remote_port, None)
+    let addrs = stream.addrs();
+    let peer = addrs.map_or_else(|| spec.listen.to_string(), |(peer, _)| peer.to_string());
+    let (mut channel, via) = open_channel(&session, &spec.target, addrs, &options)
         .await
-        .map_err(|e| Error::other(format!("channel_direct_tcpip: {e}")))?;
+        .inspect_err(|_| pending.stats().record_error(ErrorKind::ChannelOpen))?;
+    let guard = Arc::new(pending.open(spec.to_string(), peer.as_str(), via));
+    slot.opened();
+    debug!("connected {peer} to {} via {via}", spec.target);
+    if let Some(proxy) = options.proxy_protocol {
+        channel
+            .write_all(&proxy.header(addrs))
+            .await
+            .inspect_err(|_| guard.record_error(ErrorKind::Transfer))?;
+    }
+
+    match stream {
+        Local::Tcp(stream) => splice(guard, stream, channel, slot).await,
+        Local::Unix(stream) => splice(guard, stream, channel, slot).await,
+    }
+}
 
+/// Copy between a local stream and its channel until both sides are done.
+///
+/// Both directions run concurrently on the same task (`try_join!`), which keeps
+/// all libssh2 calls for this connection on one thread while still allowing
+/// full-duplex traffic. When the local side reaches EOF we send channel EOF so
+/// the remote peer sees the half-close; when the remote side reaches EOF we
+/// shut down the write half of the local socket. After the idle timeout of
+/// `slot` without traffic both get EOF and the channel is closed, and reads on
+/// either side are throttled by its rate limits.
+async fn splice<S>(
+    guard: Arc<ConnectionGuard>,
+    stream: S,
+    mut channel: AsyncChannel<TcpStream>,
+    slot: Slot,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let throttles = slot.throttles();
     // `AsyncChannel::stream(0)` hands out an independent reader for the same
     // channel, so the two copy futures below never need `&mut` at the same time.
-    let mut channel_reader = channel.stream(0);
-    let (mut local_reader, mut local_writer) = stream.into_split();
+    let mut channel_reader = Throttled::new(channel.stream(0), throttles.down.clone());
+    let (local_reader, local_writer) = tokio::io::split(stream);
+    let mut local_reader = Throttled::new(
+        Metered::new(local_reader, Arc::clone(&guard)),
+        throttles.up.clone(),
+    );
+    let mut local_writer = Metered::new(local_writer, Arc::clone(&guard));
 
     let local_to_remote = async {
         let n = copy(&mut local_reader, &mut channel).await?;
@@ -84,22 +154,147 @@ async fn handle_req(
         Ok::<u64, Error>(n)
     };
 
-    let (up, down) = tokio::try_join!(local_to_remote, remote_to_local)?;
-    debug!("forwarded {up} bytes up, {down} bytes down");
+    let copied = tokio::select! {
+        copied = async { tokio::try_join!(local_to_remote, remote_to_local) } => Some(copied),
+        () = guard.idle(slot.idle_timeout()) => None,
+    };
+    if let Some(copied) = copied {
+        let (up, down) = copied.inspect_err(|_| guard.record_error(ErrorKind::Transfer))?;
+        debug!("forwarded {up} bytes up, {down} bytes down");
+    } else {
+        debug!(
+            "connection idle for {:?}, closing",
+            guard.connection().idle_for()
+        );
+        let _ = channel.send_eof().await;
+        let _ = local_writer.shutdown().await;
+    }
 
     let _ = channel.close().await;
     Ok(())
 }
 
-#[instrument]
+/// The `--sessions` connections are spread over.
+struct SessionPool {
+    sessions: Vec<Arc<PooledSession>>,
+}
+
+struct PooledSession {
+    session: AsyncSession<TcpStream>,
+    /// Connections currently using the session.
+    connections: AtomicUsize,
+}
+
+impl SessionPool {
+    /// The session with the fewest connections, for one more.
+    fn lease(&self) -> Lease {
+        let pooled = self
+            .sessions
+            .iter()
+            .min_by_key(|pooled| pooled.connections.load(Ordering::Relaxed))
+            .expect("the pool has at least one session");
+        pooled.connections.fetch_add(1, Ordering::Relaxed);
+        Lease(Arc::clone(pooled))
+    }
+}
+
+/// A connection's use of a pooled session, given back when dropped.
+struct Lease(Arc<PooledSession>);
+
+impl Deref for Lease {
+    type Target = AsyncSession<TcpStream>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0.session
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How connections reach their targets, shared by every connection.
+#[derive(Debug)]
+struct ChannelOptions {
+    /// `--exec-fallback`
+    exec_fallback: bool,
+    /// Set once the server has refused a `direct-tcpip` channel as
+    /// prohibited, so later connections go straight to `exec`.
+    prohibited: AtomicBool,
+    /// Header sent ahead of each connection's data, if any.
+    proxy_protocol: Option<ProxyProtocol>,
+}
+
+/// libssh2 only tells why a channel open failed in the error message.
+fn is_prohibited(err: &async_ssh2_lite::Error) -> bool {
+    err.as_ssh2()
+        .is_some_and(|e| e.message().ends_with("(administratively prohibited)"))
+}
+
+/// Open a `direct-tcpip` channel to `target` on behalf of the client at
+/// `addrs`, or, once the server has prohibited those and `--exec-fallback` is
+/// set, a `session` channel running a command that connects to it. Returns the
+/// channel and how it reaches the target.
+async fn open_channel(
+    session: &AsyncSession<TcpStream>,
+    target: &Endpoint,
+    addrs: Option<(SocketAddr, SocketAddr)>,
+    options: &ChannelOptions,
+) -> std::io::Result<(AsyncChannel<TcpStream>, &'static str)> {
+    if !options.prohibited.load(Ordering::Relaxed) {
+        let tcp = target.as_tcp().expect("Unix targets are rejected in main");
+        let (originator, originator_port) = forward::originator(addrs.map(|(peer, _)| peer));
+        match session
+            .channel_direct_tcpip(&tcp.host, tcp.port, Some((&originator, originator_port)))
+            .await
+        {
+            Ok(channel) => return Ok((channel, "direct-tcpip")),
+            Err(e) if options.exec_fallback && is_prohibited(&e) => {
+                warn!("direct-tcpip is prohibited by the server, using exec from now on");
+                options.prohibited.store(true, Ordering::Relaxed);
+            }
+            Err(e) if is_prohibited(&e) => {
+                return Err(Error::other(format!(
+                    "channel_direct_tcpip: {e} (see --exec-fallback)"
+                )));
+            }
+            Err(e) => return Err(Error::other(format!("channel_direct_tcpip: {e}"))),
+        }
+    }
+
+    let mut channel = session
+        .channel_session()
+        .await
+        .map_err(|e| Error::other(format!("channel_session: {e}")))?;
+    // Nothing reads the command's stderr; unread, it would stall the channel.
+    channel
+        .handle_extended_data(ExtendedData::Ignore)
+        .await
+        .map_err(|e| Error::other(format!("handle_extended_data: {e}")))?;
+    channel
+        .exec(&exec_fallback::connect_command(target))
+        .await
+        .map_err(|e| Error::other(format!("exec: {e}")))?;
+    Ok((channel, VIA_EXEC))
+}
+
+#[instrument(skip(transport))]
 async fn create_ssh_session(
     username: &str,
     remote_address: SocketAddr,
     key_pair: SSHKeyPair<'_>,
+    transport: &Transport,
 ) -> Result<AsyncSession<TcpStream>, Error> {
     let stream = TcpStream::connect(remote_address).await?;
-    let mut session = AsyncSession::new(stream, None)?;
+    let mut config = SessionConfiguration::new();
+    config.set_keepalive(true, KEEPALIVE_INTERVAL);
+    config.set_compress(transport.compression);
+    let mut session = AsyncSession::new(stream, Some(config))?;
+    prefer_methods(&session, transport).await?;
     session.handshake().await?;
+    log_methods(&session);
     session
         .userauth_pubkey_file(
             username,
@@ -119,25 +314,200 @@ async fn create_ssh_session(
     }
 }
 
-#[instrument(skip(ssh_session))]
-async fn local_port_forward(
-    local_listener: TcpListener,
-    remote_port: u16,
-    ssh_session: AsyncSession<TcpStream>,
-) -> std::io::Result<()> {
-    let ssh_session = Arc::from(ssh_session);
+/// Apply `--kex-algorithms`, `--host-key-algorithms`, `--ciphers` and
+/// `--macs`; libssh2 takes the latter two once per direction.
+///
+/// ## Errors
+/// if libssh2 supports none of the algorithms in a list
+async fn prefer_methods(
+    session: &AsyncSession<TcpStream>,
+    transport: &Transport,
+) -> Result<(), Error> {
+    for (option, names, method_types) in [
+        (
+            "--kex-algorithms",
+            &transport.kex_algorithms,
+            &[MethodType::Kex][..],
+        ),
+        (
+            "--host-key-algorithms",
+            &transport.host_key_algorithms,
+            &[MethodType::HostKey],
+        ),
+        (
+            "--ciphers",
+            &transport.ciphers,
+            &[MethodType::CryptCs, MethodType::CryptSc],
+        ),
+        (
+            "--macs",
+            &transport.macs,
+            &[MethodType::MacCs, MethodType::MacSc],
+        ),
+    ] {
+        if names.is_empty() {
+            continue;
+        }
+        let prefs = names.join(",");
+        for &method_type in method_types {
+            session
+                .method_pref(method_type, &prefs)
+                .await
+                .map_err(|e| Error::other(format!("{option} {prefs}: {e}")))?;
+        }
+    }
+    Ok(())
+}
+
+/// Log what the handshake settled on, so benchmark runs record it.
+fn log_methods(session: &AsyncSession<TcpStream>) {
+    let method = |method_type| session.methods(method_type).unwrap_or("?");
+    info!(
+        "negotiated kex {}, host key {}, ciphers {}/{}, MACs {}/{}, compression {}/{}",
+        method(MethodType::Kex),
+        method(MethodType::HostKey),
+        method(MethodType::CryptCs),
+        method(MethodType::CryptSc),
+        method(MethodType::MacCs),
+        method(MethodType::MacSc),
+        method(MethodType::CompCs),
+        method(MethodType::CompSc),
+    );
+}
+
+/// libssh2 only sends the keepalives configured on a session when asked to,
+/// so this drives them on every session for as long as the tunnel runs.
+async fn send_keepalives(pool: Arc<SessionPool>, stats: Arc<Stats>) {
+    loop {
+        let mut next = KEEPALIVE_INTERVAL;
+        for pooled in &pool.sessions {
+            match pooled.session.keepalive_send().await {
+                Ok(secs) => next = next.min(secs.max(1)),
+                Err(e) => {
+                    warn!("sending keepalive failed: {e}");
+                    stats.record_keepalive_failure();
+                }
+            }
+        }
+        sleep(Duration::from_secs(next.into())).await;
+    }
+}
+
+/// What a local forward listens on. A Unix socket's file is removed when the
+/// listener is dropped.
+enum Listener {
+    Tcp(TcpListener),
+    Unix {
+        listener: UnixListener,
+        /// `None` for a socket systemd passed in, whose file is its own.
+        _file: Option<SocketFile>,
+    },
+}
+
+/// An accepted local client.
+enum Local {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
 
+impl Local {
+    /// The client's address and the local address it connected to;
+    /// Unix-socket clients have neither.
+    fn addrs(&self) -> Option<(SocketAddr, SocketAddr)> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().ok().zip(stream.local_addr().ok()),
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl Listener {
+    /// Take the socket systemd passed in for `spec`, or bind one.
+    fn bind(
+        spec: &ForwardSpec,
+        socket_mode: u32,
+        activated: &mut Activated,
+    ) -> std::io::Result<Self> {
+        match (activated.take(&spec.listen), &spec.listen) {
+            (Some(Inherited::Tcp(listener)), _) => Ok(Self::Tcp(TcpListener::from_std(listener)?)),
+            (Some(Inherited::Unix(listener)), _) => Ok(Self::Unix {
+                listener: UnixListener::from_std(listener)?,
+                _file: None,
+            }),
+            (None, Endpoint::Tcp(listen)) => {
+                let listener = std::net::TcpListener::bind((listen.host.as_str(), listen.port))?;
+                listener.set_nonblocking(true)?;
+                Ok(Self::Tcp(TcpListener::from_std(listener)?))
+            }
+            (None, Endpoint::Unix(path)) => {
+                let (listener, file) = unix_socket::bind(path, socket_mode)?;
+                listener.set_nonblocking(true)?;
+                Ok(Self::Unix {
+                    listener: UnixListener::from_std(listener)?,
+                    _file: Some(file),
+                })
+            }
+        }
+    }
+
+    /// Accept one client, named by its address or, for Unix-socket clients,
+    /// which have none, by the socket path.
+    async fn accept(&self, spec: &ForwardSpec) -> std::io::Result<(Local, String)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                let _ = stream.set_nodelay(true);
+                Ok((Local::Tcp(stream), peer.to_string()))
+            }
+            Self::Unix { listener, .. } => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Local::Unix(stream), spec.listen.to_string()))
+            }
+        }
+    }
+}
+
+#[instrument(skip(local_listener, pool, options, limits, stats), fields(forward = %spec))]
+async fn local_port_forward(
+    local_listener: Listener,
+    spec: Arc<ForwardSpec>,
+    pool: Arc<SessionPool>,
+    options: Arc<ChannelOptions>,
+    limits: ForwardLimiter,
+    stats: Arc<Stats>,
+) {
     loop {
-        let (stream, peer) = local_listener.accept().await?;
-        let _ = stream.set_nodelay(true);
+        let slot = limits.wait().await;
+        let (stream, peer) = match local_listener.accept(&spec).await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("accepting on {}: {e}", spec.listen);
+                stats.record_error(ErrorKind::Accept);
+                sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let pending = stats.pending_connection();
+        let Some(slot) = limits.admit(slot).await else {
+            debug!("connection limit reached, resetting {peer}");
+            stats.record_error(ErrorKind::Limit);
+            if let Local::Tcp(stream) = &stream {
+                let _ = limits::reset_on_close(stream);
+            }
+            continue;
+        };
 
         let unique_id = Uuid::new_v4().to_string();
-        let cloned_session = Arc::clone(&ssh_session);
+        let spec = Arc::clone(&spec);
+        let session = pool.lease();
+        let options = Arc::clone(&options);
         let span = tracing::debug_span!("handle_req", unique_id = %unique_id, peer = %peer);
 
         tokio::spawn(
             async move {
-                if let Err(e) = handle_req(remote_port, cloned_session, stream, unique_id).await {
+                let res =
+                    handle_req(spec, session, options, pending, stream, unique_id, slot).await;
+                if let Err(e) = res {
                     error!("connection from {peer} failed: {e}");
                 }
             }
@@ -167,10 +537,76 @@ fn init_tracing() {
         .init();
 }
 
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
-    init_tracing();
+fn main() -> std::io::Result<()> {
     let args = get_args();
+    // Before the runtime's threads, and any the tracing setup starts; systemd
+    // addresses its sockets to the process it started, not a forked one.
+    let activated = Activated::from_env(&args.forwards())?;
+    let readiness = Readiness::start(&args)?;
+    init_tracing();
+
+    tokio::runtime::Runtime::new()?.block_on(tunnel(args, activated, readiness))
+}
+
+async fn tunnel(
+    args: Arguments,
+    mut activated: Activated,
+    mut readiness: Readiness,
+) -> std::io::Result<()> {
+    if args.stdio.is_some() {
+        return Err(Error::other(
+            "--stdio is only supported by the russh backend",
+        ));
+    }
+    if !args.udp_forwards.is_empty() {
+        return Err(Error::other(
+            "UDP forwards (-U) are only supported by the russh backend",
+        ));
+    }
+    let specs = args.forwards();
+    if specs.is_empty() {
+        return Err(Error::other(
+            "nothing to forward: pass --local-port/--remote-port or -L",
+        ));
+    }
+    if specs.iter().any(|spec| spec.direction == Direction::Remote) {
+        return Err(Error::other(
+            "remote forwards (-R) are only supported by the russh backend",
+        ));
+    }
+    if specs.iter().any(|spec| spec.target.as_tcp().is_none()) {
+        return Err(Error::other(
+            "Unix socket targets are only supported by the russh backend",
+        ));
+    }
+    if args.control_path.is_some() {
+        return Err(Error::other(
+            "the control socket is only supported by the russh backend",
+        ));
+    }
+    if let Some(option) = args.transport().russh_only() {
+        return Err(Error::other(format!(
+            "{option} is only supported by the russh backend"
+        )));
+    }
+    if args.lazy || args.idle_disconnect.is_some() {
+        return Err(Error::other(
+            "--lazy is only supported by the russh backend",
+        ));
+    }
+    if !args.priorities.is_empty() {
+        return Err(Error::other(
+            "--priority is only supported by the ssh2-rs backend",
+        ));
+    }
+    // Watching an idle channel here means polling the session every
+    // millisecond, and a channel whose data another task took off the socket
+    // is not woken for it.
+    if args.preopen_channels > 0 {
+        return Err(Error::other(
+            "--preopen-channels is only supported by the russh and ssh2-rs backends",
+        ));
+    }
 
     let remote_address = SocketAddr::new(IpAddr::V4(args.ip), 22);
 
@@ -187,23 +623,95 @@ async fn main() -> std::io::Result<()> {
         private_key: private_key.as_ref().map(AsRef::as_ref),
     };
 
-    let session = create_ssh_session(&args.user, remote_address, key_pair).await?;
+    let stats = Stats::new();
+    if let Some(addr) = args.metrics_listen {
+        metrics::spawn(addr, Arc::clone(&stats))?;
+    }
+
+    let transport = args.transport();
+    let mut sessions = Vec::with_capacity(args.sessions.get());
+    for _ in 0..args.sessions.get() {
+        sessions.push(Arc::new(PooledSession {
+            session: create_ssh_session(&args.user, remote_address, key_pair, &transport).await?,
+            connections: AtomicUsize::new(0),
+        }));
+    }
+    if sessions.len() > 1 {
+        info!("opened {} sessions", sessions.len());
+    }
+    let pool = Arc::new(SessionPool { sessions });
 
-    let local_address = make_socket_address(("127.0.0.1", args.local_port));
-    let local_listener = TcpListener::bind(local_address).await.map_err(|e| {
-        error!("error binding to local port {}: {e}", args.local_port);
-        e
-    })?;
+    let options = Arc::new(ChannelOptions {
+        exec_fallback: args.exec_fallback,
+        prohibited: AtomicBool::new(false),
+        proxy_protocol: args.proxy_protocol,
+    });
 
-    debug!("listening on {}", local_listener.local_addr()?);
+    let limiter = Limiter::new(args.limits());
+    let mut forwards = JoinSet::new();
+    for spec in specs {
+        let local_listener =
+            Listener::bind(&spec, args.socket_mode, &mut activated).map_err(|e| {
+                error!("error binding to {}: {e}", spec.listen);
+                e
+            })?;
 
+        debug!("listening on {} -> {}", spec.listen, spec.target);
+
+        forwards.spawn(local_port_forward(
+            local_listener,
+            Arc::new(spec),
+            Arc::clone(&pool),
+            Arc::clone(&options),
+            limiter.forward(),
+            Arc::clone(&stats),
+        ));
+    }
+
+    let idle = Arc::new(Notify::new());
+    if let Some(idle_time) = args.exit_idle_time {
+        let idle = Arc::clone(&idle);
+        activation::exit_when_idle(Arc::clone(&stats), idle_time, move || idle.notify_one())?;
+    }
+
+    let mut signals = Signals::new()?;
+    if args.profile.is_some() {
+        // SIGHUP asks for the config file to be read again, which only the
+        // russh backend does; unhandled, it would end the tunnel.
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                warn!(
+                    "SIGHUP ignored: reloading the config file is only supported by the russh \
+                     backend"
+                );
+            }
+        });
+    }
+    readiness.ready()?;
     select! {
-        res = local_port_forward(local_listener, args.remote_port, session) => res?,
-        res = tokio::signal::ctrl_c() => {
-            res?;
-            debug!("ctrl-c received, shutting down");
+        Some(res) = forwards.join_next() => res.map_err(Error::other)?,
+        () = send_keepalives(Arc::clone(&pool), Arc::clone(&stats)) => {},
+        name = signals.recv() => info!("{name} received, shutting down"),
+        () = idle.notified() => {},
+    }
+    signals.exit_on_next();
+    daemon::notify_systemd("STOPPING=1");
+
+    // Dropping the accept loops closes the listeners; the connection tasks
+    // they spawned keep running.
+    forwards.abort_all();
+    shutdown::drain(&stats, args.drain_timeout).await;
+    for pooled in &pool.sessions {
+        if let Err(e) = pooled
+            .session
+            .disconnect(Some(DisconnectCode::ByApplication), "shutting down", None)
+            .await
+        {
+            error!("error closing session: {e}");
         }
     }
 
+    stats.log_summary();
     Ok(())
 }
@@ -0,0 +1,296 @@
+//! systemd socket activation: listening sockets passed in by the service
+//! manager (`LISTEN_FDS`) instead of bound by the tunnel.
+//!
+//! Each socket is matched to the `-L` forward it serves by its
+//! `FileDescriptorName=`, written like a `--priority` LISTEN (a port or a
+//! socket path, since systemd does not allow colons in names), or else by the
+//! address it is bound to. Forwards without a socket bind their own as usual.
+//!
+//! systemd starts the tunnel on the first connection to one of its sockets,
+//! so that is when the SSH session is made, and with `--exit-idle-time` the
+//! tunnel exits once idle, leaving systemd to start it again on the next one.
+
+use std::{
+    env,
+    io::{self, Error, ErrorKind},
+    net::{IpAddr, TcpListener},
+    os::{
+        fd::{FromRawFd, OwnedFd},
+        unix::net::UnixListener,
+    },
+    process,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use socket2::{SockAddr, SockRef, Type};
+use tracing::{debug, info};
+
+use crate::{
+    forward::{Direction, Endpoint, ForwardSpec},
+    stats::Stats,
+};
+
+/// The first descriptor systemd passes (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: i32 = 3;
+
+/// How often [`exit_when_idle`] looks at the connections.
+const IDLE_POLL: Duration = Duration::from_secs(1);
+
+/// A socket passed in for a forward, set non-blocking.
+#[derive(Debug)]
+pub enum Inherited {
+    Tcp(TcpListener),
+    /// Its file belongs to systemd and is left in place.
+    Unix(UnixListener),
+}
+
+/// The sockets systemd passed in, by the listen address they serve.
+#[derive(Debug, Default)]
+pub struct Activated {
+    /// The listen address each serves, its name and the socket.
+    sockets: Vec<(Endpoint, String, Inherited)>,
+}
+
+impl Activated {
+    /// Take the sockets passed in for this process, if any, and match each
+    /// to one of the local forwards among `specs`. The variables describing
+    /// them are removed, so nothing the tunnel runs inherits them.
+    ///
+    /// Only the thread of a process that has not forked yet may call this.
+    ///
+    /// ## Errors
+    /// if the variables are malformed, or a socket is not a stream socket or
+    /// serves none of the forwards
+    pub fn from_env(specs: &[ForwardSpec]) -> io::Result<Self> {
+        let for_us = env::var("LISTEN_PID").is_ok_and(|pid| pid.parse() == Ok(process::id()));
+        let count = env::var("LISTEN_FDS").ok();
+        let names = env::var("LISTEN_FDNAMES").ok();
+        for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(var);
+        }
+        let (true, Some(count)) = (for_us, count) else {
+            return Ok(Self::default());
+        };
+        let count: i32 = count.parse().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("LISTEN_FDS is not a number: {count:?}"),
+            )
+        })?;
+        let names: Vec<&str> = names
+            .as_deref()
+            .map_or_else(Vec::new, |names| names.split(':').collect());
+
+        let mut activated = Self::default();
+        for (index, fd) in (LISTEN_FDS_START..LISTEN_FDS_START + count).enumerate() {
+            // SAFETY: systemd passes this process `count` open descriptors
+            // from 3 on, and nothing else has taken them.
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let name = names.get(index).copied().unwrap_or("unknown");
+            activated.adopt(fd, name, specs)?;
+        }
+        Ok(activated)
+    }
+
+    /// Match `fd`, named `name`, to the first forward among `specs` it
+    /// serves that no other socket has.
+    fn adopt(&mut self, fd: OwnedFd, name: &str, specs: &[ForwardSpec]) -> io::Result<()> {
+        let socket = SockRef::from(&fd);
+        if socket.r#type()? != Type::STREAM {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("socket {name} passed in is not a stream socket"),
+            ));
+        }
+        socket.set_cloexec(true)?;
+        socket.set_nonblocking(true)?;
+        let address = socket.local_addr()?;
+
+        let unclaimed = specs.iter().filter(|spec| {
+            spec.direction == Direction::Local
+                && !self
+                    .sockets
+                    .iter()
+                    .any(|(listen, ..)| *listen == spec.listen)
+        });
+        let Some(spec) = unclaimed
+            .clone()
+            .find(|spec| spec.listen.is_named(name))
+            .or_else(|| {
+                unclaimed
+                    .clone()
+                    .find(|spec| is_bound_to(&spec.listen, &address))
+            })
+        else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "socket {name} passed in ({}) serves none of the local forwards",
+                    describe(&address)
+                ),
+            ));
+        };
+
+        // A name says nothing about what kind of socket it is.
+        let (fits, kind) = match &spec.listen {
+            Endpoint::Tcp(_) => (address.as_socket().is_some(), "TCP"),
+            Endpoint::Unix(_) => (address.as_pathname().is_some(), "Unix"),
+        };
+        if !fits {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "socket {name} passed in ({}) is not a {kind} socket, as {} needs",
+                    describe(&address),
+                    spec.listen
+                ),
+            ));
+        }
+        let inherited = match &spec.listen {
+            Endpoint::Tcp(_) => Inherited::Tcp(TcpListener::from(fd)),
+            Endpoint::Unix(_) => Inherited::Unix(UnixListener::from(fd)),
+        };
+        // Tracing is not set up yet; `take` says which socket was used.
+        self.sockets
+            .push((spec.listen.clone(), name.to_owned(), inherited));
+        Ok(())
+    }
+
+    /// The socket passed in for the forward listening on `listen`, if any.
+    pub fn take(&mut self, listen: &Endpoint) -> Option<Inherited> {
+        let index = self
+            .sockets
+            .iter()
+            .position(|(served, ..)| served == listen)?;
+        let (_, name, inherited) = self.sockets.swap_remove(index);
+        info!("using socket {name} passed in for {listen}");
+        Some(inherited)
+    }
+}
+
+/// Is a socket bound to `address` what `listen` asks for?
+fn is_bound_to(listen: &Endpoint, address: &SockAddr) -> bool {
+    match (listen, address.as_socket(), address.as_pathname()) {
+        (Endpoint::Tcp(listen), Some(address), _) => {
+            listen.port == address.port() && listen.host.parse::<IpAddr>() == Ok(address.ip())
+        }
+        (Endpoint::Unix(path), _, Some(bound)) => path == bound,
+        _ => false,
+    }
+}
+
+fn describe(address: &SockAddr) -> String {
+    match (address.as_socket(), address.as_pathname()) {
+        (Some(address), _) => address.to_string(),
+        (_, Some(path)) => path.display().to_string(),
+        _ => "unnamed".to_owned(),
+    }
+}
+
+/// Call `exit` once no connection has been open for `idle`
+/// (`--exit-idle-time`). Watches from a thread of its own, so it serves every
+/// backend.
+///
+/// ## Errors
+/// if the thread cannot be started
+pub fn exit_when_idle(
+    stats: Arc<Stats>,
+    idle: Duration,
+    exit: impl FnOnce() + Send + 'static,
+) -> io::Result<()> {
+    thread::Builder::new()
+        .name("exit-idle".into())
+        .spawn(move || {
+            let mut last_seen = (stats.total_connections(), Instant::now());
+            loop {
+                thread::sleep(IDLE_POLL);
+                // A connection opened and closed between two looks still
+                // counts as activity.
+                let total = stats.total_connections();
+                if total != last_seen.0 || stats.active_connections() > 0 {
+                    last_seen = (total, Instant::now());
+                } else if last_seen.1.elapsed() >= idle {
+                    info!("no connections for {idle:?}, exiting");
+                    exit();
+                    return;
+                }
+            }
+        })?;
+    debug!("exiting after {idle:?} without connections");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn specs(specs: &[&str]) -> Vec<ForwardSpec> {
+        specs
+            .iter()
+            .map(|spec| ForwardSpec::parse_local(spec).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn matches_by_name_then_address() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = tcp.local_addr().unwrap().port();
+        let dir = temp_dir("activation-match");
+        let path = dir.join("forward.sock");
+        let unix = UnixListener::bind(&path).unwrap();
+        let specs = specs(&[
+            &format!("{port}:host:80"),
+            &format!("{}:host:80", path.display()),
+        ]);
+
+        let mut activated = Activated::default();
+        activated
+            .adopt(OwnedFd::from(unix), &path.display().to_string(), &specs)
+            .unwrap();
+        activated
+            .adopt(OwnedFd::from(tcp), "unnamed", &specs)
+            .unwrap();
+        assert!(matches!(
+            activated.take(&specs[0].listen),
+            Some(Inherited::Tcp(_))
+        ));
+        assert!(matches!(
+            activated.take(&specs[1].listen),
+            Some(Inherited::Unix(_))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_name_on_the_wrong_kind_of_socket() {
+        let dir = temp_dir("activation-wrong");
+        let path = dir.join("forward.sock");
+        let specs = specs(&["8080:host:80", &format!("{}:host:80", path.display())]);
+
+        let unix = UnixListener::bind(&path).unwrap();
+        let err = Activated::default()
+            .adopt(OwnedFd::from(unix), "8080", &specs)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "socket 8080 passed in ({}) is not a TCP socket, as 127.0.0.1:8080 needs",
+                path.display()
+            )
+        );
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let err = Activated::default()
+            .adopt(OwnedFd::from(tcp), &path.display().to_string(), &specs)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("is not a Unix socket"), "{err}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
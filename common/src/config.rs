@@ -0,0 +1,697 @@
+//! Named profiles in a TOML config file (`--config`, `--profile`).
+//!
+//! ```toml
+//! [profiles.web.destination]
+//! user = "root"
+//! ip = "203.0.113.7"
+//!
+//! [profiles.web.auth]
+//! private_key_path = "~/.ssh/id_ed25519"
+//!
+//! [profiles.web.forwards]
+//! local = ["8080:127.0.0.1:80", "/tmp/db.sock:127.0.0.1:5432"]
+//! exec_fallback = true
+//! lazy = true
+//! idle_disconnect = "10m"
+//!
+//! [profiles.web.limits]
+//! max_connections = 200
+//! idle_timeout = "15m"
+//!
+//! [profiles.web.transport]
+//! window_size = "16MiB"
+//! ciphers = ["aes256-gcm@openssh.com", "aes128-ctr"]
+//! ```
+//!
+//! Keys are named after the options they stand for and take the same values.
+//! The whole file is checked when a profile is used, and errors name the line
+//! at fault. Options on the command line override the profile's, except
+//! forwards, which are added to the profile's.
+//!
+//! Jump hosts (`destination.jump_hosts`) and a reconnect policy
+//! (`reconnect`) are refused rather than ignored: the tunnel connects to the
+//! destination directly and exits once its sessions have failed.
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    marker::PhantomData,
+    net::Ipv4Addr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::{
+    parser::{ArgMatches, ValueSource},
+    Command, ValueEnum,
+};
+use serde::{
+    de::{self, Deserializer, Visitor},
+    Deserialize,
+};
+
+use crate::{
+    forward::{ForwardSpec, UdpSpec},
+    limits::LimitAction,
+    proxy_protocol::ProxyProtocol,
+    rate_limit::RateLimit,
+    transport::Transport,
+    unix_socket, Arguments,
+};
+
+/// Where profiles are read from without `--config`.
+pub const DEFAULT_PATH: &str = "~/.config/port-forward/config.toml";
+
+/// A checked config file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// One `[profiles.NAME]` table.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub destination: Destination,
+    pub auth: Auth,
+    pub forwards: Forwards,
+    pub limits: ProfileLimits,
+    pub transport: Transport,
+    #[serde(deserialize_with = "refuse_reconnect")]
+    reconnect: Refused,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Destination {
+    pub user: Option<String>,
+    #[serde(deserialize_with = "one::<_, Ipv4>")]
+    pub ip: Option<Ipv4Addr>,
+    #[serde(deserialize_with = "refuse_jump_hosts")]
+    jump_hosts: Refused,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    pub private_key_path: Option<PathBuf>,
+    pub public_key_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Forwards {
+    #[serde(deserialize_with = "list::<_, Local>")]
+    pub local: Vec<ForwardSpec>,
+    #[serde(deserialize_with = "list::<_, Remote>")]
+    pub remote: Vec<ForwardSpec>,
+    #[serde(deserialize_with = "list::<_, Udp>")]
+    pub udp: Vec<UdpSpec>,
+    #[serde(deserialize_with = "one::<_, SocketMode>")]
+    pub socket_mode: Option<u32>,
+    pub exec_fallback: Option<bool>,
+    #[serde(deserialize_with = "one::<_, Enum<_>>")]
+    pub proxy_protocol: Option<ProxyProtocol>,
+    pub sessions: Option<NonZeroUsize>,
+    pub preopen_channels: Option<usize>,
+    pub lazy: Option<bool>,
+    #[serde(deserialize_with = "one::<_, Humantime>")]
+    pub idle_disconnect: Option<Duration>,
+}
+
+/// The limit options; unlike [`crate::limits::Limits`] every one may be unset.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileLimits {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_forward: Option<usize>,
+    pub max_pending_opens: Option<usize>,
+    #[serde(deserialize_with = "one::<_, Enum<_>>")]
+    pub on_limit: Option<LimitAction>,
+    #[serde(deserialize_with = "one::<_, Humantime>")]
+    pub idle_timeout: Option<Duration>,
+    #[serde(deserialize_with = "one::<_, Str<_>>")]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(deserialize_with = "one::<_, Str<_>>")]
+    pub connection_rate_limit: Option<RateLimit>,
+}
+
+impl Config {
+    /// Read and check the file at `path`.
+    ///
+    /// ## Errors
+    /// if the file cannot be read, or naming the line of the first problem in
+    /// it
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text =
+            fs::read_to_string(path).map_err(|e| format!("reading {}: {e}", path.display()))?;
+        Self::parse(path, &text)
+    }
+
+    /// Check `text`, read from `path`.
+    ///
+    /// ## Errors
+    /// naming the line of the first problem
+    pub fn parse(path: &Path, text: &str) -> Result<Self, String> {
+        let located = |e: &toml::de::Error, key: String| {
+            let message = e.message().trim_end();
+            match e.span() {
+                Some(span) => {
+                    let line = text[..span.start].matches('\n').count() + 1;
+                    format!("{}:{line}: {key}{message}", path.display())
+                }
+                None => format!("{}: {key}{message}", path.display()),
+            }
+        };
+        let deserializer =
+            toml::Deserializer::parse(text).map_err(|e| located(&e, String::new()))?;
+        serde_path_to_error::deserialize(deserializer).map_err(|e| {
+            let key = match e.path().to_string() {
+                key if key == "." => String::new(),
+                key => format!("{key}: "),
+            };
+            located(e.inner(), key)
+        })
+    }
+
+    /// ## Errors
+    /// if the file has no profile `name`
+    pub fn profile(mut self, name: &str) -> Result<Profile, String> {
+        self.profiles.remove(name).ok_or_else(|| {
+            let names: Vec<_> = self.profiles.keys().map(String::as_str).collect();
+            if names.is_empty() {
+                format!("no profile `{name}`: the config file has none")
+            } else {
+                format!("no profile `{name}`, only {}", names.join(", "))
+            }
+        })
+    }
+}
+
+impl Profile {
+    /// The profile selected by `--profile` on the command line `command`
+    /// would parse, if any.
+    ///
+    /// ## Errors
+    /// if the config file cannot be read, has a problem or lacks the profile
+    pub(crate) fn selected(command: &Command) -> Result<Option<Self>, String> {
+        // Required options may be missing until the profile supplies them.
+        let Ok(matches) = command.clone().ignore_errors(true).try_get_matches() else {
+            return Ok(None);
+        };
+        let Some(name) = matches.get_one::<String>("profile") else {
+            return Ok(None);
+        };
+        let path = match matches.get_one::<PathBuf>("config") {
+            Some(path) => path.clone(),
+            None => crate::expand_home_dir(DEFAULT_PATH)?.into_owned(),
+        };
+        Config::load(&path)?.profile(name).map(Some)
+    }
+
+    /// Let the required options the profile sets be left off `command`.
+    pub(crate) fn relax(&self, mut command: Command) -> Command {
+        let defaults = [
+            ("user", self.destination.user.clone().map(Into::into)),
+            ("ip", self.destination.ip.map(|ip| ip.to_string().into())),
+            (
+                "private_key_path",
+                self.auth
+                    .private_key_path
+                    .clone()
+                    .map(PathBuf::into_os_string),
+            ),
+        ];
+        for (id, value) in defaults {
+            if let Some(value) = value {
+                command = command.mut_arg(id, |arg| arg.required(false).default_value(value));
+            }
+        }
+        command
+    }
+
+    /// Fill in what `matches`, the parsed command line, left out of `args`.
+    pub(crate) fn apply(self, args: &mut Arguments, matches: &ArgMatches) {
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        let Self {
+            auth,
+            forwards,
+            limits,
+            transport,
+            ..
+        } = self;
+
+        fill(
+            &mut args.public_key_path,
+            auth.public_key_path.map(Some),
+            given("public_key_path"),
+        );
+
+        args.local_forwards.splice(..0, forwards.local);
+        args.remote_forwards.splice(..0, forwards.remote);
+        args.udp_forwards.splice(..0, forwards.udp);
+        fill(
+            &mut args.socket_mode,
+            forwards.socket_mode,
+            given("socket_mode"),
+        );
+        fill(
+            &mut args.exec_fallback,
+            forwards.exec_fallback,
+            given("exec_fallback"),
+        );
+        fill(
+            &mut args.proxy_protocol,
+            forwards.proxy_protocol.map(Some),
+            given("proxy_protocol"),
+        );
+        fill(&mut args.sessions, forwards.sessions, given("sessions"));
+        fill(
+            &mut args.preopen_channels,
+            forwards.preopen_channels,
+            given("preopen_channels"),
+        );
+        fill(&mut args.lazy, forwards.lazy, given("lazy"));
+        fill(
+            &mut args.idle_disconnect,
+            forwards.idle_disconnect.map(Some),
+            given("idle_disconnect"),
+        );
+
+        fill(
+            &mut args.max_connections,
+            limits.max_connections.map(Some),
+            given("max_connections"),
+        );
+        fill(
+            &mut args.max_connections_per_forward,
+            limits.max_connections_per_forward.map(Some),
+            given("max_connections_per_forward"),
+        );
+        fill(
+            &mut args.max_pending_opens,
+            limits.max_pending_opens.map(Some),
+            given("max_pending_opens"),
+        );
+        fill(&mut args.on_limit, limits.on_limit, given("on_limit"));
+        fill(
+            &mut args.idle_timeout,
+            limits.idle_timeout.map(Some),
+            given("idle_timeout"),
+        );
+        fill(
+            &mut args.rate_limit,
+            limits.rate_limit.map(Some),
+            given("rate_limit"),
+        );
+        fill(
+            &mut args.connection_rate_limit,
+            limits.connection_rate_limit.map(Some),
+            given("connection_rate_limit"),
+        );
+
+        fill(
+            &mut args.window_size,
+            transport.window_size.map(Some),
+            given("window_size"),
+        );
+        fill(
+            &mut args.max_packet_size,
+            transport.max_packet_size.map(Some),
+            given("max_packet_size"),
+        );
+        fill(
+            &mut args.channel_buffer_size,
+            transport.channel_buffer_size.map(Some),
+            given("channel_buffer_size"),
+        );
+        fill(
+            &mut args.inactivity_timeout,
+            transport.inactivity_timeout.map(Some),
+            given("inactivity_timeout"),
+        );
+        fill(
+            &mut args.keepalive_interval,
+            transport.keepalive_interval.map(Some),
+            given("keepalive_interval"),
+        );
+        fill(
+            &mut args.keepalive_max,
+            transport.keepalive_max.map(Some),
+            given("keepalive_max"),
+        );
+        fill(
+            &mut args.rekey_data,
+            transport.rekey_data.map(Some),
+            given("rekey_data"),
+        );
+        fill(
+            &mut args.rekey_time,
+            transport.rekey_time.map(Some),
+            given("rekey_time"),
+        );
+        for (field, list, id) in [
+            (
+                &mut args.kex_algorithms,
+                transport.kex_algorithms,
+                "kex_algorithms",
+            ),
+            (&mut args.ciphers, transport.ciphers, "ciphers"),
+            (&mut args.macs, transport.macs, "macs"),
+            (
+                &mut args.host_key_algorithms,
+                transport.host_key_algorithms,
+                "host_key_algorithms",
+            ),
+        ] {
+            fill(field, (!list.is_empty()).then_some(list), given(id));
+        }
+        args.compression |= transport.compression;
+    }
+}
+
+/// Set `field` to `value`, unless the command line already did.
+fn fill<T>(field: &mut T, value: Option<T>, given: bool) {
+    if let Some(value) = value.filter(|_| !given) {
+        *field = value;
+    }
+}
+
+/// How a value written as a string, or as an integer taken as one, is parsed.
+pub(crate) trait Parse {
+    type Output;
+
+    fn parse(text: &str) -> Result<Self::Output, String>;
+}
+
+/// A value read through `P`. Errors point at the value itself, even within
+/// an array.
+struct Parsed<P: Parse>(P::Output);
+
+impl<'de, P: Parse> Deserialize<'de> for Parsed<P> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = deserializer.deserialize_any(Text)?;
+        P::parse(&text).map(Self).map_err(de::Error::custom)
+    }
+}
+
+/// For `deserialize_with`: one value read through `P`.
+pub(crate) fn one<'de, D: Deserializer<'de>, P: Parse>(
+    deserializer: D,
+) -> Result<Option<P::Output>, D::Error> {
+    Parsed::<P>::deserialize(deserializer).map(|parsed| Some(parsed.0))
+}
+
+/// For `deserialize_with`: an array of values, each read through `P`.
+fn list<'de, D: Deserializer<'de>, P: Parse>(deserializer: D) -> Result<Vec<P::Output>, D::Error> {
+    let parsed = Vec::<Parsed<P>>::deserialize(deserializer)?;
+    Ok(parsed.into_iter().map(|parsed| parsed.0).collect())
+}
+
+/// A string, or an integer as the string it is written as.
+struct Text;
+
+impl Visitor<'_> for Text {
+    type Value = String;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string")
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<String, E> {
+        Ok(s.to_owned())
+    }
+
+    fn visit_i64<E: de::Error>(self, n: i64) -> Result<String, E> {
+        Ok(n.to_string())
+    }
+
+    fn visit_u64<E: de::Error>(self, n: u64) -> Result<String, E> {
+        Ok(n.to_string())
+    }
+}
+
+/// Anything parsed with its [`FromStr`].
+pub(crate) struct Str<T>(PhantomData<T>);
+
+impl<T: FromStr<Err = String>> Parse for Str<T> {
+    type Output = T;
+
+    fn parse(text: &str) -> Result<T, String> {
+        text.parse()
+    }
+}
+
+/// A [`ValueEnum`], by the name it has on the command line.
+struct Enum<T>(PhantomData<T>);
+
+impl<T: ValueEnum> Parse for Enum<T> {
+    type Output = T;
+
+    fn parse(text: &str) -> Result<T, String> {
+        T::from_str(text, false).map_err(|_| {
+            let names: Vec<_> = T::value_variants()
+                .iter()
+                .filter_map(|variant| Some(variant.to_possible_value()?.get_name().to_owned()))
+                .collect();
+            format!("`{text}` is not one of {}", names.join(", "))
+        })
+    }
+}
+
+/// A duration like `10m` or `1h 30m`.
+pub(crate) struct Humantime;
+
+impl Parse for Humantime {
+    type Output = Duration;
+
+    fn parse(text: &str) -> Result<Duration, String> {
+        humantime::parse_duration(text).map_err(|e| format!("`{text}` is not a duration: {e}"))
+    }
+}
+
+struct Ipv4;
+
+impl Parse for Ipv4 {
+    type Output = Ipv4Addr;
+
+    fn parse(text: &str) -> Result<Ipv4Addr, String> {
+        text.parse()
+            .map_err(|_| format!("`{text}` is not an IPv4 address"))
+    }
+}
+
+struct SocketMode;
+
+impl Parse for SocketMode {
+    type Output = u32;
+
+    fn parse(text: &str) -> Result<u32, String> {
+        unix_socket::parse_mode(text)
+    }
+}
+
+struct Local;
+
+impl Parse for Local {
+    type Output = ForwardSpec;
+
+    fn parse(text: &str) -> Result<ForwardSpec, String> {
+        ForwardSpec::parse_local(text)
+    }
+}
+
+struct Remote;
+
+impl Parse for Remote {
+    type Output = ForwardSpec;
+
+    fn parse(text: &str) -> Result<ForwardSpec, String> {
+        ForwardSpec::parse_remote(text)
+    }
+}
+
+struct Udp;
+
+impl Parse for Udp {
+    type Output = UdpSpec;
+
+    fn parse(text: &str) -> Result<UdpSpec, String> {
+        UdpSpec::parse(text)
+    }
+}
+
+/// A key that is refused with an error, rather than ignored, when present.
+#[derive(Debug, Default)]
+struct Refused;
+
+fn refuse_reconnect<'de, D: Deserializer<'de>>(_: D) -> Result<Refused, D::Error> {
+    Err(de::Error::custom(
+        "reconnecting is not supported: the tunnel exits once its sessions have failed",
+    ))
+}
+
+fn refuse_jump_hosts<'de, D: Deserializer<'de>>(_: D) -> Result<Refused, D::Error> {
+    Err(de::Error::custom(
+        "jump hosts are not supported: the tunnel connects to the destination directly",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ByteSize;
+
+    fn parse(text: &str) -> Result<Config, String> {
+        Config::parse(Path::new("config.toml"), text)
+    }
+
+    #[test]
+    fn reads_a_profile() {
+        let text = r#"
+[profiles.web.destination]
+user = "root"
+ip = "203.0.113.7"
+
+[profiles.web.auth]
+private_key_path = "~/.ssh/id_ed25519"
+
+[profiles.web.forwards]
+local = ["8080:127.0.0.1:80"]
+sessions = 2
+idle_disconnect = "10m"
+socket_mode = 660
+
+[profiles.web.limits]
+on_limit = "reset"
+rate_limit = "1MiB/s"
+
+[profiles.web.transport]
+window_size = "16MiB"
+ciphers = ["aes128-ctr"]
+
+[profiles.empty]
+"#;
+        let mut config = parse(text).unwrap();
+        assert_eq!(config.profiles.keys().collect::<Vec<_>>(), ["empty", "web"]);
+        let web = config.profiles.remove("web").unwrap();
+        assert_eq!(web.destination.user.as_deref(), Some("root"));
+        assert_eq!(web.destination.ip, Some(Ipv4Addr::new(203, 0, 113, 7)));
+        assert_eq!(web.forwards.local.len(), 1);
+        assert_eq!(web.forwards.sessions.map(NonZeroUsize::get), Some(2));
+        assert_eq!(web.forwards.idle_disconnect, Some(Duration::from_secs(600)));
+        assert_eq!(web.forwards.socket_mode, Some(0o660));
+        assert_eq!(web.limits.on_limit, Some(LimitAction::Reset));
+        assert_eq!(web.transport.window_size, Some(ByteSize(16 << 20)));
+        assert_eq!(web.transport.ciphers, ["aes128-ctr"]);
+        assert!(config.profile("empty").is_ok());
+    }
+
+    #[test]
+    fn reads_all_of_toml() {
+        // Inline tables, dotted keys, literal and multi-line strings.
+        let text = r#"
+[profiles.web]
+destination = { user = 'root', ip = "203.0.113.7" }
+auth.private_key_path = '''C:\keys\id'''
+forwards.local = [
+    """8080:127.0.0.1:80""",  # a comment
+]
+"#;
+        let web = parse(text).unwrap().profile("web").unwrap();
+        assert_eq!(web.destination.user.as_deref(), Some("root"));
+        assert_eq!(
+            web.auth.private_key_path,
+            Some(PathBuf::from(r"C:\keys\id"))
+        );
+        assert_eq!(web.forwards.local.len(), 1);
+    }
+
+    #[test]
+    fn errors_name_the_line_and_key() {
+        for (text, error) in [
+            (
+                "[profiles.a.destination]\nuser = \"u\"\nport = 22",
+                "config.toml:3: profiles.a.destination.port: unknown field `port`, expected one \
+                 of `user`, `ip`, `jump_hosts`",
+            ),
+            (
+                "[profiles.a.destination]\nip = \"::1\"",
+                "config.toml:2: profiles.a.destination.ip: `::1` is not an IPv4 address",
+            ),
+            (
+                "[profiles.a.destination]\nip = true",
+                "config.toml:2: profiles.a.destination.ip: invalid type: boolean `true`, expected \
+                 a string",
+            ),
+            (
+                "[profiles.a.destination]\njump_hosts = [\"gw\"]",
+                "config.toml:2: profiles.a.destination.jump_hosts: jump hosts are not supported: \
+                 the tunnel connects to the destination directly",
+            ),
+            (
+                "[profiles.a.forwards]\nlocal = [\n  \"8080:h:80\",\n  \"42\",\n]",
+                "config.toml:2: profiles.a.forwards.local[1]: invalid forward `42`: expected \
+                 [bind_address:]port:host:hostport, where either side may be a /path/to/socket",
+            ),
+            (
+                "[profiles.a.forwards]\nlocal = \"8080:h:80\"",
+                "config.toml:2: profiles.a.forwards.local: invalid type: string \"8080:h:80\", \
+                 expected a sequence",
+            ),
+            (
+                "[profiles.a.forwards]\nsessions = 0",
+                "config.toml:2: profiles.a.forwards.sessions: invalid value: integer `0`, \
+                 expected a nonzero usize",
+            ),
+            (
+                "[profiles.a.limits]\nmax_connections = -1",
+                "config.toml:2: profiles.a.limits.max_connections: invalid value: integer `-1`, \
+                 expected usize",
+            ),
+            (
+                "[profiles.a.limits]\non_limit = \"drop\"",
+                "config.toml:2: profiles.a.limits.on_limit: `drop` is not one of backlog, reset",
+            ),
+            (
+                "[profiles.a.forwards]\nlazy = \"yes\"",
+                "config.toml:2: profiles.a.forwards.lazy: invalid type: string \"yes\", expected \
+                 a boolean",
+            ),
+            (
+                "[profiles.a.reconnect]\nenabled = true",
+                "config.toml:1: profiles.a.reconnect: reconnecting is not supported: the tunnel \
+                 exits once its sessions have failed",
+            ),
+            (
+                "[profiles.a]\ndestination = 1",
+                "config.toml:2: profiles.a.destination: invalid type: integer `1`, expected \
+                 struct Destination",
+            ),
+            (
+                "profile = 1\n[profiles.a]",
+                "config.toml:1: profile: unknown field `profile`, expected `profiles`",
+            ),
+            ("[profiles.a]\nx = 1\nx = 2", "config.toml:3: duplicate key"),
+        ] {
+            assert_eq!(parse(text).err().as_deref(), Some(error), "{text}");
+        }
+    }
+
+    #[test]
+    fn names_the_profiles_there_are() {
+        let config = parse("[profiles.a]\n[profiles.b]").unwrap();
+        assert_eq!(
+            config.profile("c").err().as_deref(),
+            Some("no profile `c`, only a, b")
+        );
+        assert_eq!(
+            Config::default().profile("c").err().as_deref(),
+            Some("no profile `c`: the config file has none")
+        );
+    }
+}
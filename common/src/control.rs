@@ -0,0 +1,291 @@
+//! Runtime control socket protocol, in the spirit of OpenSSH's `-O` commands.
+//!
+//! A running tunnel started with `--control-path` listens on a Unix-domain
+//! socket. Each client line is one JSON [`Request`] and is answered by exactly
+//! one JSON [`Response`] line, so a connection can carry several commands, and
+//! `socat - UNIX-CONNECT:<path>` is a usable client. The `ctl` subcommand
+//! ([`run_ctl`]) wraps this for the common cases.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, Subcommand};
+use serde::{Deserialize, Serialize};
+
+use crate::forward::ForwardSpec;
+
+/// One command sent to the control socket.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    /// Report active forwards and connections.
+    List,
+    /// Start a new forward; `spec` is `-L ...` or `-R ...`.
+    Forward { spec: String },
+    /// Stop the forward with this id. Its established connections are left to
+    /// finish on their own.
+    Cancel { id: u64 },
+    /// Close the session and exit.
+    Exit,
+}
+
+/// The reply to one [`Request`].
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The id assigned by a successful `forward`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forwards: Vec<ForwardInfo>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub connections: Vec<ConnectionInfo>,
+}
+
+impl Response {
+    #[must_use]
+    pub fn ok() -> Self {
+        Self {
+            ok: true,
+            ..Self::default()
+        }
+    }
+
+    #[must_use]
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            error: Some(message.into()),
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForwardInfo {
+    pub id: u64,
+    /// The forward in `-L spec` / `-R spec` form.
+    pub spec: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    /// The forward this connection arrived on, in `-L spec` / `-R spec` form.
+    pub forward: String,
+    pub peer: String,
+    /// The channel type, or `exec` for the fallback (see `Connection::via`).
+    pub via: String,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub age_secs: f64,
+}
+
+/// Arguments of the `ctl` subcommand.
+#[derive(Args, Debug)]
+pub struct CtlArguments {
+    /// The control socket of the running tunnel (its `--control-path`).
+    #[arg(short = 'S', long)]
+    pub control_path: PathBuf,
+    #[command(subcommand)]
+    pub command: CtlCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CtlCommand {
+    /// List active forwards and connections.
+    List,
+    /// Add a forward to the running session.
+    Forward {
+        /// A local forward, as with `ssh -L`.
+        #[arg(short = 'L', value_name = "SPEC", value_parser = ForwardSpec::parse_local,
+              conflicts_with = "remote", required_unless_present = "remote")]
+        local: Option<ForwardSpec>,
+        /// A remote forward, as with `ssh -R`.
+        #[arg(short = 'R', value_name = "SPEC", value_parser = ForwardSpec::parse_remote)]
+        remote: Option<ForwardSpec>,
+    },
+    /// Cancel a forward by the id shown by `list`.
+    Cancel { id: u64 },
+    /// Ask the tunnel to shut down cleanly.
+    Exit,
+}
+
+/// Send one request and wait for its response.
+///
+/// ## Errors
+/// if the socket cannot be reached or the reply is not valid JSON
+pub fn send_request(control_path: &Path, request: &Request) -> Result<Response, String> {
+    let mut stream = UnixStream::connect(control_path)
+        .map_err(|e| format!("connecting to {}: {e}", control_path.display()))?;
+
+    let mut line = serde_json::to_string(request).map_err(|e| e.to_string())?;
+    line.push('\n');
+    stream
+        .write_all(line.as_bytes())
+        .map_err(|e| format!("sending request: {e}"))?;
+
+    let mut reply = String::new();
+    BufReader::new(stream)
+        .read_line(&mut reply)
+        .map_err(|e| format!("reading response: {e}"))?;
+    serde_json::from_str(&reply).map_err(|e| format!("malformed response {reply:?}: {e}"))
+}
+
+/// Run the `ctl` subcommand, printing the outcome to stdout.
+///
+/// ## Errors
+/// if the request could not be delivered or the tunnel reported a failure
+pub fn run_ctl(args: &CtlArguments) -> Result<(), String> {
+    let request = match &args.command {
+        CtlCommand::List => Request::List,
+        CtlCommand::Forward { local, remote } => Request::Forward {
+            spec: local
+                .as_ref()
+                .or(remote.as_ref())
+                .expect("clap requires -L or -R")
+                .to_string(),
+        },
+        CtlCommand::Cancel { id } => Request::Cancel { id: *id },
+        CtlCommand::Exit => Request::Exit,
+    };
+
+    let response = send_request(&args.control_path, &request)?;
+    if !response.ok {
+        return Err(response
+            .error
+            .unwrap_or_else(|| "request failed".to_owned()));
+    }
+
+    match request {
+        Request::List => {
+            println!("forwards:");
+            for forward in &response.forwards {
+                println!("  {:>4}  {}", forward.id, forward.spec);
+            }
+            println!("connections:");
+            for conn in &response.connections {
+                println!(
+                    "  {:>4}  {}  from {}  via {}  {} up / {} down  {:.0}s",
+                    conn.id,
+                    conn.forward,
+                    conn.peer,
+                    conn.via,
+                    conn.bytes_up,
+                    conn.bytes_down,
+                    conn.age_secs
+                );
+            }
+        }
+        Request::Forward { .. } => {
+            if let Some(id) = response.id {
+                println!("{id}");
+            }
+        }
+        Request::Cancel { .. } | Request::Exit => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixListener, thread};
+
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn requests_are_tagged_by_cmd() {
+        for (request, json) in [
+            (Request::List, r#"{"cmd":"list"}"#),
+            (
+                Request::Forward {
+                    spec: "-L 8080:h:80".to_owned(),
+                },
+                r#"{"cmd":"forward","spec":"-L 8080:h:80"}"#,
+            ),
+            (Request::Cancel { id: 3 }, r#"{"cmd":"cancel","id":3}"#),
+            (Request::Exit, r#"{"cmd":"exit"}"#),
+        ] {
+            assert_eq!(serde_json::to_string(&request).unwrap(), json);
+            let parsed: Request = serde_json::from_str(json).unwrap();
+            assert_eq!(format!("{parsed:?}"), format!("{request:?}"));
+        }
+        assert!(serde_json::from_str::<Request>(r#"{"cmd":"reload"}"#).is_err());
+        assert!(serde_json::from_str::<Request>(r#"{"cmd":"cancel"}"#).is_err());
+    }
+
+    #[test]
+    fn responses_leave_out_what_is_empty() {
+        assert_eq!(
+            serde_json::to_string(&Response::ok()).unwrap(),
+            r#"{"ok":true}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Response::error("no such forward")).unwrap(),
+            r#"{"ok":false,"error":"no such forward"}"#
+        );
+        let response: Response = serde_json::from_str(r#"{"ok":true,"id":7}"#).unwrap();
+        assert_eq!(response.id, Some(7));
+        assert!(response.forwards.is_empty() && response.connections.is_empty());
+    }
+
+    #[test]
+    fn send_request_round_trips_over_the_socket() {
+        let dir = temp_dir("control");
+        let path = dir.join("ctl.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // A tunnel answering one `list`, as the russh backend does.
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            let request: Request = serde_json::from_str(&line).unwrap();
+            assert!(matches!(request, Request::List), "{line}");
+            let response = Response {
+                forwards: vec![ForwardInfo {
+                    id: 1,
+                    spec: "-L 8080:h:80".to_owned(),
+                }],
+                connections: vec![ConnectionInfo {
+                    id: 4,
+                    forward: "-L 8080:h:80".to_owned(),
+                    peer: "127.0.0.1:5000".to_owned(),
+                    via: "direct-tcpip".to_owned(),
+                    bytes_up: 10,
+                    bytes_down: 20,
+                    age_secs: 1.5,
+                }],
+                ..Response::ok()
+            };
+            let mut reply = serde_json::to_string(&response).unwrap();
+            reply.push('\n');
+            (&stream).write_all(reply.as_bytes()).unwrap();
+        });
+
+        let response = send_request(&path, &Request::List).unwrap();
+        server.join().unwrap();
+        assert!(response.ok);
+        assert_eq!(response.forwards[0].spec, "-L 8080:h:80");
+        let connection = &response.connections[0];
+        assert_eq!(
+            (
+                connection.id,
+                connection.via.as_str(),
+                connection.bytes_down
+            ),
+            (4, "direct-tcpip", 20)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        let err = send_request(&path, &Request::List).unwrap_err();
+        assert!(err.starts_with("connecting to "), "{err}");
+    }
+}
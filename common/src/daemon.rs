@@ -0,0 +1,262 @@
+//! Telling whoever started the tunnel that it is up: `--daemon`, `--pidfile`
+//! and systemd's `sd_notify` protocol.
+//!
+//! With `--daemon` the process forks before doing anything else and the
+//! foreground half waits on a pipe: it exits 0 once the background half calls
+//! [`Readiness::ready`], that is once the tunnel has authenticated and bound
+//! its listeners, and 1 if the background half exits first. Scripts can go on
+//! as soon as the command returns instead of polling the local port.
+//!
+//! Under systemd (`NOTIFY_SOCKET` set) `READY=1` is sent at the same point and
+//! `STOPPING=1` when shutdown begins, for `Type=notify` units. With
+//! `--daemon` it is the foreground process, the one systemd started, that
+//! sends `READY=1` along with the background one's `MAINPID`, as the default
+//! `NotifyAccess=main` only accepts it from there.
+//!
+//! The pidfile is removed however the tunnel exits, so long as it gets to run
+//! its exit paths: see [`remove_pidfile`].
+
+use std::{
+    env,
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::{ffi::OsStrExt, net::UnixDatagram},
+    },
+    path::{Path, PathBuf},
+    process,
+    sync::{Mutex, PoisonError},
+};
+
+use tracing::{debug, warn};
+
+use crate::Arguments;
+
+/// The pidfile this process wrote, and so is to remove.
+static WRITTEN_PIDFILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// What is waiting to hear that the tunnel is up.
+#[derive(Debug)]
+pub struct Readiness {
+    /// The pipe to the foreground process, with `--daemon`.
+    parent: Option<OwnedFd>,
+    pidfile: Option<PathBuf>,
+}
+
+impl Readiness {
+    /// Fork into the background if `--daemon` was given; the foreground
+    /// process does not return from this.
+    ///
+    /// Only the calling thread survives a fork, so this must run before any
+    /// other thread starts: before the tokio runtime, a signal handler thread
+    /// or the metrics server.
+    ///
+    /// ## Errors
+    /// if the pipe cannot be created or the fork fails
+    pub fn start(args: &Arguments) -> io::Result<Self> {
+        let parent = if args.daemon {
+            Some(fork_background()?)
+        } else {
+            None
+        };
+        Ok(Self {
+            parent,
+            pidfile: args.pidfile.clone(),
+        })
+    }
+
+    /// Report that the tunnel is up: write the pidfile, tell systemd and let
+    /// the foreground process exit.
+    ///
+    /// ## Errors
+    /// if the pidfile cannot be written
+    pub fn ready(&mut self) -> io::Result<()> {
+        let pid = process::id();
+        if let Some(path) = &self.pidfile {
+            fs::write(path, format!("{pid}\n")).map_err(|e| {
+                io::Error::new(e.kind(), format!("writing {}: {e}", path.display()))
+            })?;
+            *WRITTEN_PIDFILE
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some(path.clone());
+        }
+        match self.parent.take() {
+            // The foreground process tells systemd as it exits.
+            Some(parent) => {
+                File::from(parent).write_all(&[0])?;
+                debug!("running in the background as process {pid}");
+            }
+            None => notify_systemd("READY=1"),
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Readiness {
+    fn drop(&mut self) {
+        remove_pidfile();
+    }
+}
+
+/// Remove the pidfile, if this process wrote one. Dropping the [`Readiness`]
+/// does this; call it before a `process::exit`, which drops nothing.
+pub fn remove_pidfile() {
+    let path = WRITTEN_PIDFILE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    if let Some(path) = path {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("removing {}: {e}", path.display());
+        }
+    }
+}
+
+/// Send `state` to systemd's notification socket, if it set one, as
+/// `sd_notify(3)` would. A failure is only logged: the tunnel works either way.
+pub fn notify_systemd(state: &str) {
+    let Some(socket) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send_notification(&socket, state) {
+        warn!("notifying systemd: {e}");
+    }
+}
+
+fn send_notification(socket: &OsStr, state: &str) -> io::Result<()> {
+    let datagram = UnixDatagram::unbound()?;
+    match socket.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+            datagram.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "abstract notification sockets are Linux-only",
+            ))
+        }
+        None => {
+            datagram.send_to(state.as_bytes(), Path::new(socket))?;
+        }
+    }
+    Ok(())
+}
+
+/// Fork, returning in the child with the write end of a pipe to the parent.
+/// The parent waits for a byte on it, tells systemd the child is up, and
+/// exits.
+fn fork_background() -> io::Result<OwnedFd> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors `pipe` writes.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `pipe` succeeded, so both are open descriptors nothing else owns.
+    let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+    // SAFETY: no other thread is running (see `Readiness::start`), so the
+    // child starts in a consistent state.
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(read);
+            // Leave the terminal's session, so its hangup does not reach us.
+            // SAFETY: `setsid` takes nothing and touches no memory of ours.
+            if unsafe { libc::setsid() } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // stdin and stdout are not used; pointing them at /dev/null keeps a
+            // caller reading our output from waiting for the tunnel to exit.
+            // stderr stays for the logs.
+            let null = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("/dev/null")?;
+            for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO] {
+                // SAFETY: both descriptors are open for the duration of the call.
+                if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(write)
+        }
+        child => {
+            drop(write);
+            let mut byte = [0];
+            // EOF means the child exited before it was up; it has said why on
+            // the stderr we share.
+            let status = match File::from(read).read(&mut byte) {
+                Ok(1) => {
+                    notify_systemd(&format!("READY=1\nMAINPID={child}"));
+                    0
+                }
+                _ => 1,
+            };
+            process::exit(status);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn received(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 256];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn notifications_are_one_datagram_of_newline_separated_assignments() {
+        let dir = temp_dir("daemon-notify");
+        let path = dir.join("notify.sock");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+
+        send_notification(path.as_os_str(), "READY=1\nMAINPID=42").unwrap();
+        send_notification(path.as_os_str(), "STOPPING=1").unwrap();
+        assert_eq!(received(&systemd), "READY=1\nMAINPID=42");
+        assert_eq!(received(&systemd), "STOPPING=1");
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(send_notification(path.as_os_str(), "READY=1").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn an_at_sign_names_an_abstract_socket() {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let name = format!("daemon-test-{}", process::id());
+        let systemd =
+            UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        send_notification(OsStr::new(&format!("@{name}")), "READY=1").unwrap();
+        assert_eq!(received(&systemd), "READY=1");
+    }
+
+    #[test]
+    fn the_pidfile_is_written_when_ready_and_removed_on_drop() {
+        let dir = temp_dir("daemon-pidfile");
+        let path = dir.join("tunnel.pid");
+        let mut readiness = Readiness {
+            parent: None,
+            pidfile: Some(path.clone()),
+        };
+
+        readiness.ready().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+        drop(readiness);
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
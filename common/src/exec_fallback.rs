@@ -0,0 +1,156 @@
+//! Reaching forward targets through `exec` when the server refuses forwarding.
+//!
+//! With `AllowTcpForwarding no` (or `AllowStreamLocalForwarding no`) sshd
+//! rejects `direct-tcpip` / `direct-streamlocal` channels as "administratively
+//! prohibited", but usually still runs commands. `--exec-fallback` then opens
+//! a `session` channel running [`connect_command`] and splices its stdio
+//! instead, so the connection is made by a process on the server rather than
+//! by sshd itself.
+
+use crate::{forward::Endpoint, shell_quote};
+
+/// How a connection's server side was reached, as reported per connection.
+pub const VIA_EXEC: &str = "exec";
+
+/// Relays stdio to `/dev/tcp/$0/$1` for servers with neither `nc` nor `socat`.
+const BASH_TCP: &str = r#"exec 3<>"/dev/tcp/$0/$1" && { cat <&3 & cat >&3; wait; }"#;
+
+/// A POSIX shell command that connects its stdin/stdout to `target`, using
+/// whichever of `nc`, `socat` or bash's `/dev/tcp` the server has.
+///
+/// bash cannot open Unix sockets, so those need `nc` or `socat`. When nothing
+/// suitable is installed the command fails and the channel simply closes.
+#[must_use]
+pub fn connect_command(target: &Endpoint) -> String {
+    match target {
+        Endpoint::Tcp(target) => {
+            let host = shell_quote(&target.host);
+            let port = target.port;
+            first_available(&[
+                ("nc", format!("nc {host} {port}")),
+                (
+                    "socat",
+                    format!("socat - {}", shell_quote(&format!("TCP:{target}"))),
+                ),
+                (
+                    "bash",
+                    format!("bash -c {} {host} {port}", shell_quote(BASH_TCP)),
+                ),
+            ])
+        }
+        Endpoint::Unix(path) => {
+            let path = path.to_string_lossy();
+            first_available(&[
+                ("nc", format!("nc -U {}", shell_quote(&path))),
+                (
+                    "socat",
+                    format!("socat - {}", shell_quote(&format!("UNIX-CONNECT:{path}"))),
+                ),
+            ])
+        }
+    }
+}
+
+/// `exec` the command of the first `(program, command)` the server has.
+fn first_available(candidates: &[(&str, String)]) -> String {
+    let mut script = String::new();
+    for (program, command) in candidates {
+        let keyword = if script.is_empty() { "if" } else { "elif" };
+        script += &format!("{keyword} command -v {program} >/dev/null 2>&1; then exec {command}; ");
+    }
+    let programs: Vec<_> = candidates.iter().map(|(program, _)| *program).collect();
+    script += &format!(
+        "else echo 'exec fallback: none of {} on the server' >&2; exit 127; fi",
+        programs.join(", ")
+    );
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        path::PathBuf,
+        process::{Command, Stdio},
+    };
+
+    use super::*;
+    use crate::forward::HostPort;
+
+    fn sh(script: &str) -> Command {
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[test]
+    fn tries_nc_then_socat_then_bash() {
+        let command = connect_command(&Endpoint::Tcp(HostPort {
+            host: "db.internal".to_owned(),
+            port: 5432,
+        }));
+        let nc = command.find("command -v nc").unwrap();
+        let socat = command.find("command -v socat").unwrap();
+        let bash = command.find("command -v bash").unwrap();
+        assert!(nc < socat && socat < bash, "{command}");
+        assert!(command
+            .starts_with("if command -v nc >/dev/null 2>&1; then exec nc 'db.internal' 5432; "));
+        assert!(command.contains("exec socat - 'TCP:db.internal:5432'; "));
+    }
+
+    #[test]
+    fn unix_targets_are_quoted_and_skip_bash() {
+        let command = connect_command(&Endpoint::Unix(PathBuf::from("/run/it's.sock")));
+        assert!(
+            command.contains(r"exec nc -U '/run/it'\''s.sock'; "),
+            "{command}"
+        );
+        assert!(command.contains(r"exec socat - 'UNIX-CONNECT:/run/it'\''s.sock'; "));
+        assert!(!command.contains("bash"));
+    }
+
+    #[test]
+    fn fails_with_127_when_the_server_has_none_of_them() {
+        let command = connect_command(&Endpoint::Unix(PathBuf::from("/run/db.sock")));
+        let output = sh(&command).env("PATH", "/nonexistent").output().unwrap();
+        assert_eq!(output.status.code(), Some(127));
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            "exec fallback: none of nc, socat on the server\n"
+        );
+    }
+
+    #[test]
+    fn relays_stdio_to_a_tcp_target() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let command = connect_command(&Endpoint::Tcp(HostPort {
+            host: "127.0.0.1".to_owned(),
+            port,
+        }));
+        let mut child = sh(&command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let (mut target, _) = listener.accept().unwrap();
+        child.stdin.take().unwrap().write_all(b"ping").unwrap();
+        let mut request = [0; 4];
+        target.read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"ping");
+        target.write_all(b"pong").unwrap();
+        drop(target);
+
+        let mut response = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut response)
+            .unwrap();
+        assert_eq!(response, "pong");
+        child.wait().unwrap();
+    }
+}
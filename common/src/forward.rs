@@ -0,0 +1,535 @@
+//! `ssh -L` / `ssh -R` style forward specifications.
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The originator to name in a `direct-tcpip` request for a client at `peer`.
+///
+/// Clients without an address (Unix-socket listeners, `--stdio`) are reported
+/// as `127.0.0.1:65535`, as OpenSSH does, so servers that validate the
+/// originator are happy.
+#[must_use]
+pub fn originator(peer: Option<SocketAddr>) -> (String, u16) {
+    peer.map_or_else(
+        || ("127.0.0.1".to_owned(), 65535),
+        |peer| (peer.ip().to_string(), peer.port()),
+    )
+}
+
+/// Which side listens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// We listen locally and the server connects to the target (`-L`).
+    Local,
+    /// The server listens and we connect to the target locally (`-R`).
+    Remote,
+}
+
+impl Direction {
+    /// The OpenSSH flag for this direction.
+    #[must_use]
+    pub const fn flag(self) -> &'static str {
+        match self {
+            Self::Local => "-L",
+            Self::Remote => "-R",
+        }
+    }
+}
+
+/// A host name or address plus a port.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostPort {
+    pub host: String,
+    pub port: u16,
+}
+
+impl fmt::Display for HostPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// One end of a forward: where we listen or where a connection is delivered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    /// A TCP host and port.
+    Tcp(HostPort),
+    /// A Unix-domain socket path. As a `-L` target this is a socket on the
+    /// server, reached with `direct-streamlocal@openssh.com`.
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    /// The host and port, unless this is a Unix socket.
+    #[must_use]
+    pub const fn as_tcp(&self) -> Option<&HostPort> {
+        match self {
+            Self::Tcp(host_port) => Some(host_port),
+            Self::Unix(_) => None,
+        }
+    }
+
+    /// Does `name`, a port, `bind_address:port` or `/path/to/socket`, name
+    /// this listen address?
+    #[must_use]
+    pub fn is_named(&self, name: &str) -> bool {
+        match self {
+            Self::Tcp(listen) => name == listen.port.to_string() || name == listen.to_string(),
+            Self::Unix(path) => Path::new(name) == path,
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    /// Parse `host:port`, `[v6addr]:port` or a `/path/to/socket`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed =
+            || format!("invalid destination `{s}`: expected host:port or /path/to/socket");
+
+        if s.starts_with('/') {
+            return Ok(Self::Unix(PathBuf::from(s)));
+        }
+        match split_fields(s).map_err(|_| malformed())?.as_slice() {
+            [host, port] if !host.is_empty() => Ok(Self::Tcp(HostPort {
+                host: host.clone(),
+                port: port
+                    .parse()
+                    .map_err(|_| format!("invalid destination `{s}`: bad port `{port}`"))?,
+            })),
+            _ => Err(malformed()),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(host_port) => host_port.fmt(f),
+            Self::Unix(path) => path.display().fmt(f),
+        }
+    }
+}
+
+/// One forward: where to listen and where each accepted connection goes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForwardSpec {
+    pub direction: Direction,
+    /// Always [`Endpoint::Tcp`] for remote forwards.
+    pub listen: Endpoint,
+    pub target: Endpoint,
+}
+
+impl ForwardSpec {
+    /// Listen address used when a spec does not name one. As with OpenSSH
+    /// without `GatewayPorts`, forwards are only reachable from loopback.
+    pub const DEFAULT_BIND: &'static str = "127.0.0.1";
+
+    /// Parse `[bind_address:]port:host:hostport`, as accepted by `ssh -L`.
+    /// Either side may instead be a socket path: `local_socket:host:hostport`,
+    /// `[bind_address:]port:remote_socket` or `local_socket:remote_socket`.
+    ///
+    /// ## Errors
+    /// if the spec is malformed
+    pub fn parse_local(spec: &str) -> Result<Self, String> {
+        Self::parse(Direction::Local, spec)
+    }
+
+    /// Parse `[bind_address:]port:host:hostport` or
+    /// `[bind_address:]port:local_socket`, as accepted by `ssh -R`.
+    ///
+    /// ## Errors
+    /// if the spec is malformed
+    pub fn parse_remote(spec: &str) -> Result<Self, String> {
+        Self::parse(Direction::Remote, spec)
+    }
+
+    /// Parse a spec for the given direction.
+    ///
+    /// ## Errors
+    /// if the spec is malformed
+    pub fn parse(direction: Direction, spec: &str) -> Result<Self, String> {
+        let malformed = || {
+            format!(
+                "invalid forward `{spec}`: expected [bind_address:]port:host:hostport, where \
+                 either side may be a /path/to/socket"
+            )
+        };
+
+        let fields = split_fields(spec)?;
+        // Like OpenSSH, a field starting with `/` is a socket path. A listen
+        // path is just the first field; a target path runs to the end of the
+        // spec, so it may itself contain `:`.
+        let (listen_path, rest) = match fields.split_first() {
+            Some((path, rest)) if path.starts_with('/') => (Some(PathBuf::from(path)), rest),
+            _ => (None, fields.as_slice()),
+        };
+        let (listen, target) = match rest.iter().position(|field| field.starts_with('/')) {
+            Some(at) => (
+                &rest[..at],
+                Endpoint::Unix(PathBuf::from(rest[at..].join(":"))),
+            ),
+            None if rest.len() >= 2 => {
+                let (listen, target) = rest.split_at(rest.len() - 2);
+                if target[0].is_empty() {
+                    return Err(format!("invalid forward `{spec}`: empty target host"));
+                }
+                let target = HostPort {
+                    host: target[0].clone(),
+                    port: parse_port(spec, &target[1])?,
+                };
+                (listen, Endpoint::Tcp(target))
+            }
+            None => return Err(malformed()),
+        };
+
+        let listen = match (listen_path, listen) {
+            (Some(path), []) => Endpoint::Unix(path),
+            (None, [port]) => Endpoint::Tcp(HostPort {
+                host: Self::DEFAULT_BIND.to_owned(),
+                port: parse_port(spec, port)?,
+            }),
+            (None, [bind, port]) => Endpoint::Tcp(HostPort {
+                host: if bind.is_empty() || bind == "*" {
+                    // `ssh -L :8080:...` / `*:8080:...` mean "every interface".
+                    "0.0.0.0".to_owned()
+                } else {
+                    bind.clone()
+                },
+                port: parse_port(spec, port)?,
+            }),
+            _ => return Err(malformed()),
+        };
+
+        if direction == Direction::Remote && listen.as_tcp().is_none() {
+            return Err(format!(
+                "invalid forward `{spec}`: remote forwards must listen on a port"
+            ));
+        }
+
+        Ok(Self {
+            direction,
+            listen,
+            target,
+        })
+    }
+}
+
+impl fmt::Display for ForwardSpec {
+    /// Formats as the flag plus a spec that parses back to the same forward.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}:{}",
+            self.direction.flag(),
+            self.listen,
+            self.target
+        )
+    }
+}
+
+impl FromStr for ForwardSpec {
+    type Err = String;
+
+    /// Parse the [`Display`](fmt::Display) form, i.e. `-L spec` or `-R spec`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once(char::is_whitespace) {
+            Some(("-L", spec)) => Self::parse_local(spec.trim()),
+            Some(("-R", spec)) => Self::parse_remote(spec.trim()),
+            _ => Err(format!(
+                "invalid forward `{s}`: expected `-L spec` or `-R spec`"
+            )),
+        }
+    }
+}
+
+/// How a forward's connections are served relative to others on the same
+/// session (`--priority`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Served first, e.g. for an interactive session or an IDE.
+    High,
+    #[default]
+    Normal,
+    /// Served last and in smaller pieces, e.g. for bulk transfers.
+    Low,
+}
+
+impl Priority {
+    /// Every class, in the order they are served.
+    pub const ALL: [Self; 3] = [Self::High, Self::Normal, Self::Low];
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "high" => Ok(Self::High),
+            "normal" => Ok(Self::Normal),
+            "low" => Ok(Self::Low),
+            _ => Err(format!(
+                "invalid priority `{s}`: expected high, normal or low"
+            )),
+        }
+    }
+}
+
+/// A `--priority LISTEN=CLASS` option: the class of the forward listening on
+/// `listen`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForwardPriority {
+    /// A port, `bind_address:port` or `/path/to/socket`.
+    pub listen: String,
+    pub priority: Priority,
+}
+
+impl ForwardPriority {
+    /// Does this option name where `spec` listens?
+    #[must_use]
+    pub fn matches(&self, spec: &ForwardSpec) -> bool {
+        spec.listen.is_named(&self.listen)
+    }
+}
+
+impl FromStr for ForwardPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once('=') {
+            Some((listen, priority)) if !listen.is_empty() => Ok(Self {
+                listen: listen.to_owned(),
+                priority: priority.parse()?,
+            }),
+            _ => Err(format!(
+                "invalid priority `{s}`: expected LISTEN=CLASS, e.g. 8080=low"
+            )),
+        }
+    }
+}
+
+/// A UDP forward (`-U`): datagrams sent to `listen` are relayed to `target`
+/// from the server (see [`crate::udp_relay`]).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UdpSpec {
+    pub listen: HostPort,
+    pub target: HostPort,
+}
+
+impl UdpSpec {
+    /// Parse `[bind_address:]port:host:hostport`.
+    ///
+    /// ## Errors
+    /// if the spec is malformed or names a socket path
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let forward = ForwardSpec::parse_local(spec)?;
+        match (forward.listen, forward.target) {
+            (Endpoint::Tcp(listen), Endpoint::Tcp(target)) => Ok(Self { listen, target }),
+            _ => Err(format!(
+                "invalid UDP forward `{spec}`: socket paths are not supported"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for UdpSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "-U {}:{}", self.listen, self.target)
+    }
+}
+
+fn parse_port(spec: &str, port: &str) -> Result<u16, String> {
+    port.parse()
+        .map_err(|_| format!("invalid forward `{spec}`: bad port `{port}`"))
+}
+
+/// Split on `:`, treating a `[...]` group (an IPv6 address) as one field.
+fn split_fields(spec: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut current = String::new();
+    let mut chars = spec.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '[' if current.is_empty() => {
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some(c) => current.push(c),
+                        None => return Err(format!("invalid forward `{spec}`: unclosed `[`")),
+                    }
+                }
+                match chars.next() {
+                    Some(':') => fields.push(std::mem::take(&mut current)),
+                    None => {}
+                    Some(_) => return Err(format!("invalid forward `{spec}`: bad `[...]`")),
+                }
+            }
+            ':' => fields.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    fields.push(current);
+
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(host: &str, port: u16) -> Endpoint {
+        Endpoint::Tcp(HostPort {
+            host: host.to_owned(),
+            port,
+        })
+    }
+
+    fn unix(path: &str) -> Endpoint {
+        Endpoint::Unix(PathBuf::from(path))
+    }
+
+    #[test]
+    fn split_fields_keeps_brackets_together() {
+        assert_eq!(
+            split_fields("[::1]:8080:[fe80::1]:80").unwrap(),
+            ["::1", "8080", "fe80::1", "80"]
+        );
+        assert_eq!(split_fields(":8080").unwrap(), ["", "8080"]);
+        assert_eq!(split_fields("[::1]").unwrap(), ["::1"]);
+        assert!(split_fields("[::1]x:80").is_err());
+        assert!(split_fields("[::1:80").is_err());
+    }
+
+    #[test]
+    fn parses_port_host_hostport() {
+        let spec = ForwardSpec::parse_local("8080:example.com:80").unwrap();
+        assert_eq!(spec.direction, Direction::Local);
+        assert_eq!(spec.listen, tcp(ForwardSpec::DEFAULT_BIND, 8080));
+        assert_eq!(spec.target, tcp("example.com", 80));
+    }
+
+    #[test]
+    fn parses_bind_addresses() {
+        let listen = |spec| ForwardSpec::parse_local(spec).unwrap().listen;
+        assert_eq!(listen("10.0.0.1:8080:h:80"), tcp("10.0.0.1", 8080));
+        assert_eq!(listen("*:8080:h:80"), tcp("0.0.0.0", 8080));
+        assert_eq!(listen(":8080:h:80"), tcp("0.0.0.0", 8080));
+        assert_eq!(listen("[::1]:8080:h:80"), tcp("::1", 8080));
+    }
+
+    #[test]
+    fn parses_ipv6_targets() {
+        let spec = ForwardSpec::parse_local("8080:[2001:db8::1]:443").unwrap();
+        assert_eq!(spec.target, tcp("2001:db8::1", 443));
+    }
+
+    #[test]
+    fn parses_socket_paths() {
+        let spec = ForwardSpec::parse_local("/tmp/l.sock:/run/db:1.sock").unwrap();
+        assert_eq!(spec.listen, unix("/tmp/l.sock"));
+        // A target path runs to the end of the spec.
+        assert_eq!(spec.target, unix("/run/db:1.sock"));
+
+        let spec = ForwardSpec::parse_local("127.0.0.1:5432:/run/pg.sock").unwrap();
+        assert_eq!(spec.listen, tcp("127.0.0.1", 5432));
+        assert_eq!(spec.target, unix("/run/pg.sock"));
+
+        let spec = ForwardSpec::parse_local("/tmp/l.sock:db:5432").unwrap();
+        assert_eq!(spec.listen, unix("/tmp/l.sock"));
+        assert_eq!(spec.target, tcp("db", 5432));
+    }
+
+    #[test]
+    fn rejects_malformed_specs() {
+        for spec in [
+            "",
+            "8080",
+            "8080:host",
+            "x:host:80",
+            "8080:host:99999",
+            "8080::80",
+            "a:b:8080:host:80",
+            "/tmp/a:b.sock:host:80",
+        ] {
+            assert!(ForwardSpec::parse_local(spec).is_err(), "{spec}");
+        }
+        assert!(ForwardSpec::parse_remote("/tmp/l.sock:host:80").is_err());
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for (flag, spec) in [
+            ("-L", "8080:example.com:80"),
+            ("-L", "*:8080:example.com:80"),
+            ("-L", "[::1]:8080:[2001:db8::1]:443"),
+            ("-L", "/tmp/l.sock:/run/db:1.sock"),
+            ("-L", "5432:/run/pg.sock"),
+            ("-R", "0.0.0.0:9000:localhost:3000"),
+            ("-R", "9000:/tmp/app.sock"),
+        ] {
+            let parsed: ForwardSpec = format!("{flag} {spec}").parse().unwrap();
+            assert_eq!(parsed.to_string().parse::<ForwardSpec>(), Ok(parsed));
+        }
+        assert!("-X 1:h:2".parse::<ForwardSpec>().is_err());
+    }
+
+    #[test]
+    fn endpoint_from_str() {
+        assert_eq!("host:22".parse(), Ok(tcp("host", 22)));
+        assert_eq!("[::1]:22".parse(), Ok(tcp("::1", 22)));
+        assert_eq!("/run/a:b.sock".parse(), Ok(unix("/run/a:b.sock")));
+        assert!(":22".parse::<Endpoint>().is_err());
+        assert!("host:http".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn udp_specs_take_ports_only() {
+        let spec = UdpSpec::parse("5353:10.0.0.2:53").unwrap();
+        assert_eq!(spec.to_string(), "-U 127.0.0.1:5353:10.0.0.2:53");
+        assert!(UdpSpec::parse("5353:/run/dns.sock").is_err());
+    }
+
+    #[test]
+    fn parses_priorities() {
+        assert_eq!("high".parse(), Ok(Priority::High));
+        assert_eq!("low".parse(), Ok(Priority::Low));
+        assert!("urgent".parse::<Priority>().is_err());
+        assert!("High".parse::<Priority>().is_err());
+
+        let priority: ForwardPriority = "127.0.0.1:8080=low".parse().unwrap();
+        assert_eq!(priority.listen, "127.0.0.1:8080");
+        assert_eq!(priority.priority, Priority::Low);
+        for bad in ["8080", "=low", "8080=", "8080=fast"] {
+            assert!(bad.parse::<ForwardPriority>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn priorities_match_by_port_address_or_path() {
+        let tcp_spec = ForwardSpec::parse_local("127.0.0.1:8080:host:80").unwrap();
+        let unix_spec = ForwardSpec::parse_local("/tmp/l.sock:host:80").unwrap();
+        let named = |listen: &str| ForwardPriority {
+            listen: listen.to_owned(),
+            priority: Priority::High,
+        };
+        assert!(named("8080").matches(&tcp_spec));
+        assert!(named("127.0.0.1:8080").matches(&tcp_spec));
+        assert!(!named("0.0.0.0:8080").matches(&tcp_spec));
+        assert!(!named("8081").matches(&tcp_spec));
+        assert!(named("/tmp/l.sock").matches(&unix_spec));
+        assert!(!named("8080").matches(&unix_spec));
+    }
+}
@@ -1,15 +1,54 @@
+//! Code shared by the port-forward backends.
+//!
+//! The async helpers in [`limits`], [`shutdown`] and [`preopen`] serve the
+//! tokio backends (russh alone pre-opens channels); ssh2-rs keeps the same
+//! bookkeeping in its event loop.
+
 use std::{
     borrow::Cow,
+    env,
     fs::OpenOptions,
-    net::Ipv4Addr,
+    net::{Ipv4Addr, SocketAddr},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
-use clap::Parser;
+use clap::{error::ErrorKind, CommandFactory, FromArgMatches, Parser, Subcommand};
 use lazy_static::lazy_static;
 use tracing::{debug, instrument};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
+pub mod activation;
+pub mod config;
+pub mod control;
+pub mod daemon;
+pub mod exec_fallback;
+pub mod forward;
+pub mod limits;
+pub mod metrics;
+pub mod preopen;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod shutdown;
+pub mod stats;
+#[cfg(test)]
+mod test_util;
+pub mod transport;
+pub mod udp_relay;
+pub mod unix_socket;
+
+use crate::{
+    config::Profile,
+    control::CtlArguments,
+    forward::{Direction, Endpoint, ForwardPriority, ForwardSpec, HostPort, Priority, UdpSpec},
+    limits::{LimitAction, Limits},
+    proxy_protocol::ProxyProtocol,
+    rate_limit::RateLimit,
+    transport::{ByteSize, Transport},
+    udp_relay::UdpRelayArguments,
+};
+
 const BUFFER_SIZE: usize = 16_384;
 
 /// Expand a tilde to the full path of the user's home directory
@@ -17,7 +56,7 @@ const BUFFER_SIZE: usize = 16_384;
 /// ## Errors
 /// if the path does not start with a tilde or if the HOME environment variable
 /// is not set
-pub fn expand_home_dir<P: AsRef<Path> + ?Sized>(path: &P) -> Result<Cow<Path>, String> {
+pub fn expand_home_dir<P: AsRef<Path> + ?Sized>(path: &P) -> Result<Cow<'_, Path>, String> {
     let path = path.as_ref();
 
     if !path.starts_with("~") {
@@ -35,6 +74,12 @@ pub fn expand_home_dir<P: AsRef<Path> + ?Sized>(path: &P) -> Result<Cow<Path>, S
         .into())
 }
 
+/// Quote `arg` for the POSIX shell the server runs `exec` commands with.
+#[must_use]
+pub fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
 /// Simple program to forward a local port to a remote port on a remote host.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -46,23 +91,386 @@ pub struct Arguments {
     #[arg(short, long)]
     pub ip: Ipv4Addr,
     /// The port on the remote host to connect to (e.g. 8000).
-    #[arg(short, long)]
-    pub remote_port: u16,
+    #[arg(short, long, requires = "local_port")]
+    pub remote_port: Option<u16>,
     /// The local port to listen on (e.g 9876).
-    #[arg(short, long)]
-    pub local_port: u16,
+    #[arg(short, long, requires = "remote_port")]
+    pub local_port: Option<u16>,
+    /// Forward a local port or Unix socket to a host or Unix socket reachable
+    /// from the server, as with `ssh -L` ([bind_address:]port:host:hostport,
+    /// where either side may be a /path/to/socket). May be repeated.
+    #[arg(short = 'L', long = "local-forward", value_name = "SPEC",
+          value_parser = ForwardSpec::parse_local)]
+    pub local_forwards: Vec<ForwardSpec>,
+    /// Forward a port on the server to a host or Unix socket reachable from
+    /// here, as with `ssh -R` ([bind_address:]port:host:hostport or
+    /// [bind_address:]port:/local/socket). May be repeated.
+    #[arg(short = 'R', long = "remote-forward", value_name = "SPEC",
+          value_parser = ForwardSpec::parse_remote)]
+    pub remote_forwards: Vec<ForwardSpec>,
+    /// Forward a local UDP port to a host reachable from the server
+    /// ([bind_address:]port:host:hostport). Needs `--udp-relay-command` to
+    /// work on the server. May be repeated.
+    #[arg(short = 'U', long = "udp-forward", value_name = "SPEC", value_parser = UdpSpec::parse)]
+    pub udp_forwards: Vec<UdpSpec>,
+    /// The command run on the server to relay `-U` datagrams; it is given the
+    /// target host:port and must behave like this binary's `udp-relay`.
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        default_value = "russh-port-forward udp-relay"
+    )]
+    pub udp_relay_command: String,
+    /// Permissions, in octal, of the Unix sockets local forwards listen on.
+    #[arg(long, value_name = "MODE", default_value = "600", value_parser = unix_socket::parse_mode)]
+    pub socket_mode: u32,
     /// The path to the private key to use for authentication.
     #[arg(short, long)]
     pub private_key_path: PathBuf,
     /// The path to the public key to use for authentication.
     #[arg(short = 'k', long)]
     pub public_key_path: Option<PathBuf>,
+    /// When the server refuses a forwarding channel as administratively
+    /// prohibited (e.g. `AllowTcpForwarding no`), reach the target by running
+    /// `nc`, `socat` or bash's `/dev/tcp` on the server instead.
+    #[arg(long)]
+    pub exec_fallback: bool,
+    /// Start every forwarded connection with a PROXY protocol header, so the
+    /// target sees the client's address instead of the tunnel's. The target
+    /// must be configured to expect it.
+    #[arg(long, value_name = "VERSION")]
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Open this many SSH sessions to the server and give each new
+    /// connection to the one with the fewest open. libssh2 serializes all
+    /// the channels of a session, so the libssh2 backends gain the most;
+    /// russh spreads only local forwards and drops sessions that fail.
+    #[arg(long, value_name = "N", default_value = "1")]
+    pub sessions: NonZeroUsize,
+    /// Keep this many channels to each local forward's target open ahead of
+    /// time, so new connections skip the channel-open round trip. The target
+    /// sees them as idle connections from the server; ones that close while
+    /// idle are replaced. They are opened before any client arrives, so the
+    /// server is told they come from 127.0.0.1:65535, not from the client that
+    /// gets one: leave this off if the server logs or checks originators.
+    /// russh and ssh2-rs only; with ssh2-rs each `--sessions` session keeps
+    /// its own.
+    #[arg(long, value_name = "K", default_value = "0")]
+    pub preopen_channels: usize,
+    /// Listen at once, but connect to the server only when the first client
+    /// arrives, so a tunnel that is rarely used keeps no session open. Local
+    /// forwards only. russh only.
+    #[arg(long)]
+    pub lazy: bool,
+    /// With --lazy, disconnect from the server once no forwarded connection
+    /// has been open for this long; the next client connects again. russh
+    /// only.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub idle_disconnect: Option<Duration>,
+    /// Initial flow-control window of each channel (e.g. 16MiB); larger
+    /// windows keep high-latency links busy. russh only.
+    #[arg(long, value_name = "SIZE")]
+    pub window_size: Option<ByteSize>,
+    /// Largest packet the server may send on a channel. russh only.
+    #[arg(long, value_name = "SIZE")]
+    pub max_packet_size: Option<ByteSize>,
+    /// Messages buffered per channel before the session stops reading from
+    /// the server. russh only.
+    #[arg(long, value_name = "N")]
+    pub channel_buffer_size: Option<usize>,
+    /// Disconnect from the server after this long without any traffic.
+    /// russh only.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub inactivity_timeout: Option<Duration>,
+    /// Send a keepalive after hearing nothing from the server for this long.
+    /// russh only.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub keepalive_interval: Option<Duration>,
+    /// Disconnect after this many keepalives go unanswered. russh only.
+    #[arg(long, value_name = "N")]
+    pub keepalive_max: Option<usize>,
+    /// Re-exchange keys after this much data either way (at most 1GiB).
+    /// russh only.
+    #[arg(long, value_name = "SIZE")]
+    pub rekey_data: Option<ByteSize>,
+    /// Re-exchange keys after this long. russh only.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub rekey_time: Option<Duration>,
+    /// Key exchange algorithms to offer, most preferred first (e.g.
+    /// curve25519-sha256,diffie-hellman-group14-sha256).
+    #[arg(long, value_name = "ALGORITHMS", value_delimiter = ',')]
+    pub kex_algorithms: Vec<String>,
+    /// Ciphers to offer, most preferred first.
+    #[arg(long, value_name = "ALGORITHMS", value_delimiter = ',')]
+    pub ciphers: Vec<String>,
+    /// MACs to offer, most preferred first.
+    #[arg(long, value_name = "ALGORITHMS", value_delimiter = ',')]
+    pub macs: Vec<String>,
+    /// Host key algorithms to accept, most preferred first (e.g.
+    /// ssh-ed25519,rsa-sha2-256).
+    #[arg(long, value_name = "ALGORITHMS", value_delimiter = ',')]
+    pub host_key_algorithms: Vec<String>,
+    /// Ask the server to compress the session with zlib, which pays off on
+    /// slow links carrying compressible data.
+    #[arg(long)]
+    pub compression: bool,
+    /// Forwarded connections open at once, across all forwards.
+    #[arg(long, value_name = "N")]
+    pub max_connections: Option<usize>,
+    /// Forwarded connections open at once on any single forward.
+    #[arg(long, value_name = "N")]
+    pub max_connections_per_forward: Option<usize>,
+    /// Accepted connections still waiting for their channel to open.
+    #[arg(long, value_name = "N")]
+    pub max_pending_opens: Option<usize>,
+    /// What to do with new clients while a connection limit is reached.
+    #[arg(long, value_enum, value_name = "ACTION", default_value_t)]
+    pub on_limit: LimitAction,
+    /// Close a forwarded connection once no bytes have moved either way for
+    /// this long (e.g. 90s, 15m).
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub idle_timeout: Option<Duration>,
+    /// Bandwidth of each forward, shared by its connections: RATE for upload
+    /// and download each (e.g. 5MiB/s), or UP,DOWN.
+    #[arg(long, value_name = "RATE")]
+    pub rate_limit: Option<RateLimit>,
+    /// Bandwidth of each connection, as for --rate-limit.
+    #[arg(long, value_name = "RATE")]
+    pub connection_rate_limit: Option<RateLimit>,
+    /// Serve the connections of the forward listening on LISTEN (its port,
+    /// bind_address:port or /path/to/socket) with priority CLASS: high,
+    /// normal or low. While several have data, each pass over the session
+    /// serves high forwards first and with twice the share of normal ones,
+    /// and low forwards, such as bulk transfers, last with half. ssh2-rs
+    /// only. May be repeated.
+    #[arg(long = "priority", value_name = "LISTEN=CLASS")]
+    pub priorities: Vec<ForwardPriority>,
+    /// On SIGINT or SIGTERM, stop accepting and give open connections this
+    /// long to finish before disconnecting. A second signal exits at once.
+    #[arg(long, value_name = "DURATION", default_value = "10s",
+          value_parser = humantime::parse_duration)]
+    pub drain_timeout: Duration,
+    /// Serve Prometheus metrics over HTTP on this address (e.g.
+    /// 127.0.0.1:9100).
+    #[arg(long)]
+    pub metrics_listen: Option<SocketAddr>,
+    /// Instead of listening, connect stdin/stdout to HOST:PORT (or a
+    /// /path/to/socket) on the server's side and exit when it closes, as
+    /// with `ssh -W`. Usable as an OpenSSH `ProxyCommand`.
+    #[arg(short = 'W', long, value_name = "HOST:PORT",
+          conflicts_with_all = ["local_port", "local_forwards", "remote_forwards", "udp_forwards",
+                                "control_path"])]
+    pub stdio: Option<Endpoint>,
+    /// Accept runtime commands (see `ctl`) on a Unix socket at this path.
+    #[arg(short = 'S', long)]
+    pub control_path: Option<PathBuf>,
+    /// Go into the background once authenticated and listening, like `ssh
+    /// -f`. The command returns then, with status 0, or 1 if the tunnel failed
+    /// to come up.
+    #[arg(short = 'f', long, conflicts_with = "stdio")]
+    pub daemon: bool,
+    /// Write the tunnel's process id to this file once it is up, and remove
+    /// it on exit.
+    #[arg(long, value_name = "FILE")]
+    pub pidfile: Option<PathBuf>,
+    /// Exit once no connection has been open for this long. Meant for systemd
+    /// socket activation, which starts the tunnel again on the next
+    /// connection to the sockets it passes in.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub exit_idle_time: Option<Duration>,
+    /// Read profiles from this TOML file instead of
+    /// ~/.config/port-forward/config.toml.
+    #[arg(long, value_name = "FILE", requires = "profile")]
+    pub config: Option<PathBuf>,
+    /// Take options from this profile of the config file. Options given here
+    /// override the profile's; forwards are added to its. On SIGHUP the russh
+    /// backend reads the file again and starts and stops forwards to match;
+    /// the others log that they ignore it.
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+}
+
+impl Arguments {
+    /// Every forward requested on the command line; `--local-port` /
+    /// `--remote-port` is shorthand for `-L local_port:127.0.0.1:remote_port`.
+    #[must_use]
+    pub fn forwards(&self) -> Vec<ForwardSpec> {
+        let shorthand = self
+            .local_port
+            .zip(self.remote_port)
+            .map(|(local_port, remote_port)| ForwardSpec {
+                direction: Direction::Local,
+                listen: Endpoint::Tcp(HostPort {
+                    host: ForwardSpec::DEFAULT_BIND.to_owned(),
+                    port: local_port,
+                }),
+                // The literal address, not "localhost": sshd resolves the
+                // forward target itself, and "localhost" yields ::1 first on
+                // hosts with an IPv6 loopback, leaving IPv4-only targets
+                // reachable only via sshd's fallback from the refused ::1
+                // attempt.
+                target: Endpoint::Tcp(HostPort {
+                    host: "127.0.0.1".to_owned(),
+                    port: remote_port,
+                }),
+            });
+
+        shorthand
+            .into_iter()
+            .chain(self.local_forwards.iter().cloned())
+            .chain(self.remote_forwards.iter().cloned())
+            .collect()
+    }
+
+    /// The `--priority` of `spec`; the last option naming it wins.
+    #[must_use]
+    pub fn priority(&self, spec: &ForwardSpec) -> Priority {
+        self.priorities
+            .iter()
+            .rev()
+            .find(|priority| priority.matches(spec))
+            .map_or_else(Priority::default, |priority| priority.priority)
+    }
+
+    /// The `--max-connections*` limits, what to do when one is reached,
+    /// `--idle-timeout` and the rate limits.
+    #[must_use]
+    pub const fn limits(&self) -> Limits {
+        Limits {
+            max_connections: self.max_connections,
+            max_connections_per_forward: self.max_connections_per_forward,
+            max_pending_opens: self.max_pending_opens,
+            on_limit: self.on_limit,
+            idle_timeout: self.idle_timeout,
+            rate_limit: self.rate_limit,
+            connection_rate_limit: self.connection_rate_limit,
+        }
+    }
+
+    /// The flow-control, keepalive, rekeying, algorithm and compression
+    /// options.
+    #[must_use]
+    pub fn transport(&self) -> Transport {
+        Transport {
+            window_size: self.window_size,
+            max_packet_size: self.max_packet_size,
+            channel_buffer_size: self.channel_buffer_size,
+            inactivity_timeout: self.inactivity_timeout,
+            keepalive_interval: self.keepalive_interval,
+            keepalive_max: self.keepalive_max,
+            rekey_data: self.rekey_data,
+            rekey_time: self.rekey_time,
+            kex_algorithms: self.kex_algorithms.clone(),
+            ciphers: self.ciphers.clone(),
+            macs: self.macs.clone(),
+            host_key_algorithms: self.host_key_algorithms.clone(),
+            compression: self.compression,
+        }
+    }
+}
+
+/// Simple program to forward a local port to a remote port on a remote host.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(flatten)]
+    tunnel: Option<Arguments>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send a command to a running tunnel's control socket.
+    Ctl(CtlArguments),
+    /// Relay `-U` datagrams to a target; run on the server by the tunnel.
+    UdpRelay(UdpRelayArguments),
+}
+
+/// What the binary was asked to do.
+#[derive(Debug)]
+pub enum Invocation {
+    /// Run a tunnel.
+    Tunnel(Box<Arguments>),
+    /// Talk to a running tunnel's control socket.
+    Ctl(CtlArguments),
+    /// Serve as the server-side UDP relay.
+    UdpRelay(UdpRelayArguments),
 }
 
 /// Get arguments from the command line.
 #[must_use]
 pub fn get_args() -> Arguments {
-    Arguments::parse()
+    parse_or_exit(|args: &mut Arguments| Some(args))
+}
+
+/// Like [`get_args`], but also accepts the `ctl` and `udp-relay`
+/// subcommands, for the backend that implements them.
+#[must_use]
+pub fn get_invocation() -> Invocation {
+    let cli: Cli = parse_or_exit(|cli: &mut Cli| cli.tunnel.as_mut());
+    match (cli.command, cli.tunnel) {
+        (Some(Command::Ctl(ctl)), _) => Invocation::Ctl(ctl),
+        (Some(Command::UdpRelay(relay)), _) => Invocation::UdpRelay(relay),
+        (None, Some(tunnel)) => Invocation::Tunnel(Box::new(tunnel)),
+        // Every tunnel argument group has required members, so clap only
+        // yields neither when it has already printed an error.
+        (None, None) => unreachable!("clap requires either tunnel arguments or a subcommand"),
+    }
+}
+
+/// Parse the tunnel's command line again, re-reading its `--profile`, for a
+/// reload.
+///
+/// ## Errors
+/// if the config file cannot be read, has a problem or lost the profile, or
+/// the command line no longer parses with it
+pub fn reload_args() -> Result<Arguments, String> {
+    let command = command::<Cli>();
+    let profile = Profile::selected(&command)?;
+    let cli: Cli =
+        parse(command, profile, |cli: &mut Cli| cli.tunnel.as_mut()).map_err(|e| e.to_string())?;
+    cli.tunnel
+        .ok_or_else(|| "the command line has no tunnel arguments".to_owned())
+}
+
+/// Parse the command line as `T`, exiting with clap's message on errors.
+fn parse_or_exit<T: CommandFactory + FromArgMatches>(
+    tunnel: impl FnOnce(&mut T) -> Option<&mut Arguments>,
+) -> T {
+    let mut command = command::<T>();
+    let profile = Profile::selected(&command)
+        .unwrap_or_else(|e| command.error(ErrorKind::InvalidValue, e).exit());
+    parse(command, profile, tunnel).unwrap_or_else(|e| e.exit())
+}
+
+fn command<T: CommandFactory>() -> clap::Command {
+    let command = T::command();
+    // Name the binary in errors about the profile as clap would in its own.
+    match env::args_os()
+        .next()
+        .and_then(|arg0| Some(Path::new(&arg0).file_name()?.to_str()?.to_owned()))
+    {
+        Some(name) => command.bin_name(name),
+        None => command,
+    }
+}
+
+/// Parse the command line as `T`, taking what it leaves out from `profile`
+/// if `tunnel` finds tunnel arguments in it.
+fn parse<T: FromArgMatches>(
+    mut command: clap::Command,
+    profile: Option<Profile>,
+    tunnel: impl FnOnce(&mut T) -> Option<&mut Arguments>,
+) -> Result<T, clap::Error> {
+    if let Some(profile) = &profile {
+        command = profile.relax(command);
+    }
+    let matches = command.try_get_matches_from_mut(env::args_os())?;
+    let mut parsed = T::from_arg_matches(&matches).map_err(|e| e.format(&mut command))?;
+    if let (Some(profile), Some(args)) = (profile, tunnel(&mut parsed)) {
+        profile.apply(args, &matches);
+    }
+    Ok(parsed)
 }
 
 #[instrument(skip(reader_buf))]
@@ -0,0 +1,307 @@
+//! Connection limits (`--max-connections`, `--max-connections-per-forward`,
+//! `--max-pending-opens`).
+//!
+//! Every forwarded connection holds a [`Slot`] from the moment it is accepted
+//! until it closes, and a pending-open slot until its channel is open. When a
+//! forward has no free slot it either leaves new clients in the kernel's listen
+//! backlog or accepts and resets them (`--on-limit`).
+
+use std::{io, os::fd::AsFd, sync::Arc, time::Duration};
+
+use clap::ValueEnum;
+use socket2::SockRef;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::rate_limit::{RateLimit, Throttles};
+
+/// What a forward does with new clients while it is at a limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LimitAction {
+    /// Stop accepting until a slot frees up; clients wait in the backlog.
+    #[default]
+    Backlog,
+    /// Accept and immediately reset the connection.
+    Reset,
+}
+
+/// The limit flags, see [`crate::Arguments::limits`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_forward: Option<usize>,
+    pub max_pending_opens: Option<usize>,
+    pub on_limit: LimitAction,
+    pub idle_timeout: Option<Duration>,
+    pub rate_limit: Option<RateLimit>,
+    pub connection_rate_limit: Option<RateLimit>,
+}
+
+/// Make `stream` close with a TCP RST rather than a FIN when dropped, so a
+/// client turned away by a limit sees an error instead of an empty reply.
+///
+/// ## Errors
+/// if `SO_LINGER` cannot be set
+pub fn reset_on_close<S: AsFd>(stream: &S) -> io::Result<()> {
+    SockRef::from(stream).set_linger(Some(Duration::ZERO))
+}
+
+/// Slots shared by every forward of a tunnel.
+#[derive(Debug)]
+pub struct Limiter {
+    action: LimitAction,
+    per_forward: Option<usize>,
+    connections: Option<Arc<Semaphore>>,
+    pending_opens: Option<Arc<Semaphore>>,
+    idle_timeout: Option<Duration>,
+    rate_limit: Option<RateLimit>,
+    connection_rate_limit: Option<RateLimit>,
+}
+
+impl Limiter {
+    #[must_use]
+    pub fn new(limits: Limits) -> Arc<Self> {
+        let semaphore = |limit: Option<usize>| limit.map(|n| Arc::new(Semaphore::new(n)));
+        Arc::new(Self {
+            action: limits.on_limit,
+            per_forward: limits.max_connections_per_forward,
+            connections: semaphore(limits.max_connections),
+            pending_opens: semaphore(limits.max_pending_opens),
+            idle_timeout: limits.idle_timeout,
+            rate_limit: limits.rate_limit,
+            connection_rate_limit: limits.connection_rate_limit,
+        })
+    }
+
+    /// The slots of one more forward.
+    #[must_use]
+    pub fn forward(self: &Arc<Self>) -> ForwardLimiter {
+        ForwardLimiter {
+            shared: Arc::clone(self),
+            connections: self.per_forward.map(|n| Arc::new(Semaphore::new(n))),
+            throttles: Throttles::default().with(self.rate_limit),
+        }
+    }
+}
+
+/// The slots of one forward, on top of the tunnel-wide ones.
+#[derive(Clone, Debug)]
+pub struct ForwardLimiter {
+    shared: Arc<Limiter>,
+    connections: Option<Arc<Semaphore>>,
+    /// The forward's shared buckets.
+    throttles: Throttles,
+}
+
+impl ForwardLimiter {
+    /// With `--on-limit backlog`, wait until this forward may take another
+    /// connection, before accepting it. With `--on-limit reset` this returns
+    /// `None` at once and [`ForwardLimiter::admit`] decides after accepting
+    /// instead.
+    ///
+    /// Only the forward's own slot is held from here on. The tunnel-wide ones
+    /// are merely waited for, so an idle listener does not keep them from the
+    /// other forwards; the client takes them in [`ForwardLimiter::admit`].
+    pub async fn wait(&self) -> Option<Reserved> {
+        if self.shared.action != LimitAction::Backlog {
+            return None;
+        }
+        let forward = acquire(self.connections.as_ref()).await;
+        drop(acquire(self.shared.connections.as_ref()).await);
+        drop(acquire(self.shared.pending_opens.as_ref()).await);
+        Some(Reserved { forward })
+    }
+
+    /// The slot for a client just accepted, after [`ForwardLimiter::wait`].
+    ///
+    /// With `--on-limit backlog` this waits for the tunnel-wide slots, should
+    /// another forward's client have taken them since. With `--on-limit
+    /// reset` it returns `None` if any limit is reached.
+    pub async fn admit(&self, reserved: Option<Reserved>) -> Option<Slot> {
+        let Some(Reserved { forward }) = reserved else {
+            return self.try_acquire();
+        };
+        // Always in this order, so two forwards never wait on each other.
+        Some(Slot {
+            _forward: forward,
+            _global: acquire(self.shared.connections.as_ref()).await,
+            pending_open: acquire(self.shared.pending_opens.as_ref()).await,
+            idle_timeout: self.shared.idle_timeout,
+            throttles: self.connection_throttles(),
+        })
+    }
+
+    /// A slot for a connection that has already arrived, or `None` if any
+    /// limit is reached.
+    #[must_use]
+    pub fn try_acquire(&self) -> Option<Slot> {
+        Some(Slot {
+            _forward: try_acquire(self.connections.as_ref())?,
+            _global: try_acquire(self.shared.connections.as_ref())?,
+            pending_open: try_acquire(self.shared.pending_opens.as_ref())?,
+            idle_timeout: self.shared.idle_timeout,
+            throttles: self.connection_throttles(),
+        })
+    }
+
+    fn connection_throttles(&self) -> Throttles {
+        self.throttles.with(self.shared.connection_rate_limit)
+    }
+}
+
+/// A forward's own slot, held while it waits to accept a client.
+#[derive(Debug)]
+pub struct Reserved {
+    forward: Option<OwnedSemaphorePermit>,
+}
+
+/// One connection's hold on its limits, released when dropped.
+#[derive(Debug)]
+pub struct Slot {
+    _forward: Option<OwnedSemaphorePermit>,
+    _global: Option<OwnedSemaphorePermit>,
+    pending_open: Option<OwnedSemaphorePermit>,
+    idle_timeout: Option<Duration>,
+    throttles: Throttles,
+}
+
+impl Slot {
+    /// The connection's channel is open; it no longer counts as pending.
+    pub fn opened(&mut self) {
+        self.pending_open = None;
+    }
+
+    /// How long the connection may go without traffic, see
+    /// [`crate::stats::ConnectionGuard::idle`].
+    #[must_use]
+    pub const fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// The buckets the connection's reads draw from, see
+    /// [`crate::rate_limit::Throttled`].
+    #[must_use]
+    pub const fn throttles(&self) -> &Throttles {
+        &self.throttles
+    }
+}
+
+async fn acquire(semaphore: Option<&Arc<Semaphore>>) -> Option<OwnedSemaphorePermit> {
+    match semaphore {
+        Some(semaphore) => Some(
+            Arc::clone(semaphore)
+                .acquire_owned()
+                .await
+                .expect("limit semaphores are never closed"),
+        ),
+        None => None,
+    }
+}
+
+/// `Some(None)` when there is no limit, `None` when it is reached.
+fn try_acquire(semaphore: Option<&Arc<Semaphore>>) -> Option<Option<OwnedSemaphorePermit>> {
+    match semaphore {
+        Some(semaphore) => Arc::clone(semaphore).try_acquire_owned().ok().map(Some),
+        None => Some(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+
+    use super::*;
+
+    /// Whether `future` is still waiting after a short while.
+    async fn blocks<F: Future>(future: F) -> bool {
+        tokio::time::timeout(Duration::from_millis(50), future)
+            .await
+            .is_err()
+    }
+
+    fn limits(on_limit: LimitAction) -> Limits {
+        Limits {
+            on_limit,
+            ..Limits::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn backlog_waits_for_a_free_slot() {
+        let limiter = Limiter::new(Limits {
+            max_connections_per_forward: Some(1),
+            ..limits(LimitAction::Backlog)
+        });
+        let forward = limiter.forward();
+        let slot = forward.admit(forward.wait().await).await.unwrap();
+        assert!(blocks(forward.wait()).await);
+        // Another forward has slots of its own.
+        let other = limiter.forward();
+        assert!(other.admit(other.wait().await).await.is_some());
+
+        drop(slot);
+        assert!(!blocks(forward.wait()).await);
+    }
+
+    #[tokio::test]
+    async fn idle_listeners_leave_shared_slots_to_others() {
+        let limiter = Limiter::new(Limits {
+            max_connections: Some(1),
+            max_pending_opens: Some(1),
+            ..limits(LimitAction::Backlog)
+        });
+        let (a, b) = (limiter.forward(), limiter.forward());
+        // Both listeners are ready to accept, and hold nothing shared.
+        let reserved_a = a.wait().await;
+        let reserved_b = b.wait().await;
+        let slot = b.admit(reserved_b).await.unwrap();
+
+        // `a`'s client waits for the slot `b`'s took meanwhile.
+        assert!(blocks(a.admit(reserved_a)).await);
+        assert!(blocks(a.wait()).await);
+        drop(slot);
+        let reserved_a = a.wait().await;
+        assert!(a.admit(reserved_a).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn opened_releases_the_pending_open_slot() {
+        let limiter = Limiter::new(Limits {
+            max_pending_opens: Some(1),
+            ..limits(LimitAction::Backlog)
+        });
+        let (a, b) = (limiter.forward(), limiter.forward());
+        let mut slot = a.admit(a.wait().await).await.unwrap();
+        assert!(blocks(b.wait()).await);
+
+        // The connection still holds its other slots, but no longer counts
+        // as pending.
+        slot.opened();
+        let other = b.admit(b.wait().await).await.unwrap();
+        assert!(blocks(a.wait()).await);
+        drop(other);
+        drop(slot);
+    }
+
+    #[tokio::test]
+    async fn reset_refuses_clients_over_any_limit() {
+        let limiter = Limiter::new(Limits {
+            max_connections: Some(2),
+            max_connections_per_forward: Some(1),
+            ..limits(LimitAction::Reset)
+        });
+        let (a, b, c) = (limiter.forward(), limiter.forward(), limiter.forward());
+        assert!(a.wait().await.is_none());
+
+        let slot_a = a.admit(None).await.unwrap();
+        // Over the per-forward limit.
+        assert!(a.admit(None).await.is_none());
+        let slot_b = b.admit(None).await.unwrap();
+        // Over the tunnel-wide one.
+        assert!(c.admit(None).await.is_none());
+
+        // A refusal gives back what it took on the way.
+        drop(slot_a);
+        assert!(c.admit(None).await.is_some());
+        drop(slot_b);
+    }
+}
@@ -0,0 +1,294 @@
+//! Prometheus text-format exposition of [`Stats`].
+//!
+//! The endpoint is served from a plain `std` thread rather than a tokio task
+//! so the synchronous ssh2-rs backend can use it unchanged. Scrapes are rare
+//! and tiny, so requests are handled one at a time.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use tracing::{debug, info, warn};
+
+use crate::stats::{ErrorKind, Histogram, Stats};
+
+/// A scraper that has not sent its request by then, or stops reading the
+/// response for that long, is dropped; requests are served one at a time.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Requests are a single `GET` line plus a few headers; anything bigger is
+/// not a scrape.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Bind `addr` and serve `/metrics` from a background thread.
+///
+/// ## Errors
+/// if the address cannot be bound or the thread cannot be spawned
+pub fn spawn(addr: SocketAddr, stats: Arc<Stats>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!(
+        "serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    thread::Builder::new()
+        .name("metrics".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = serve_one(stream, &stats) {
+                            debug!("metrics request failed: {e}");
+                        }
+                    }
+                    Err(e) => warn!("metrics accept failed: {e}"),
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn serve_one(mut stream: TcpStream, stats: &Stats) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 || request.len() + n > MAX_REQUEST_SIZE {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request_line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|&b| b == b' ');
+    let (method, path) = (parts.next(), parts.next());
+
+    let (status, body) = match (method, path) {
+        (Some(b"GET"), Some(b"/metrics" | b"/")) => ("200 OK", render(stats)),
+        (Some(b"GET"), _) => ("404 Not Found", "not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_owned()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: \
+         {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// Render every metric in the Prometheus text exposition format.
+#[must_use]
+pub fn render(stats: &Stats) -> String {
+    let mut out = String::new();
+
+    metric(
+        &mut out,
+        "port_forward_uptime_seconds",
+        "gauge",
+        "Seconds since the tunnel started.",
+    );
+    let _ = writeln!(
+        out,
+        "port_forward_uptime_seconds {}",
+        stats.uptime().as_secs_f64()
+    );
+
+    metric(
+        &mut out,
+        "port_forward_bytes_total",
+        "counter",
+        "Bytes forwarded; up is local to remote, down is remote to local.",
+    );
+    let _ = writeln!(
+        out,
+        "port_forward_bytes_total{{direction=\"up\"}} {}",
+        stats.bytes_up()
+    );
+    let _ = writeln!(
+        out,
+        "port_forward_bytes_total{{direction=\"down\"}} {}",
+        stats.bytes_down()
+    );
+
+    metric(
+        &mut out,
+        "port_forward_connections_active",
+        "gauge",
+        "Forwarded connections currently open.",
+    );
+    let _ = writeln!(
+        out,
+        "port_forward_connections_active {}",
+        stats.active_connections()
+    );
+
+    metric(
+        &mut out,
+        "port_forward_connections_total",
+        "counter",
+        "Forwarded connections opened since start.",
+    );
+    let _ = writeln!(
+        out,
+        "port_forward_connections_total {}",
+        stats.total_connections()
+    );
+
+    metric(
+        &mut out,
+        "port_forward_errors_total",
+        "counter",
+        "Errors by kind.",
+    );
+    for kind in ErrorKind::ALL {
+        let _ = writeln!(
+            out,
+            "port_forward_errors_total{{kind=\"{kind}\"}} {}",
+            stats.errors(kind)
+        );
+    }
+
+    metric(
+        &mut out,
+        "port_forward_reconnects_total",
+        "counter",
+        "SSH sessions re-opened after failing or being closed while idle (russh --lazy).",
+    );
+    let _ = writeln!(out, "port_forward_reconnects_total {}", stats.reconnects());
+
+    metric(
+        &mut out,
+        "port_forward_keepalive_failures_total",
+        "counter",
+        "SSH keepalives that could not be sent or were not answered.",
+    );
+    let _ = writeln!(
+        out,
+        "port_forward_keepalive_failures_total {}",
+        stats.keepalive_failures()
+    );
+
+    histogram(
+        &mut out,
+        "port_forward_channel_open_latency_seconds",
+        "Time from accepting a client to its channel being open.",
+        stats.open_latency(),
+    );
+    histogram(
+        &mut out,
+        "port_forward_connection_duration_seconds",
+        "Lifetime of closed forwarded connections.",
+        stats.duration(),
+    );
+
+    out
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    metric(out, name, "histogram", help);
+    for (bound, count) in histogram.cumulative_buckets() {
+        let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+    }
+    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", histogram.count());
+    let _ = writeln!(out, "{name}_sum {}", histogram.sum().as_secs_f64());
+    let _ = writeln!(out, "{name}_count {}", histogram.count());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_counters_and_histograms() {
+        let stats = Stats::new();
+        let guard = stats.open_connection("f", "p", "direct-tcpip", Duration::from_millis(20));
+        guard.record_up(10);
+        guard.record_down(20);
+        stats.record_error(ErrorKind::ChannelOpen);
+        stats.record_reconnect();
+
+        let text = render(&stats);
+        for line in [
+            "# TYPE port_forward_bytes_total counter",
+            "port_forward_bytes_total{direction=\"up\"} 10",
+            "port_forward_bytes_total{direction=\"down\"} 20",
+            "port_forward_connections_active 1",
+            "port_forward_connections_total 1",
+            "port_forward_errors_total{kind=\"channel_open\"} 1",
+            "port_forward_errors_total{kind=\"limit\"} 0",
+            "port_forward_reconnects_total 1",
+            "port_forward_keepalive_failures_total 0",
+            "# TYPE port_forward_channel_open_latency_seconds histogram",
+            "port_forward_channel_open_latency_seconds_bucket{le=\"0.01\"} 0",
+            "port_forward_channel_open_latency_seconds_bucket{le=\"0.025\"} 1",
+            "port_forward_channel_open_latency_seconds_bucket{le=\"+Inf\"} 1",
+            "port_forward_channel_open_latency_seconds_sum 0.02",
+            "port_forward_channel_open_latency_seconds_count 1",
+            "port_forward_connection_duration_seconds_count 0",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+        // Every sample line belongs to a metric declared before it.
+        let mut declared = Vec::new();
+        for line in text.lines() {
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                declared.push(rest.split(' ').next().unwrap());
+            } else if !line.starts_with('#') {
+                let name = line.split(['{', ' ']).next().unwrap();
+                assert!(
+                    declared.iter().any(|d| name.starts_with(d)),
+                    "{line:?} is undeclared"
+                );
+            }
+        }
+
+        drop(guard);
+        assert!(render(&stats)
+            .lines()
+            .any(|l| l == "port_forward_connection_duration_seconds_count 1"));
+    }
+
+    fn scrape(stats: &Stats, request: &[u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(request).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        serve_one(server, stats).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_metrics_over_http() {
+        let stats = Stats::new();
+        let response = scrape(&stats, b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(body.contains("port_forward_connections_total 0\n"));
+
+        let response = scrape(&stats, b"GET /other HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = scrape(&stats, b"POST /metrics HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
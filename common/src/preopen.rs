@@ -0,0 +1,295 @@
+//! Pre-opened channels (`--preopen-channels`).
+//!
+//! A [`ChannelPool`] keeps up to K idle channels to one forward's target open,
+//! so an accepted connection is spliced at once instead of waiting a round
+//! trip for its channel. Whatever the target sends before a client claims a
+//! channel is kept for that client, and a channel closed meanwhile is replaced.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    select,
+    sync::{oneshot, Notify},
+    task::JoinHandle,
+    time::sleep,
+};
+use tracing::debug;
+
+/// How long to wait before pre-opening again after an open failed.
+pub const REFILL_BACKOFF: Duration = Duration::from_secs(1);
+/// Early data kept per idle channel; the rest waits in the SSH window.
+const MAX_EARLY: usize = 64 * 1024;
+
+/// A channel the pool can keep idle.
+pub trait IdleChannel: Send + Sized + 'static {
+    /// Wait for the next message on the channel, appending any data it
+    /// carries to `early`. Returns `false` once the channel is closing.
+    fn watch(&mut self, early: &mut Vec<u8>) -> impl Future<Output = bool> + Send;
+
+    /// Close a channel nobody will use.
+    fn close(self) -> impl Future<Output = ()> + Send;
+}
+
+/// A pre-opened channel, claimed by a connection.
+pub struct Preopened<C> {
+    pub channel: C,
+    /// What the target sent while the channel was idle, for the client.
+    pub early: Vec<u8>,
+}
+
+/// Handed to an idle channel's watcher to take the channel over.
+type Claim<C> = oneshot::Sender<Preopened<C>>;
+
+struct Shared<C> {
+    /// One per idle channel, oldest first.
+    idle: Mutex<VecDeque<oneshot::Sender<Claim<C>>>>,
+    /// A channel was claimed or went away.
+    changed: Notify,
+}
+
+/// The pre-opened channels of one forward. Dropping the pool stops refilling
+/// it and closes the idle channels.
+pub struct ChannelPool<C> {
+    shared: Arc<Shared<C>>,
+    refill: JoinHandle<()>,
+}
+
+impl<C: IdleChannel> ChannelPool<C> {
+    /// Keep `size` channels from `open` idle. `open` reports its own failures
+    /// and returns `None`; the pool then tries again after [`REFILL_BACKOFF`].
+    pub fn new<F, Fut>(size: usize, open: F) -> Self
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Option<C>> + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            idle: Mutex::new(VecDeque::with_capacity(size)),
+            changed: Notify::new(),
+        });
+        let refill = tokio::spawn(refill(size, open, Arc::clone(&shared)));
+        Self { shared, refill }
+    }
+
+    /// Take an idle channel, if one is still open.
+    pub async fn claim(&self) -> Option<Preopened<C>> {
+        loop {
+            let watcher = self.shared.idle.lock().unwrap().pop_front()?;
+            let (claim, claimed) = oneshot::channel();
+            // Either fails if the channel closed in the meantime.
+            if watcher.send(claim).is_ok() {
+                if let Ok(preopened) = claimed.await {
+                    return Some(preopened);
+                }
+            }
+        }
+    }
+}
+
+impl<C> Drop for ChannelPool<C> {
+    fn drop(&mut self) {
+        self.refill.abort();
+        // The watchers see their claim senders go and close the channels.
+        self.shared.idle.lock().unwrap().clear();
+    }
+}
+
+async fn refill<C, F, Fut>(size: usize, open: F, shared: Arc<Shared<C>>)
+where
+    C: IdleChannel,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Option<C>>,
+{
+    loop {
+        let idle = {
+            let mut idle = shared.idle.lock().unwrap();
+            idle.retain(|watcher| !watcher.is_closed());
+            idle.len()
+        };
+        if idle >= size {
+            shared.changed.notified().await;
+            continue;
+        }
+        let Some(channel) = open().await else {
+            sleep(REFILL_BACKOFF).await;
+            continue;
+        };
+        let (watcher, claims) = oneshot::channel();
+        shared.idle.lock().unwrap().push_back(watcher);
+        tokio::spawn(watch(channel, claims, Arc::clone(&shared)));
+    }
+}
+
+/// Keep an idle channel until it is claimed, it closes or the pool goes.
+async fn watch<C: IdleChannel>(
+    mut channel: C,
+    mut claims: oneshot::Receiver<Claim<C>>,
+    shared: Arc<Shared<C>>,
+) {
+    let mut early = Vec::new();
+    let claim = loop {
+        select! {
+            claim = &mut claims => break claim.ok(),
+            open = channel.watch(&mut early), if early.len() < MAX_EARLY => {
+                if !open {
+                    debug!("pre-opened channel closed while idle");
+                    break None;
+                }
+            }
+        }
+    };
+    shared.changed.notify_one();
+    // Dropping a claim unanswered sends the claimer on to the next channel.
+    match claim {
+        Some(claim) => {
+            if let Err(Preopened { channel, .. }) = claim.send(Preopened { channel, early }) {
+                channel.close().await;
+            }
+        }
+        _ => channel.close().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    /// A channel the test feeds through its sender; dropping the sender
+    /// closes it.
+    struct Fake {
+        id: usize,
+        data: mpsc::UnboundedReceiver<Vec<u8>>,
+        closed: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl IdleChannel for Fake {
+        async fn watch(&mut self, early: &mut Vec<u8>) -> bool {
+            match self.data.recv().await {
+                Some(data) => {
+                    early.extend(data);
+                    true
+                }
+                None => false,
+            }
+        }
+
+        async fn close(self) {
+            self.closed.lock().unwrap().push(self.id);
+        }
+    }
+
+    #[derive(Default)]
+    struct Server {
+        /// The senders of every channel opened, by id.
+        opened: Mutex<Vec<Option<mpsc::UnboundedSender<Vec<u8>>>>>,
+        closed: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Server {
+        fn pool(self: &Arc<Self>, size: usize) -> ChannelPool<Fake> {
+            let server = Arc::clone(self);
+            ChannelPool::new(size, move || {
+                let server = Arc::clone(&server);
+                async move {
+                    let (sender, data) = mpsc::unbounded_channel();
+                    let mut opened = server.opened.lock().unwrap();
+                    opened.push(Some(sender));
+                    Some(Fake {
+                        id: opened.len() - 1,
+                        data,
+                        closed: Arc::clone(&server.closed),
+                    })
+                }
+            })
+        }
+
+        fn opened(&self) -> usize {
+            self.opened.lock().unwrap().len()
+        }
+
+        fn send(&self, id: usize, data: &[u8]) {
+            let opened = self.opened.lock().unwrap();
+            opened[id].as_ref().unwrap().send(data.to_vec()).unwrap();
+        }
+
+        fn hang_up(&self, id: usize) {
+            self.opened.lock().unwrap()[id] = None;
+        }
+
+        fn closed(&self) -> Vec<usize> {
+            let mut closed = self.closed.lock().unwrap().clone();
+            closed.sort_unstable();
+            closed
+        }
+    }
+
+    /// Let the refill and watcher tasks catch up.
+    async fn settle() {
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    #[tokio::test]
+    async fn claims_take_the_oldest_channel_with_its_early_data() {
+        let server = Arc::new(Server::default());
+        let pool = server.pool(2);
+        settle().await;
+        assert_eq!(server.opened(), 2);
+
+        server.send(0, b"220 ");
+        server.send(0, b"ready");
+        settle().await;
+        let first = pool.claim().await.unwrap();
+        assert_eq!(
+            (first.channel.id, first.early.as_slice()),
+            (0, &b"220 ready"[..])
+        );
+
+        // The claimed channel is replaced.
+        settle().await;
+        assert_eq!(server.opened(), 3);
+        let second = pool.claim().await.unwrap();
+        assert_eq!((second.channel.id, second.early.len()), (1, 0));
+        assert!(server.closed().is_empty());
+    }
+
+    #[tokio::test]
+    async fn channels_closed_while_idle_are_replaced() {
+        let server = Arc::new(Server::default());
+        let pool = server.pool(1);
+        settle().await;
+        server.hang_up(0);
+        settle().await;
+
+        assert_eq!(server.closed(), [0]);
+        assert_eq!(server.opened(), 2);
+        assert_eq!(pool.claim().await.unwrap().channel.id, 1);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_pool_closes_its_idle_channels() {
+        let server = Arc::new(Server::default());
+        let pool = server.pool(3);
+        settle().await;
+        let claimed = pool.claim().await.unwrap();
+        settle().await;
+        drop(pool);
+        settle().await;
+
+        assert_eq!(server.opened(), 4);
+        assert_eq!(server.closed(), [1, 2, 3]);
+        drop(claimed);
+    }
+
+    #[tokio::test]
+    async fn an_empty_pool_claims_nothing() {
+        let pool = Arc::new(Server::default()).pool(0);
+        settle().await;
+        assert!(pool.claim().await.is_none());
+    }
+}
@@ -0,0 +1,159 @@
+//! PROXY protocol headers (`--proxy-protocol`).
+//!
+//! Behind a tunnel every connection reaches the target from sshd (or from us,
+//! for `-R`), so the target cannot see who actually connected. Targets that
+//! understand HAProxy's PROXY protocol (nginx, HAProxy, many others) read the
+//! original addresses from a header sent ahead of the data.
+
+use std::net::{IpAddr, SocketAddr};
+
+use clap::ValueEnum;
+
+/// The binary (v2) header's fixed signature.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Which version of the header to send.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ProxyProtocol {
+    /// The human-readable header, e.g. `PROXY TCP4 1.2.3.4 5.6.7.8 1234 80`.
+    V1,
+    /// The binary header.
+    V2,
+}
+
+impl ProxyProtocol {
+    /// The header for a connection from `addrs.0` that arrived at `addrs.1`.
+    ///
+    /// `None`, e.g. for Unix-socket clients, yields the header for an unknown
+    /// origin (`UNKNOWN` / `LOCAL`), which targets treat as a direct
+    /// connection. An IPv4 address paired with an IPv6 one is sent IPv4-mapped.
+    #[must_use]
+    pub fn header(self, addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+        let addrs = addrs.map(
+            |(source, destination)| match (source.ip(), destination.ip()) {
+                (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+                    (source, destination)
+                }
+                _ => (to_ipv6(source), to_ipv6(destination)),
+            },
+        );
+
+        match self {
+            Self::V1 => match addrs {
+                Some((source, destination)) => format!(
+                    "PROXY {} {} {} {} {}\r\n",
+                    if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                    source.ip(),
+                    destination.ip(),
+                    source.port(),
+                    destination.port()
+                )
+                .into_bytes(),
+                None => b"PROXY UNKNOWN\r\n".to_vec(),
+            },
+            Self::V2 => {
+                let mut header = V2_SIGNATURE.to_vec();
+                match addrs {
+                    Some((source, destination)) => {
+                        // Version 2, PROXY command; then TCP over IPv4 / IPv6.
+                        header.push(0x21);
+                        let addresses = match (source.ip(), destination.ip()) {
+                            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                                header.push(0x11);
+                                [source.octets(), destination.octets()].concat()
+                            }
+                            (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                                header.push(0x21);
+                                [source.octets(), destination.octets()].concat()
+                            }
+                            _ => unreachable!("mixed families are mapped above"),
+                        };
+                        let len = u16::try_from(addresses.len() + 4).expect("at most 36 bytes");
+                        header.extend_from_slice(&len.to_be_bytes());
+                        header.extend_from_slice(&addresses);
+                        header.extend_from_slice(&source.port().to_be_bytes());
+                        header.extend_from_slice(&destination.port().to_be_bytes());
+                    }
+                    None => {
+                        // Version 2, LOCAL command, unspecified family, no
+                        // addresses.
+                        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+                    }
+                }
+                header
+            }
+        }
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    fn addrs(source: &str, destination: &str) -> Option<(SocketAddr, SocketAddr)> {
+        Some((source.parse().unwrap(), destination.parse().unwrap()))
+    }
+
+    #[test]
+    fn v1_headers() {
+        let header = |addrs| String::from_utf8(ProxyProtocol::V1.header(addrs)).unwrap();
+        assert_eq!(
+            header(addrs("192.0.2.1:56324", "198.51.100.7:443")),
+            "PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n"
+        );
+        assert_eq!(
+            header(addrs("[2001:db8::1]:56324", "[::1]:443")),
+            "PROXY TCP6 2001:db8::1 ::1 56324 443\r\n"
+        );
+        assert_eq!(
+            header(addrs("192.0.2.1:56324", "[::1]:443")),
+            "PROXY TCP6 ::ffff:192.0.2.1 ::1 56324 443\r\n"
+        );
+        assert_eq!(header(None), "PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn v2_ipv4_header() {
+        let header = ProxyProtocol::V2.header(addrs("192.0.2.1:56324", "198.51.100.7:443"));
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0x00, 12]);
+        expected.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 7]);
+        expected.extend_from_slice(&56324u16.to_be_bytes());
+        expected.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(header, expected);
+    }
+
+    #[test]
+    fn v2_ipv6_header() {
+        let header = ProxyProtocol::V2.header(addrs("[2001:db8::1]:1", "192.0.2.9:2"));
+        assert_eq!(&header[..12], V2_SIGNATURE);
+        assert_eq!(&header[12..16], &[0x21, 0x21, 0x00, 36]);
+        assert_eq!(header.len(), 16 + 36);
+        assert_eq!(
+            &header[16..32],
+            &"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets()
+        );
+        // The IPv4 destination is sent IPv4-mapped.
+        assert_eq!(
+            &header[32..48],
+            &"::ffff:192.0.2.9".parse::<Ipv6Addr>().unwrap().octets()
+        );
+        assert_eq!(&header[48..], &[0, 1, 0, 2]);
+    }
+
+    #[test]
+    fn v2_local_header() {
+        let header = ProxyProtocol::V2.header(None);
+        assert_eq!(&header[..12], V2_SIGNATURE);
+        assert_eq!(&header[12..], &[0x20, 0x00, 0x00, 0x00]);
+    }
+}
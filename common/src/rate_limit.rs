@@ -0,0 +1,407 @@
+//! Bandwidth limits (`--rate-limit`, `--connection-rate-limit`).
+//!
+//! Each limit is a pair of token buckets, one per direction: a forward's
+//! buckets are shared by all of its connections, and each connection may get
+//! its own on top. A splice only reads as many bytes as every bucket it draws
+//! from allows, so the data waits in the kernel's socket buffers (or, for the
+//! channel side, in the SSH window) rather than in ours.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Sleep},
+};
+
+/// A bucket holds at most this much of a second's worth of bytes, so a burst
+/// after an idle period stays short.
+const BURST: Duration = Duration::from_millis(100);
+/// ... but never less than this, so one full read buffer can always go out.
+const MIN_BURST: f64 = 64.0 * 1024.0;
+/// Reads wait until this many bytes (or all they asked for) may go at once,
+/// rather than trickling a few bytes per wakeup.
+const MIN_GRANT: usize = 16 * 1024;
+
+/// A bandwidth in bytes per second, written like `5MiB/s`, `500kB/s` or
+/// `1048576`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate(f64);
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rate = parse_bytes(s.strip_suffix("/s").unwrap_or(s), s, "a rate like 5MiB/s")?;
+        if rate < 1.0 {
+            return Err(format!("{s:?} is less than one byte per second"));
+        }
+        Ok(Self(rate))
+    }
+}
+
+/// `amount` in bytes, given with an optional unit like `5MiB` or `500kB`; `s`
+/// is the whole argument and `example` what it should look like, for errors.
+pub(crate) fn parse_bytes(amount: &str, s: &str, example: &str) -> Result<f64, String> {
+    let split = amount
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(amount.len());
+    let (number, unit) = amount.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("{s:?} is not {example}"))?;
+    let unit = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1.0,
+        "kb" => 1e3,
+        "mb" => 1e6,
+        "gb" => 1e9,
+        "kib" => 1024.0,
+        "mib" => 1024.0 * 1024.0,
+        "gib" => 1024.0 * 1024.0 * 1024.0,
+        _ => {
+            return Err(format!(
+                "unknown unit {unit:?} in {s:?}, use B, kB, KiB, MB, MiB, GB or GiB"
+            ))
+        }
+    };
+    Ok(number * unit)
+}
+
+/// A limit for uploads (client to target) and downloads, written `RATE` for
+/// both or `UP,DOWN`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub up: Rate,
+    pub down: Rate,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(',') {
+            Some((up, down)) => Ok(Self {
+                up: up.parse()?,
+                down: down.parse()?,
+            }),
+            None => {
+                let rate = s.parse()?;
+                Ok(Self {
+                    up: rate,
+                    down: rate,
+                })
+            }
+        }
+    }
+}
+
+/// Tokens are bytes, refilled continuously at the bucket's rate.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<Tokens>,
+}
+
+#[derive(Debug)]
+struct Tokens {
+    /// Negative when concurrent readers overdrew the bucket.
+    available: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate) -> Self {
+        let capacity = (rate.0 * BURST.as_secs_f64()).max(MIN_BURST);
+        Self {
+            rate: rate.0,
+            capacity,
+            state: Mutex::new(Tokens {
+                available: capacity,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    fn refilled(&self) -> std::sync::MutexGuard<'_, Tokens> {
+        let mut tokens = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(tokens.refilled_at).as_secs_f64();
+        tokens.available = (tokens.available + elapsed * self.rate).min(self.capacity);
+        tokens.refilled_at = now;
+        tokens
+    }
+
+    fn allowance(&self, want: usize) -> Result<usize, Duration> {
+        let available = self.refilled().available;
+        let need = want.min(MIN_GRANT) as f64;
+        if available >= need {
+            Ok((available as usize).min(want))
+        } else {
+            Err(Duration::from_secs_f64((need - available) / self.rate))
+        }
+    }
+
+    fn consume(&self, n: usize) {
+        let n = n as f64;
+        self.refilled().available -= n;
+    }
+}
+
+/// The buckets one direction of one connection draws from.
+#[derive(Clone, Debug, Default)]
+pub struct Throttle(Vec<Arc<TokenBucket>>);
+
+impl Throttle {
+    /// These buckets plus a new one of its own for `rate`.
+    #[must_use]
+    pub fn with(&self, rate: Option<Rate>) -> Self {
+        let mut buckets = self.0.clone();
+        buckets.extend(rate.map(|rate| Arc::new(TokenBucket::new(rate))));
+        Self(buckets)
+    }
+
+    #[must_use]
+    pub fn is_unlimited(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// How many of `want` bytes may be read now, or how long until some may.
+    ///
+    /// ## Errors
+    /// with the time to wait when every bucket is (nearly) empty
+    pub fn allowance(&self, want: usize) -> Result<usize, Duration> {
+        let mut allowed = want;
+        let mut wait = None;
+        for bucket in &self.0 {
+            match bucket.allowance(want) {
+                Ok(n) => allowed = allowed.min(n),
+                Err(w) => wait = wait.max(Some(w)),
+            }
+        }
+        wait.map_or(Ok(allowed), Err)
+    }
+
+    /// `n` bytes were read.
+    pub fn consume(&self, n: usize) {
+        for bucket in &self.0 {
+            bucket.consume(n);
+        }
+    }
+}
+
+/// A [`Throttle`] for each direction.
+#[derive(Clone, Debug, Default)]
+pub struct Throttles {
+    pub up: Throttle,
+    pub down: Throttle,
+}
+
+impl Throttles {
+    /// These throttles plus buckets of their own for `limit`.
+    #[must_use]
+    pub fn with(&self, limit: Option<RateLimit>) -> Self {
+        Self {
+            up: self.up.with(limit.map(|limit| limit.up)),
+            down: self.down.with(limit.map(|limit| limit.down)),
+        }
+    }
+}
+
+/// Wraps one side of a tokio splice and limits how fast it is *read*; writes
+/// pass straight through.
+pub struct Throttled<S> {
+    inner: S,
+    throttle: Throttle,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub const fn new(inner: S, throttle: Throttle) -> Self {
+        Self {
+            inner,
+            throttle,
+            sleep: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        if this.throttle.is_unlimited() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        loop {
+            if let Some(sleep) = &mut this.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            match this.throttle.allowance(buf.remaining()) {
+                Ok(allowed) => {
+                    let mut limited = ReadBuf::new(buf.initialize_unfilled_to(allowed));
+                    ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+                    let n = limited.filled().len();
+                    buf.advance(n);
+                    this.throttle.consume(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(wait) => this.sleep = Some(Box::pin(sleep(wait))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    const MIB: f64 = 1024.0 * 1024.0;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!("1048576".parse(), Ok(Rate(MIB)));
+        assert_eq!("5MiB/s".parse(), Ok(Rate(5.0 * MIB)));
+        assert_eq!("500kB/s".parse(), Ok(Rate(500e3)));
+        assert_eq!("1.5GB".parse(), Ok(Rate(1.5e9)));
+        assert_eq!("2kib/s".parse(), Ok(Rate(2048.0)));
+        for bad in [
+            "", "fast", "5MiB/m", "5 MiB/s", "0", "0.5B/s", "-1", "1.2.3",
+        ] {
+            assert!(bad.parse::<Rate>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parses_rate_limits() {
+        assert_eq!(
+            "1MiB/s".parse(),
+            Ok(RateLimit {
+                up: Rate(MIB),
+                down: Rate(MIB),
+            })
+        );
+        assert_eq!(
+            "100kB/s,2MiB/s".parse(),
+            Ok(RateLimit {
+                up: Rate(100e3),
+                down: Rate(2.0 * MIB),
+            })
+        );
+        assert!("1MiB/s,".parse::<RateLimit>().is_err());
+        assert!("1MiB/s,2MiB/s,3MiB/s".parse::<RateLimit>().is_err());
+    }
+
+    #[test]
+    fn bucket_starts_full() {
+        // A tenth of a second's worth, but at least MIN_BURST.
+        let bucket = TokenBucket::new(Rate(10.0 * MIB));
+        assert_eq!(bucket.allowance(usize::MAX), Ok(MIB as usize));
+        assert_eq!(bucket.allowance(100), Ok(100));
+        let bucket = TokenBucket::new(Rate(1000.0));
+        assert_eq!(bucket.allowance(usize::MAX), Ok(MIN_BURST as usize));
+    }
+
+    #[test]
+    fn empty_bucket_says_how_long_to_wait() {
+        let bucket = TokenBucket::new(Rate(MIB));
+        bucket.consume(bucket.allowance(usize::MAX).unwrap());
+        // MIN_GRANT bytes take about 15.6ms to come in at 1MiB/s.
+        let wait = bucket.allowance(usize::MAX).unwrap_err();
+        assert!(wait > Duration::from_millis(10) && wait <= Duration::from_millis(16));
+        // Small reads only wait for what they ask for.
+        assert!(bucket.allowance(1024).unwrap_err() < wait);
+    }
+
+    #[test]
+    fn overdrawn_bucket_waits_longer() {
+        let bucket = TokenBucket::new(Rate(MIB));
+        bucket.consume(bucket.allowance(usize::MAX).unwrap() + 1024 * 1024);
+        let wait = bucket.allowance(1).unwrap_err();
+        assert!(wait > Duration::from_millis(900), "{wait:?}");
+    }
+
+    #[test]
+    fn throttle_takes_the_tightest_bucket() {
+        assert!(Throttle::default().with(None).is_unlimited());
+        assert_eq!(Throttle::default().allowance(123), Ok(123));
+
+        let shared = Throttle::default().with(Some(Rate(10.0 * MIB)));
+        let own = shared.with(Some(Rate(1000.0)));
+        assert_eq!(own.allowance(usize::MAX), Ok(MIN_BURST as usize));
+
+        // Draining the connection's bucket draws on the shared one too.
+        own.consume(MIN_BURST as usize);
+        assert!(own.allowance(1).is_err());
+        // Less what the refill added since.
+        let left = shared.allowance(usize::MAX).unwrap();
+        let expected = MIB as usize - MIN_BURST as usize;
+        assert!((expected..expected + 4096).contains(&left), "{left}");
+    }
+
+    #[tokio::test]
+    async fn throttled_reads_keep_to_the_rate() {
+        struct Zeros;
+
+        impl AsyncRead for Zeros {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                let n = buf.remaining();
+                buf.initialize_unfilled_to(n);
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        // The first 100KiB is the burst, the other 200KiB take ~0.2s.
+        let mut reader =
+            Throttled::new(Zeros, Throttle::default().with(Some(Rate(MIB)))).take(300 * 1024);
+        let started = Instant::now();
+        let read = tokio::io::copy(&mut reader, &mut tokio::io::sink())
+            .await
+            .unwrap();
+        let elapsed = started.elapsed();
+        assert_eq!(read, 300 * 1024);
+        assert!(
+            elapsed >= Duration::from_millis(150) && elapsed < Duration::from_secs(1),
+            "{elapsed:?}"
+        );
+
+        // Without a limit nothing waits.
+        let mut reader = Throttled::new(tokio::io::empty(), Throttle::default());
+        assert_eq!(reader.read(&mut [0; 16]).await.unwrap(), 0);
+    }
+}
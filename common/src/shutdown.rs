@@ -0,0 +1,149 @@
+//! Graceful shutdown (`--drain-timeout`).
+//!
+//! On SIGINT or SIGTERM a tunnel stops accepting, gives the connections that
+//! are already open up to `--drain-timeout` to finish on their own, and only
+//! then disconnects the session. A second signal while draining exits at once.
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    select,
+    signal::unix::{signal, Signal, SignalKind},
+    time::sleep,
+};
+use tracing::{info, warn};
+
+use crate::{daemon, stats::Stats};
+
+/// How often [`drain`] checks whether the last connection has closed.
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+/// The exit status after a forced exit, as for a shell killed by SIGINT.
+pub const FORCED_EXIT_STATUS: i32 = 130;
+
+/// The signals that stop a tunnel.
+pub struct Signals {
+    interrupt: Signal,
+    terminate: Signal,
+}
+
+impl Signals {
+    /// Start listening for SIGINT and SIGTERM.
+    ///
+    /// ## Errors
+    /// if a signal handler cannot be installed
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+        })
+    }
+
+    /// The name of the next signal received.
+    pub async fn recv(&mut self) -> &'static str {
+        select! {
+            _ = self.interrupt.recv() => "SIGINT",
+            _ = self.terminate.recv() => "SIGTERM",
+        }
+    }
+
+    /// Exit the process, without draining, on the next signal.
+    pub fn exit_on_next(mut self) {
+        tokio::spawn(async move {
+            let name = self.recv().await;
+            warn!("{name} received while draining, exiting now");
+            daemon::remove_pidfile();
+            std::process::exit(FORCED_EXIT_STATUS);
+        });
+    }
+}
+
+/// Wait until every connection registered in `stats`, and every client still
+/// waiting for its channel, has closed, or until `timeout` has passed. Returns
+/// whether they all closed.
+pub async fn drain(stats: &Stats, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let open = || stats.active_connections() + stats.pending_connections();
+    let active = open();
+    if active > 0 {
+        info!("draining {active} connection(s) for up to {timeout:?}");
+    }
+    loop {
+        let active = open();
+        if active == 0 {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            warn!("drain timed out, {active} connection(s) dropped");
+            return false;
+        }
+        sleep(DRAIN_POLL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn open(stats: &Arc<Stats>) -> crate::stats::ConnectionGuard {
+        stats.open_connection(
+            "-L 8080:h:80",
+            "127.0.0.1:5000",
+            "direct-tcpip",
+            Duration::ZERO,
+        )
+    }
+
+    #[tokio::test]
+    async fn drain_returns_at_once_without_connections() {
+        let started = Instant::now();
+        assert!(drain(&Stats::new(), Duration::from_secs(10)).await);
+        assert!(started.elapsed() < DRAIN_POLL);
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_the_last_connection() {
+        let stats = Stats::new();
+        let guard = open(&stats);
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(150)).await;
+            drop(guard);
+        });
+        let started = Instant::now();
+        assert!(drain(&stats, Duration::from_secs(10)).await);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn drain_gives_up_at_the_timeout() {
+        let stats = Stats::new();
+        let _guard = open(&stats);
+        let started = Instant::now();
+        assert!(!drain(&stats, Duration::from_millis(250)).await);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(250), "{elapsed:?}");
+        assert_eq!(stats.active_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_clients_still_opening_a_channel() {
+        let stats = Stats::new();
+        let pending = stats.pending_connection();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(150)).await;
+            let guard = pending.open("-L 8080:h:80", "127.0.0.1:5000", "direct-tcpip");
+            sleep(Duration::from_millis(150)).await;
+            drop(guard);
+        });
+        let started = Instant::now();
+        assert!(drain(&stats, Duration::from_secs(10)).await);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert_eq!(stats.total_connections(), 1);
+        assert!(stats.open_latency().max() >= Duration::from_millis(150));
+    }
+}
@@ -0,0 +1,648 @@
+//! Live traffic statistics shared by every backend.
+//!
+//! A single [`Stats`] registry is created per process and handed to the
+//! backend's splice loop, which records bytes as they move (not once at
+//! close), so the numbers are meaningful while a long transfer is still
+//! running. Every forwarded connection is tracked by a [`ConnectionGuard`]:
+//! creating one bumps the active/total counters and dropping it records the
+//! connection's lifetime.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::info;
+
+/// Bucket upper bounds (seconds) for the channel-open latency histogram.
+const OPEN_LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Bucket upper bounds (seconds) for the connection duration histogram.
+const DURATION_BUCKETS: &[f64] = &[0.1, 1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0];
+
+/// What went wrong, for the per-kind error counters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorKind {
+    /// Accepting a local connection failed.
+    Accept,
+    /// The server refused or failed to open a channel.
+    ChannelOpen,
+    /// A channel open did not complete in time.
+    ChannelOpenTimeout,
+    /// Connecting to the local target of a remote (`-R`) forward failed.
+    Connect,
+    /// A connection was turned away because a connection limit was reached.
+    Limit,
+    /// Reading or writing either side of an established connection failed.
+    Transfer,
+}
+
+impl ErrorKind {
+    pub const ALL: [Self; 6] = [
+        Self::Accept,
+        Self::ChannelOpen,
+        Self::ChannelOpenTimeout,
+        Self::Connect,
+        Self::Limit,
+        Self::Transfer,
+    ];
+
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Accept => "accept",
+            Self::ChannelOpen => "channel_open",
+            Self::ChannelOpenTimeout => "channel_open_timeout",
+            Self::Connect => "connect",
+            Self::Limit => "limit",
+            Self::Transfer => "transfer",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A fixed-bucket histogram of durations, cumulative like Prometheus'.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// One counter per bound plus the trailing `+Inf` bucket (not cumulative).
+    buckets: Box<[AtomicU64]>,
+    sum_micros: AtomicU64,
+    max_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            max_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let idx = self
+            .bounds
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+
+        let micros = u64::try_from(value.as_micros()).unwrap_or(u64::MAX);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `(upper bound in seconds, cumulative count)` pairs, excluding `+Inf`
+    /// (whose count is [`Histogram::count`]).
+    #[must_use]
+    pub fn cumulative_buckets(&self) -> Vec<(f64, u64)> {
+        let mut running = 0;
+        self.bounds
+            .iter()
+            .zip(self.buckets.iter())
+            .map(|(&bound, bucket)| {
+                running += bucket.load(Ordering::Relaxed);
+                (bound, running)
+            })
+            .collect()
+    }
+
+    #[must_use]
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    #[must_use]
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_micros.load(Ordering::Relaxed))
+    }
+
+    #[must_use]
+    pub fn mean(&self) -> Duration {
+        match self.count() {
+            0 => Duration::ZERO,
+            n => self.sum() / u32::try_from(n).unwrap_or(u32::MAX),
+        }
+    }
+}
+
+/// Counters for one forwarded connection.
+#[derive(Debug)]
+pub struct Connection {
+    pub id: u64,
+    /// The forward this connection arrived on, in `-L spec` / `-R spec` form.
+    pub forward: String,
+    /// Who connected, as shown in logs (an address or socket path).
+    pub peer: String,
+    /// How the far side was reached: the SSH channel type (`direct-tcpip`,
+    /// `direct-streamlocal`, `forwarded-tcpip`) or `exec` for the fallback.
+    pub via: &'static str,
+    pub opened_at: Instant,
+    pub open_latency: Duration,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    /// When bytes last moved either way, in microseconds after `opened_at`.
+    last_active_micros: AtomicU64,
+}
+
+impl Connection {
+    /// Bytes sent from the local client towards the remote target so far.
+    #[must_use]
+    pub fn bytes_up(&self) -> u64 {
+        self.bytes_up.load(Ordering::Relaxed)
+    }
+
+    /// Bytes sent from the remote target back to the local client so far.
+    #[must_use]
+    pub fn bytes_down(&self) -> u64 {
+        self.bytes_down.load(Ordering::Relaxed)
+    }
+
+    /// How long no bytes have moved in either direction.
+    #[must_use]
+    pub fn idle_for(&self) -> Duration {
+        self.opened_at
+            .elapsed()
+            .saturating_sub(Duration::from_micros(
+                self.last_active_micros.load(Ordering::Relaxed),
+            ))
+    }
+
+    fn touch(&self) {
+        let micros = u64::try_from(self.opened_at.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.last_active_micros.store(micros, Ordering::Relaxed);
+    }
+}
+
+/// Aggregate statistics for the whole process.
+#[derive(Debug)]
+pub struct Stats {
+    started_at: Instant,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    active: AtomicU64,
+    pending: AtomicU64,
+    total: AtomicU64,
+    next_id: AtomicU64,
+    errors: [AtomicU64; ErrorKind::ALL.len()],
+    reconnects: AtomicU64,
+    keepalive_failures: AtomicU64,
+    open_latency: Histogram,
+    duration: Histogram,
+    connections: Mutex<BTreeMap<u64, Arc<Connection>>>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            active: AtomicU64::new(0),
+            pending: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            next_id: AtomicU64::new(0),
+            errors: Default::default(),
+            reconnects: AtomicU64::new(0),
+            keepalive_failures: AtomicU64::new(0),
+            open_latency: Histogram::new(OPEN_LATENCY_BUCKETS),
+            duration: Histogram::new(DURATION_BUCKETS),
+            connections: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Stats {
+    #[must_use]
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Register a connection whose channel has just been opened, `open_latency`
+    /// after the client was accepted.
+    pub fn open_connection(
+        self: &Arc<Self>,
+        forward: impl Into<String>,
+        peer: impl Into<String>,
+        via: &'static str,
+        open_latency: Duration,
+    ) -> ConnectionGuard {
+        let conn = Arc::new(Connection {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            forward: forward.into(),
+            peer: peer.into(),
+            via,
+            opened_at: Instant::now(),
+            open_latency,
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            last_active_micros: AtomicU64::new(0),
+        });
+
+        self.active.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(1, Ordering::Relaxed);
+        self.open_latency.observe(open_latency);
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(conn.id, Arc::clone(&conn));
+
+        ConnectionGuard {
+            conn,
+            stats: Arc::clone(self),
+        }
+    }
+
+    /// Count a client just accepted as pending until its channel is open and
+    /// [`PendingGuard::open`] registers it.
+    pub fn pending_connection(self: &Arc<Self>) -> PendingGuard {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        PendingGuard {
+            stats: Arc::clone(self),
+            accepted_at: Instant::now(),
+        }
+    }
+
+    pub fn record_error(&self, kind: ErrorKind) {
+        self.errors[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An SSH session was re-opened after failing or being closed while idle.
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A keepalive could not be sent, or went unanswered for too long.
+    pub fn record_keepalive_failure(&self) {
+        self.keepalive_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    #[must_use]
+    pub fn bytes_up(&self) -> u64 {
+        self.bytes_up.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn bytes_down(&self) -> u64 {
+        self.bytes_down.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn active_connections(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Clients accepted whose channel (or, for `-R`, local connection) is not
+    /// open yet.
+    #[must_use]
+    pub fn pending_connections(&self) -> u64 {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn total_connections(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn errors(&self, kind: ErrorKind) -> u64 {
+        self.errors[kind as usize].load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn keepalive_failures(&self) -> u64 {
+        self.keepalive_failures.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn open_latency(&self) -> &Histogram {
+        &self.open_latency
+    }
+
+    #[must_use]
+    pub fn duration(&self) -> &Histogram {
+        &self.duration
+    }
+
+    /// Snapshot of the currently open connections, oldest first.
+    #[must_use]
+    pub fn connections(&self) -> Vec<Arc<Connection>> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Log a one-shot summary, meant for shutdown.
+    pub fn log_summary(&self) {
+        let errors = ErrorKind::ALL
+            .iter()
+            .map(|&kind| format!("{kind}={}", self.errors(kind)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        info!(
+            "{} connection(s) over {:.1?} ({} still open): {} bytes up, {} bytes down, channel \
+             open mean {:.1?} / max {:.1?}, errors: {}",
+            self.total_connections(),
+            self.uptime(),
+            self.active_connections(),
+            self.bytes_up(),
+            self.bytes_down(),
+            self.open_latency.mean(),
+            self.open_latency.max(),
+            errors,
+        );
+    }
+}
+
+/// Keeps one accepted client counted as pending until dropped, see
+/// [`Stats::pending_connection`].
+#[derive(Debug)]
+pub struct PendingGuard {
+    stats: Arc<Stats>,
+    accepted_at: Instant,
+}
+
+impl PendingGuard {
+    #[must_use]
+    pub const fn stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    /// Register the connection now that its channel is open, with the time
+    /// since it was accepted as its open latency.
+    pub fn open(
+        self,
+        forward: impl Into<String>,
+        peer: impl Into<String>,
+        via: &'static str,
+    ) -> ConnectionGuard {
+        let open_latency = self.accepted_at.elapsed();
+        self.stats.open_connection(forward, peer, via, open_latency)
+    }
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.stats.pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Keeps one connection registered with [`Stats`] until dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    conn: Arc<Connection>,
+    stats: Arc<Stats>,
+}
+
+impl ConnectionGuard {
+    #[must_use]
+    pub fn id(&self) -> u64 {
+        self.conn.id
+    }
+
+    #[must_use]
+    pub fn connection(&self) -> &Arc<Connection> {
+        &self.conn
+    }
+
+    pub fn record_up(&self, n: usize) {
+        let n = n as u64;
+        self.conn.bytes_up.fetch_add(n, Ordering::Relaxed);
+        self.stats.bytes_up.fetch_add(n, Ordering::Relaxed);
+        if n > 0 {
+            self.conn.touch();
+        }
+    }
+
+    pub fn record_down(&self, n: usize) {
+        let n = n as u64;
+        self.conn.bytes_down.fetch_add(n, Ordering::Relaxed);
+        self.stats.bytes_down.fetch_add(n, Ordering::Relaxed);
+        if n > 0 {
+            self.conn.touch();
+        }
+    }
+
+    /// Resolves once no bytes have moved for `timeout` (`--idle-timeout`);
+    /// never, without one. Meant to race the splice in a `select!`.
+    pub async fn idle(&self, timeout: Option<Duration>) {
+        let Some(timeout) = timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            let idle_for = self.conn.idle_for();
+            if idle_for >= timeout {
+                return;
+            }
+            tokio::time::sleep(timeout - idle_for).await;
+        }
+    }
+
+    pub fn record_error(&self, kind: ErrorKind) {
+        self.stats.record_error(kind);
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.stats.active.fetch_sub(1, Ordering::Relaxed);
+        self.stats.duration.observe(self.conn.opened_at.elapsed());
+        self.stats.connections.lock().unwrap().remove(&self.conn.id);
+    }
+}
+
+/// Wraps the *local* side of a tokio splice and counts bytes as they move:
+/// reads from the client are "up", writes to the client are "down".
+///
+/// Works on a whole stream or on either half of a split one.
+pub struct Metered<S> {
+    inner: S,
+    guard: Arc<ConnectionGuard>,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, guard: Arc<ConnectionGuard>) -> Self {
+        Self { inner, guard }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            self.guard.record_up(buf.filled().len() - before);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.guard.record_down(n);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn guards_count_connections_and_bytes() {
+        let stats = Stats::new();
+        let first = stats.open_connection(
+            "-L 8080:h:80",
+            "127.0.0.1:5000",
+            "direct-tcpip",
+            Duration::from_millis(3),
+        );
+        let second = stats.open_connection(
+            "-L 8080:h:80",
+            "127.0.0.1:5001",
+            "exec",
+            Duration::from_millis(30),
+        );
+        assert_ne!(first.id(), second.id());
+        assert_eq!(stats.active_connections(), 2);
+
+        first.record_up(100);
+        first.record_down(1000);
+        second.record_up(1);
+        assert_eq!(first.connection().bytes_up(), 100);
+        assert_eq!(first.connection().bytes_down(), 1000);
+        assert_eq!((stats.bytes_up(), stats.bytes_down()), (101, 1000));
+
+        let open: Vec<_> = stats.connections().iter().map(|c| c.via).collect();
+        assert_eq!(open, ["direct-tcpip", "exec"]);
+
+        drop(first);
+        assert_eq!(stats.active_connections(), 1);
+        assert_eq!(stats.total_connections(), 2);
+        assert_eq!(stats.connections().len(), 1);
+        assert_eq!(stats.duration().count(), 1);
+        // Bytes stay counted after the connection closes.
+        assert_eq!(stats.bytes_up(), 101);
+        drop(second);
+        assert_eq!(stats.active_connections(), 0);
+    }
+
+    #[test]
+    fn errors_are_counted_by_kind() {
+        let stats = Stats::new();
+        stats.record_error(ErrorKind::Limit);
+        stats.record_error(ErrorKind::Limit);
+        let guard = stats.open_connection("f", "p", "direct-tcpip", Duration::ZERO);
+        guard.record_error(ErrorKind::Transfer);
+        stats.record_reconnect();
+        stats.record_keepalive_failure();
+
+        let counts: Vec<_> = ErrorKind::ALL
+            .iter()
+            .map(|&kind| stats.errors(kind))
+            .collect();
+        assert_eq!(counts, [0, 0, 0, 0, 2, 1]);
+        assert_eq!(stats.reconnects(), 1);
+        assert_eq!(stats.keepalive_failures(), 1);
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new(&[0.1, 1.0]);
+        for millis in [50, 100, 500, 5000] {
+            histogram.observe(Duration::from_millis(millis));
+        }
+        // A value on a bound falls in that bound's bucket.
+        assert_eq!(histogram.cumulative_buckets(), [(0.1, 2), (1.0, 3)]);
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.sum(), Duration::from_millis(5650));
+        assert_eq!(histogram.max(), Duration::from_secs(5));
+        assert_eq!(histogram.mean(), Duration::from_micros(1_412_500));
+        assert_eq!(Histogram::new(&[1.0]).mean(), Duration::ZERO);
+    }
+
+    #[test]
+    fn idle_time_restarts_when_bytes_move() {
+        let stats = Stats::new();
+        let guard = stats.open_connection("f", "p", "direct-tcpip", Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(guard.connection().idle_for() >= Duration::from_millis(20));
+        guard.record_up(0);
+        assert!(guard.connection().idle_for() >= Duration::from_millis(20));
+        guard.record_up(1);
+        assert!(guard.connection().idle_for() < Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn metered_counts_reads_up_and_writes_down() {
+        let stats = Stats::new();
+        let guard = Arc::new(stats.open_connection("f", "p", "direct-tcpip", Duration::ZERO));
+        let (client, server) = tokio::io::duplex(64);
+        let mut metered = Metered::new(server, Arc::clone(&guard));
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+
+        client_write.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        metered.read_exact(&mut buf).await.unwrap();
+        metered.write_all(b"hi").await.unwrap();
+        client_read.read_exact(&mut buf[..2]).await.unwrap();
+
+        assert_eq!(guard.connection().bytes_up(), 5);
+        assert_eq!(guard.connection().bytes_down(), 2);
+        assert_eq!((stats.bytes_up(), stats.bytes_down()), (5, 2));
+    }
+}
@@ -0,0 +1,12 @@
+//! Helpers shared by the unit tests.
+
+use std::{env, fs, path::PathBuf, process};
+
+/// A fresh, empty directory under the system temp dir, unique to `name` and
+/// this test process. Tests remove it when they are done.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("port-forward-{name}-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    dir
+}
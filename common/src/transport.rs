@@ -0,0 +1,113 @@
+//! SSH transport tuning: flow control, keepalives, rekeying, algorithm
+//! preferences and compression.
+//!
+//! Every option left unset keeps the SSH library's default. Algorithm lists
+//! are names as OpenSSH spells them, most preferred first, and replace the
+//! library's list rather than adding to it.
+
+use std::{str::FromStr, time::Duration};
+
+use serde::Deserialize;
+
+use crate::{
+    config::{one, Humantime, Str},
+    rate_limit::parse_bytes,
+};
+
+/// A number of bytes, written like `4MiB`, `500kB` or `1048576`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = parse_bytes(s, s, "a size like 4MiB")?;
+        if bytes.fract() != 0.0 || bytes > u64::MAX as f64 {
+            return Err(format!("{s:?} is not a whole number of bytes"));
+        }
+        Ok(Self(bytes as u64))
+    }
+}
+
+/// The transport options given on the command line, or in a profile's
+/// `transport` table.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Transport {
+    /// Initial flow-control window of each channel.
+    #[serde(deserialize_with = "one::<_, Str<_>>")]
+    pub window_size: Option<ByteSize>,
+    /// Largest packet the server may send on a channel.
+    #[serde(deserialize_with = "one::<_, Str<_>>")]
+    pub max_packet_size: Option<ByteSize>,
+    /// Messages buffered per channel before the session stops reading.
+    pub channel_buffer_size: Option<usize>,
+    /// Disconnect after this long without any traffic.
+    #[serde(deserialize_with = "one::<_, Humantime>")]
+    pub inactivity_timeout: Option<Duration>,
+    /// Send a keepalive after this long without hearing from the server.
+    #[serde(deserialize_with = "one::<_, Humantime>")]
+    pub keepalive_interval: Option<Duration>,
+    /// Disconnect after this many unanswered keepalives.
+    pub keepalive_max: Option<usize>,
+    /// Re-exchange keys after this much data in either direction.
+    #[serde(deserialize_with = "one::<_, Str<_>>")]
+    pub rekey_data: Option<ByteSize>,
+    /// Re-exchange keys after this long.
+    #[serde(deserialize_with = "one::<_, Humantime>")]
+    pub rekey_time: Option<Duration>,
+    pub kex_algorithms: Vec<String>,
+    pub ciphers: Vec<String>,
+    pub macs: Vec<String>,
+    pub host_key_algorithms: Vec<String>,
+    /// Offer zlib compression ahead of none.
+    pub compression: bool,
+}
+
+impl Transport {
+    /// The first option given that only the russh backend honours, for the
+    /// others to refuse.
+    #[must_use]
+    pub fn russh_only(&self) -> Option<&'static str> {
+        [
+            (self.window_size.is_some(), "--window-size"),
+            (self.max_packet_size.is_some(), "--max-packet-size"),
+            (self.channel_buffer_size.is_some(), "--channel-buffer-size"),
+            (self.inactivity_timeout.is_some(), "--inactivity-timeout"),
+            (self.keepalive_interval.is_some(), "--keepalive-interval"),
+            (self.keepalive_max.is_some(), "--keepalive-max"),
+            (self.rekey_data.is_some(), "--rekey-data"),
+            (self.rekey_time.is_some(), "--rekey-time"),
+        ]
+        .into_iter()
+        .find_map(|(given, option)| given.then_some(option))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_byte_sizes() {
+        assert_eq!("1048576".parse(), Ok(ByteSize(1_048_576)));
+        assert_eq!("4MiB".parse(), Ok(ByteSize(4 * 1024 * 1024)));
+        assert_eq!("500kB".parse(), Ok(ByteSize(500_000)));
+        assert_eq!("1.5KiB".parse(), Ok(ByteSize(1536)));
+        assert_eq!("2gb".parse(), Ok(ByteSize(2_000_000_000)));
+        assert_eq!("0".parse(), Ok(ByteSize(0)));
+    }
+
+    #[test]
+    fn rejects_bad_byte_sizes() {
+        for bad in ["", "MiB", "-1", "1e3", "4 MiB", "4MB/s", "4TiB"] {
+            assert!(bad.parse::<ByteSize>().is_err(), "{bad}");
+        }
+        assert_eq!(
+            "0.5B".parse::<ByteSize>(),
+            Err("\"0.5B\" is not a whole number of bytes".to_owned())
+        );
+        assert!("99999999999GiB".parse::<ByteSize>().is_err());
+    }
+}
@@ -0,0 +1,200 @@
+//! The server-side half of UDP forwarding (`-U`).
+//!
+//! SSH channels only carry byte streams, so each datagram travels as a
+//! big-endian `u16` length followed by its payload. The tunnel starts this
+//! relay on the server with `exec`; the relay listens on a loopback port,
+//! prints `<port> <token>` on stdout and then serves one TCP connection per
+//! local UDP peer, each with its own UDP socket towards the target so replies
+//! find their way back to the right peer. A connection must start with the
+//! token line, which keeps other users of the server from borrowing the relay.
+//!
+//! The relay exits when its stdin (the `exec` channel) closes, i.e. when the
+//! tunnel goes away.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use clap::Args;
+
+/// Largest payload a UDP datagram can carry, and so the largest frame.
+pub const MAX_DATAGRAM: usize = 65_535;
+
+/// How often a relay connection blocked on its UDP socket checks whether the
+/// tunnel side has gone away.
+const UDP_POLL: Duration = Duration::from_secs(1);
+
+/// Arguments of the `udp-relay` subcommand.
+#[derive(Args, Debug)]
+pub struct UdpRelayArguments {
+    /// Where datagrams are sent, as host:port.
+    pub target: String,
+}
+
+/// Write one datagram as a frame.
+fn write_frame<W: Write>(writer: &mut W, datagram: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(datagram.len())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "datagram too large"))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(datagram)?;
+    writer.flush()
+}
+
+/// Read one frame into `buf`, returning its length, or `None` at a clean EOF.
+fn read_frame<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    let mut len = [0u8; 2];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = usize::from(u16::from_be_bytes(len));
+    reader.read_exact(&mut buf[..len])?;
+    Ok(Some(len))
+}
+
+/// Run the `udp-relay` subcommand until stdin closes.
+///
+/// ## Errors
+/// if the target cannot be resolved or the loopback listener cannot be set up
+pub fn run(args: &UdpRelayArguments) -> io::Result<()> {
+    let target = args
+        .target
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "target did not resolve"))?;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let token = Arc::new(random_token()?);
+
+    let mut stdout = io::stdout();
+    writeln!(stdout, "{} {token}", listener.local_addr()?.port())?;
+    stdout.flush()?;
+
+    thread::spawn(|| {
+        let _ = io::copy(&mut io::stdin(), &mut io::sink());
+        process::exit(0);
+    });
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let token = Arc::clone(&token);
+        thread::spawn(move || {
+            if let Err(e) = serve(stream, target, &token) {
+                eprintln!("udp-relay: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Relay between one tunnel connection and a UDP socket of its own.
+fn serve(stream: TcpStream, target: SocketAddr, token: &str) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line.trim_end() != token {
+        return Err(io::Error::new(ErrorKind::PermissionDenied, "bad token"));
+    }
+
+    let socket = if target.is_ipv4() {
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?
+    } else {
+        UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?
+    };
+    socket.connect(target)?;
+    socket.set_read_timeout(Some(UDP_POLL))?;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let requests = {
+        let socket = socket.try_clone()?;
+        let done = Arc::clone(&done);
+        thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            while let Ok(Some(len)) = read_frame(&mut reader, &mut buf) {
+                if let Err(e) = socket.send(&buf[..len]) {
+                    eprintln!("udp-relay: sending to {target}: {e}");
+                }
+            }
+            done.store(true, Ordering::Relaxed);
+        })
+    };
+
+    let mut writer = stream;
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    while !done.load(Ordering::Relaxed) {
+        // Timeouts just re-check `done`; other errors (e.g. ECONNREFUSED after
+        // an ICMP port unreachable) are as lossy as UDP itself.
+        if let Ok(len) = socket.recv(&mut buf) {
+            write_frame(&mut writer, &buf[..len])?;
+        }
+    }
+
+    let _ = requests.join();
+    Ok(())
+}
+
+fn random_token() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn frames_are_length_prefixed() {
+        let mut out = Vec::new();
+        write_frame(&mut out, b"hello").unwrap();
+        write_frame(&mut out, b"").unwrap();
+        assert_eq!(out, b"\x00\x05hello\x00\x00");
+    }
+
+    #[test]
+    fn frames_read_back() {
+        let mut out = Vec::new();
+        let big = vec![7u8; MAX_DATAGRAM];
+        for datagram in [&b"one"[..], b"", &big] {
+            write_frame(&mut out, datagram).unwrap();
+        }
+
+        let mut reader = Cursor::new(out);
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        assert_eq!(read_frame(&mut reader, &mut buf).unwrap(), Some(3));
+        assert_eq!(&buf[..3], b"one");
+        assert_eq!(read_frame(&mut reader, &mut buf).unwrap(), Some(0));
+        assert_eq!(
+            read_frame(&mut reader, &mut buf).unwrap(),
+            Some(MAX_DATAGRAM)
+        );
+        assert_eq!(buf, big);
+        assert_eq!(read_frame(&mut reader, &mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn oversized_datagrams_are_refused() {
+        let mut out = Vec::new();
+        let err = write_frame(&mut out, &vec![0u8; MAX_DATAGRAM + 1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn truncated_payload_is_an_error() {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let err = read_frame(&mut Cursor::new(b"\x00\x05hel"), &mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,168 @@
+//! Unix-domain socket listeners: local `-L` forwards and the control socket.
+
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    io::{self, ErrorKind},
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use tracing::debug;
+
+/// Mode given to sockets we create unless told otherwise; like OpenSSH's
+/// default `StreamLocalBindMask`, only the owner may connect.
+pub const DEFAULT_MODE: u32 = 0o600;
+
+/// Bind a listening socket at `path` with permissions `mode`.
+///
+/// A socket file left behind by a crashed run is replaced, but one that still
+/// accepts connections belongs to a live process and is reported as
+/// [`ErrorKind::AddrInUse`]. Anything at `path` that is not a socket is never
+/// removed.
+///
+/// The returned [`SocketFile`] removes the socket again when dropped.
+///
+/// ## Errors
+/// if `path` is in use or not a socket, or binding or `chmod` fails
+pub fn bind(path: &Path, mode: u32) -> io::Result<(UnixListener, SocketFile)> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Ok(_) => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("{} is in use", path.display()),
+                ));
+            }
+            debug!("removing stale socket {}", path.display());
+            fs::remove_file(path)?;
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    // Bound at `path` directly, the socket would accept connections with the
+    // umask's permissions until the chmod. So, as OpenSSH does with its
+    // `StreamLocalBindMask`, it is only made reachable once it has `mode`:
+    // bound in a directory only we can enter, then linked into place. The
+    // umask is left alone, as other threads may be creating files.
+    let private = PrivateDir::create(path)?;
+    let bound = private.0.join("s");
+    let listener = UnixListener::bind(&bound)?;
+    fs::set_permissions(&bound, Permissions::from_mode(mode))?;
+    // Unlike a rename, this fails rather than replace whatever was created
+    // at `path` meanwhile.
+    fs::hard_link(&bound, path)?;
+    Ok((listener, SocketFile(path.to_owned())))
+}
+
+/// A directory next to `path` that only we may enter, removed with whatever
+/// is left in it when dropped.
+struct PrivateDir(PathBuf);
+
+impl PrivateDir {
+    fn create(path: &Path) -> io::Result<Self> {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let dir = parent.join(format!(
+            ".bind-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        Ok(Self(dir))
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Parse a socket mode given in octal, e.g. `660` or `0o660`.
+///
+/// ## Errors
+/// if `mode` is not an octal number of at most `0o777`
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|&mode| mode <= 0o777)
+        .ok_or_else(|| format!("invalid mode `{mode}`: expected octal permissions, e.g. 660"))
+}
+
+/// Removes a socket we bound when dropped, so its path can be reused.
+#[derive(Debug)]
+pub struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn binds_with_the_mode_and_cleans_up() {
+        let dir = temp_dir("unix-socket-bind");
+        let path = dir.join("forward.sock");
+        let (listener, file) = bind(&path, 0o640).unwrap();
+
+        let meta = fs::symlink_metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o640);
+        // The private directory it was bound in is gone.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // A live socket is not taken over.
+        let err = bind(&path, 0o600).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+
+        drop(file);
+        assert!(!path.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replaces_stale_sockets_only() {
+        let dir = temp_dir("unix-socket-stale");
+        let path = dir.join("forward.sock");
+        drop(UnixListener::bind(&path).unwrap());
+        let (_listener, _file) = bind(&path, DEFAULT_MODE).unwrap();
+
+        let other = dir.join("plain");
+        fs::write(&other, "data").unwrap();
+        assert_eq!(
+            bind(&other, DEFAULT_MODE).unwrap_err().kind(),
+            ErrorKind::AlreadyExists
+        );
+        assert_eq!(fs::read(&other).unwrap(), b"data");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
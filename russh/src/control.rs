@@ -0,0 +1,129 @@
+//! Server side of the control socket (see `common_port_forward::control`).
+
+use std::{path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use common_port_forward::{
+    control::{ConnectionInfo, ForwardInfo, Request, Response},
+    forward::ForwardSpec,
+    unix_socket::{self, SocketFile},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::Notify,
+};
+use tracing::{debug, info, instrument, warn};
+
+use crate::forward::Forwards;
+
+/// Bind the control socket at `path`.
+///
+/// A leftover socket file from a crashed run is replaced, but one that still
+/// accepts connections belongs to a live tunnel and is an error, like
+/// OpenSSH's "ControlSocket already exists".
+pub fn bind(path: &Path) -> Result<(UnixListener, SocketFile)> {
+    // Anyone who can connect can add forwards, so keep it to this user.
+    let (listener, file) = unix_socket::bind(path, unix_socket::DEFAULT_MODE)
+        .with_context(|| format!("binding control socket {}", path.display()))?;
+    listener.set_nonblocking(true)?;
+    let listener = UnixListener::from_std(listener)?;
+    info!("control socket at {}", path.display());
+    Ok((listener, file))
+}
+
+/// Serve control clients until the task is dropped. `exit` is notified when a
+/// client asks the tunnel to shut down.
+pub async fn serve(listener: UnixListener, forwards: Arc<Forwards>, exit: Arc<Notify>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("accepting control connection: {e}");
+                continue;
+            }
+        };
+
+        let forwards = Arc::clone(&forwards);
+        let exit = Arc::clone(&exit);
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, &forwards, &exit).await {
+                debug!("control connection: {e:#}");
+            }
+        });
+    }
+}
+
+async fn handle_client(stream: UnixStream, forwards: &Forwards, exit: &Notify) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => dispatch(request, forwards, exit).await,
+            Err(e) => Response::error(format!("malformed request: {e}")),
+        };
+
+        let mut reply = serde_json::to_string(&response)?;
+        reply.push('\n');
+        writer.write_all(reply.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+#[instrument(skip(forwards, exit))]
+async fn dispatch(request: Request, forwards: &Forwards, exit: &Notify) -> Response {
+    match request {
+        Request::List => Response {
+            forwards: forwards
+                .list()
+                .into_iter()
+                .map(|(id, spec)| ForwardInfo {
+                    id,
+                    spec: spec.to_string(),
+                })
+                .collect(),
+            connections: forwards
+                .stats()
+                .connections()
+                .iter()
+                .map(|conn| ConnectionInfo {
+                    id: conn.id,
+                    forward: conn.forward.clone(),
+                    peer: conn.peer.clone(),
+                    via: conn.via.to_owned(),
+                    bytes_up: conn.bytes_up(),
+                    bytes_down: conn.bytes_down(),
+                    age_secs: conn.opened_at.elapsed().as_secs_f64(),
+                })
+                .collect(),
+            ..Response::ok()
+        },
+        Request::Forward { spec } => {
+            let spec = match spec.parse::<ForwardSpec>() {
+                Ok(spec) => spec,
+                Err(e) => return Response::error(e),
+            };
+            match forwards.add(spec).await {
+                Ok(id) => Response {
+                    id: Some(id),
+                    ..Response::ok()
+                },
+                Err(e) => Response::error(format!("{e:#}")),
+            }
+        }
+        Request::Cancel { id } => match forwards.cancel(id).await {
+            Ok(()) => Response::ok(),
+            Err(e) => Response::error(format!("{e:#}")),
+        },
+        Request::Exit => {
+            info!("exit requested over the control socket");
+            exit.notify_one();
+            Response::ok()
+        }
+    }
+}
@@ -0,0 +1,841 @@
+//! The set of forwards served over the session pool, which can grow and shrink
+//! at runtime (see the control socket).
+//!
+//! Local (`-L`) forwards own a TCP or Unix-socket listener and an accept task;
+//! each accepted connection gets its own `direct-tcpip` channel, or
+//! `direct-streamlocal` when the target is a Unix socket on the server, or with
+//! `--exec-fallback` an `exec` channel if the server prohibits both. Remote
+//! (`-R`) forwards are a `tcpip-forward` request on the server plus an entry in
+//! [`RemoteForwards`], which the client handler consults when the server opens
+//! a `forwarded-tcpip` channel back to us.
+//!
+//! With `--preopen-channels` each local forward keeps channels to its target
+//! open ahead of its connections; see `common_port_forward::preopen`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use common_port_forward::{
+    activation::{Activated, Inherited},
+    exec_fallback::{self, VIA_EXEC},
+    forward::{self, Direction, Endpoint, ForwardSpec},
+    limits::{self, ForwardLimiter, Limiter, Slot},
+    preopen::{ChannelPool, IdleChannel, Preopened},
+    rate_limit::Throttled,
+    stats::{ConnectionGuard, ErrorKind, Metered, PendingGuard, Stats},
+    unix_socket::{self, SocketFile},
+};
+use russh::{
+    client::{ChannelOpenHandle, Msg},
+    Channel, ChannelMsg, ChannelOpenFailure,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    select,
+    task::JoinHandle,
+    time::sleep,
+};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{
+    pool::{Lease, SessionPool},
+    Session,
+};
+
+/// Pause after a failed `accept` (e.g. `EMFILE`) before trying again, so a
+/// persistent failure does not spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A remote forward and its connection limits.
+type RemoteForward = (ForwardSpec, ForwardLimiter);
+
+/// Remote forwards the server may open `forwarded-tcpip` channels for, keyed
+/// by the address and port the server bound.
+#[derive(Clone, Default)]
+pub struct RemoteForwards(Arc<Mutex<HashMap<(String, u32), RemoteForward>>>);
+
+impl RemoteForwards {
+    /// The forward a `forwarded-tcpip` channel belongs to. Servers echo the
+    /// bind address in their own spelling, so fall back to the port alone,
+    /// but only if a single forward is bound to it.
+    pub fn lookup(&self, address: &str, port: u32) -> Option<RemoteForward> {
+        let forwards = self.0.lock().unwrap();
+        if let Some(forward) = forwards.get(&(address.to_owned(), port)) {
+            return Some(forward.clone());
+        }
+        let mut on_port = forwards.iter().filter(|((_, bound), _)| *bound == port);
+        let (_, forward) = on_port.next()?;
+        if on_port.next().is_some() {
+            warn!("several remote forwards are bound to port {port}, none to {address}");
+            return None;
+        }
+        Some(forward.clone())
+    }
+
+    fn insert(&self, address: String, port: u32, spec: ForwardSpec, limits: ForwardLimiter) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert((address, port), (spec, limits));
+    }
+
+    fn remove(&self, address: &str, port: u32) {
+        self.0.lock().unwrap().remove(&(address.to_owned(), port));
+    }
+}
+
+struct Active {
+    spec: ForwardSpec,
+    /// The accept task of a local forward; aborting it closes the listener.
+    accept_task: Option<JoinHandle<()>>,
+}
+
+/// Every forward currently served over the session pool.
+pub struct Forwards {
+    pool: Arc<SessionPool>,
+    stats: Arc<Stats>,
+    remote: RemoteForwards,
+    /// Permissions of the Unix sockets local forwards listen on.
+    socket_mode: u32,
+    limiter: Arc<Limiter>,
+    /// `--preopen-channels`, for each local forward.
+    preopen_channels: usize,
+    /// Listening sockets systemd passed in, for the forwards not added yet.
+    activated: Mutex<Activated>,
+    next_id: AtomicU64,
+    active: Mutex<BTreeMap<u64, Active>>,
+}
+
+impl Forwards {
+    pub fn new(
+        pool: Arc<SessionPool>,
+        stats: Arc<Stats>,
+        remote: RemoteForwards,
+        socket_mode: u32,
+        limiter: Arc<Limiter>,
+        preopen_channels: usize,
+        activated: Activated,
+    ) -> Self {
+        Self {
+            pool,
+            stats,
+            remote,
+            socket_mode,
+            limiter,
+            preopen_channels,
+            activated: Mutex::new(activated),
+            next_id: AtomicU64::new(0),
+            active: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    /// Start serving `spec`, returning its id.
+    ///
+    /// Binding (locally or on the server) happens before this returns, so a
+    /// port that is already taken is reported to the caller.
+    pub async fn add(&self, mut spec: ForwardSpec) -> Result<u64> {
+        let inherited = self.activated.lock().unwrap().take(&spec.listen);
+        let accept_task = match (spec.direction, &mut spec.listen) {
+            (Direction::Local, Endpoint::Tcp(listen)) => {
+                let listener = match inherited {
+                    Some(Inherited::Tcp(listener)) => TcpListener::from_std(listener)?,
+                    _ => TcpListener::bind((listen.host.as_str(), listen.port))
+                        .await
+                        .with_context(|| format!("binding {listen}"))?,
+                };
+                listen.port = listener.local_addr()?.port();
+                Some(self.spawn_accept_loop(Listener::Tcp(listener), spec.clone()))
+            }
+            (Direction::Local, Endpoint::Unix(path)) => {
+                let (listener, file) = match inherited {
+                    Some(Inherited::Unix(listener)) => (listener, None),
+                    _ => {
+                        let (listener, file) = unix_socket::bind(path, self.socket_mode)
+                            .with_context(|| format!("binding {}", path.display()))?;
+                        listener.set_nonblocking(true)?;
+                        (listener, Some(file))
+                    }
+                };
+                let listener = Listener::Unix {
+                    listener: UnixListener::from_std(listener)?,
+                    _file: file,
+                };
+                Some(self.spawn_accept_loop(listener, spec.clone()))
+            }
+            (Direction::Remote, Endpoint::Tcp(listen)) => {
+                let bound = self
+                    .pool
+                    .primary()?
+                    .session
+                    .tcpip_forward(listen.host.as_str(), listen.port.into())
+                    .await
+                    .with_context(|| format!("requesting remote forward of {listen}"))?;
+                // Port 0 asks the server to pick one; record what it chose.
+                if listen.port == 0 {
+                    listen.port = u16::try_from(bound).context("server bound a bad port")?;
+                }
+                info!("server listening on {} -> {}", listen, spec.target);
+
+                self.remote.insert(
+                    listen.host.clone(),
+                    listen.port.into(),
+                    spec.clone(),
+                    self.limiter.forward(),
+                );
+                None
+            }
+            (Direction::Remote, Endpoint::Unix(_)) => {
+                bail!("remote forwards must listen on a port")
+            }
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.active
+            .lock()
+            .unwrap()
+            .insert(id, Active { spec, accept_task });
+        Ok(id)
+    }
+
+    /// Stop accepting new connections for forward `id`. Connections already
+    /// established are left to finish.
+    pub async fn cancel(&self, id: u64) -> Result<()> {
+        let Some(active) = self.active.lock().unwrap().remove(&id) else {
+            bail!("no forward with id {id}");
+        };
+
+        if let Some(task) = active.accept_task {
+            task.abort();
+            // The listener closes as the task is dropped; wait for that, so a
+            // forward added next can bind the same address.
+            let _ = task.await;
+        }
+        if let (Direction::Remote, Endpoint::Tcp(listen)) =
+            (active.spec.direction, &active.spec.listen)
+        {
+            self.remote.remove(&listen.host, listen.port.into());
+            self.pool
+                .primary()?
+                .session
+                .cancel_tcpip_forward(listen.host.as_str(), listen.port.into())
+                .await
+                .with_context(|| format!("cancelling remote forward of {listen}"))?;
+        }
+
+        info!("cancelled {}", active.spec);
+        Ok(())
+    }
+
+    /// Stop accepting new connections on every forward, as for shutdown.
+    pub async fn cancel_all(&self) {
+        for (id, _) in self.list() {
+            if let Err(e) = self.cancel(id).await {
+                warn!("{e:#}");
+            }
+        }
+    }
+
+    /// `(id, spec)` of every active forward, in the order they were added.
+    pub fn list(&self) -> Vec<(u64, ForwardSpec)> {
+        self.active
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, active)| (id, active.spec.clone()))
+            .collect()
+    }
+
+    fn spawn_accept_loop(&self, listener: Listener, spec: ForwardSpec) -> JoinHandle<()> {
+        info!("listening on {} -> {}", spec.listen, spec.target);
+        tokio::spawn(accept_loop(
+            Arc::clone(&self.pool),
+            Arc::clone(&self.stats),
+            listener,
+            self.limiter.forward(),
+            spec,
+            self.preopen_channels,
+        ))
+    }
+}
+
+/// What a local forward listens on. Dropping it (when the accept task ends)
+/// closes the listener and removes a Unix socket's file.
+enum Listener {
+    Tcp(TcpListener),
+    Unix {
+        listener: UnixListener,
+        /// `None` for a socket systemd passed in, whose file is its own.
+        _file: Option<SocketFile>,
+    },
+}
+
+impl Listener {
+    /// Accept one client; Unix-socket clients have no address.
+    async fn accept(&self) -> io::Result<(Local, Option<SocketAddr>)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Local::Tcp(stream), Some(peer)))
+            }
+            Self::Unix { listener, .. } => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Local::Unix(stream), None))
+            }
+        }
+    }
+}
+
+async fn accept_loop(
+    pool: Arc<SessionPool>,
+    stats: Arc<Stats>,
+    listener: Listener,
+    limits: ForwardLimiter,
+    spec: ForwardSpec,
+    preopen_channels: usize,
+) {
+    // Owned by this task, so the pre-opened channels close with the listener.
+    let channels = Arc::new(Channels::new(pool, &stats, &spec.target, preopen_channels));
+    loop {
+        let slot = limits.wait().await;
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("accepting on {}: {e}", spec.listen);
+                stats.record_error(ErrorKind::Accept);
+                sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let pending = stats.pending_connection();
+        let peer_name = peer.map_or_else(|| spec.listen.to_string(), |peer| peer.to_string());
+        debug!("accepted connection from {peer_name}");
+        let Some(slot) = limits.admit(slot).await else {
+            debug!("connection limit reached, resetting {peer_name}");
+            stats.record_error(ErrorKind::Limit);
+            stream.reset();
+            continue;
+        };
+
+        let channels = Arc::clone(&channels);
+        let spec = spec.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                handle_conn(&channels, pending, stream, peer, &peer_name, &spec, slot).await
+            {
+                error!("connection {peer_name}: {e:#}");
+            }
+        });
+    }
+}
+
+/// Where a local forward's connections get their channels.
+struct Channels {
+    sessions: Arc<SessionPool>,
+    /// `--preopen-channels`
+    preopened: Option<ChannelPool<Idle>>,
+}
+
+/// A channel for one connection, with the lease on its session.
+struct Opened {
+    sess: Lease,
+    channel: Channel<Msg>,
+    via: &'static str,
+    /// What the target sent before the connection claimed the channel.
+    early: Vec<u8>,
+}
+
+/// A pre-opened channel waiting for a connection.
+struct Idle {
+    sess: Lease,
+    channel: Channel<Msg>,
+    via: &'static str,
+}
+
+impl IdleChannel for Idle {
+    async fn watch(&mut self, early: &mut Vec<u8>) -> bool {
+        match self.channel.wait().await {
+            Some(ChannelMsg::Data { data }) => {
+                early.extend_from_slice(&data);
+                true
+            }
+            Some(ChannelMsg::Eof | ChannelMsg::Close) | None => false,
+            Some(_) => true,
+        }
+    }
+
+    async fn close(self) {
+        let _ = self.channel.close().await;
+    }
+}
+
+impl Channels {
+    fn new(
+        sessions: Arc<SessionPool>,
+        stats: &Arc<Stats>,
+        target: &Endpoint,
+        preopen_channels: usize,
+    ) -> Self {
+        let preopened = (preopen_channels > 0).then(|| {
+            let sessions = Arc::clone(&sessions);
+            let stats = Arc::clone(stats);
+            let target = target.clone();
+            ChannelPool::new(preopen_channels, move || {
+                let (sessions, stats, target) =
+                    (Arc::clone(&sessions), Arc::clone(&stats), target.clone());
+                async move { preopen(&sessions, &stats, &target).await }
+            })
+        });
+        Self {
+            sessions,
+            preopened,
+        }
+    }
+
+    /// A channel to `target` for the client at `originator`: a pre-opened one
+    /// while any are left, otherwise a new one on the least loaded session.
+    async fn open(&self, target: &Endpoint, originator: Option<SocketAddr>) -> Result<Opened> {
+        if let Some(preopened) = &self.preopened {
+            if let Some(Preopened { channel, early }) = preopened.claim().await {
+                return Ok(Opened {
+                    sess: channel.sess,
+                    channel: channel.channel,
+                    via: channel.via,
+                    early,
+                });
+            }
+        }
+        loop {
+            let sess = self.sessions.lease().await?;
+            match open_channel(&sess, target, originator).await {
+                Ok((channel, via)) => {
+                    return Ok(Opened {
+                        sess,
+                        channel,
+                        via,
+                        early: Vec::new(),
+                    })
+                }
+                // The session failed under us; the next lease drops it and
+                // picks another.
+                Err(e) if sess.session.is_closed() => debug!("retrying on another session: {e:#}"),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Open a channel to `target` to keep idle. There is no client yet, so the
+/// server is told the placeholder originator, and the exec fallback is left
+/// to connections that need it.
+async fn preopen(sessions: &SessionPool, stats: &Stats, target: &Endpoint) -> Option<Idle> {
+    let sess = sessions.lease().await.ok()?;
+    let (via, prohibited) = match target {
+        Endpoint::Tcp(_) => ("direct-tcpip", &sess.tcp_prohibited),
+        Endpoint::Unix(_) => ("direct-streamlocal", &sess.streamlocal_prohibited),
+    };
+    if prohibited.load(Ordering::Relaxed) {
+        return None;
+    }
+    match open_forwarding_channel(&sess, target, None).await {
+        Ok(channel) => Some(Idle { sess, channel, via }),
+        Err(e) => {
+            warn!("pre-opening {via} channel to {target}: {e}");
+            stats.record_error(ErrorKind::ChannelOpen);
+            if sess.exec_fallback && is_prohibited(&e) {
+                warn!("{via} is prohibited by the server, using exec from now on");
+                prohibited.store(true, Ordering::Relaxed);
+            }
+            None
+        }
+    }
+}
+
+/// Splice one accepted connection onto its own channel to the target.
+///
+/// `pending` counts the connection for `--drain-timeout` until its channel is
+/// open and it is registered with the stats.
+#[instrument(skip(channels, pending, stream, peer, spec, slot), fields(forward = %spec))]
+async fn handle_conn(
+    channels: &Channels,
+    pending: PendingGuard,
+    stream: Local,
+    peer: Option<SocketAddr>,
+    peer_name: &str,
+    spec: &ForwardSpec,
+    mut slot: Slot,
+) -> Result<()> {
+    let Opened {
+        sess,
+        channel,
+        via,
+        early,
+    } = channels
+        .open(&spec.target, peer)
+        .await
+        .inspect_err(|_| pending.stats().record_error(ErrorKind::ChannelOpen))?;
+    // Counts bytes as they move, so the registry is live during long transfers.
+    let guard = Arc::new(pending.open(spec.to_string(), peer_name, via));
+    slot.opened();
+    debug!("connected {peer_name} to {} via {via}", spec.target);
+    if let Some(proxy) = sess.proxy_protocol {
+        channel
+            .data(&proxy.header(stream.addrs())[..])
+            .await
+            .inspect_err(|_| guard.record_error(ErrorKind::Transfer))
+            .context("sending the PROXY header")?;
+    }
+
+    match stream {
+        Local::Tcp(stream) => splice(guard, stream, channel, &early, slot).await,
+        Local::Unix(stream) => splice(guard, stream, channel, &early, slot).await,
+    }
+}
+
+/// Open a channel to `target` on the server's side: `direct-tcpip` for a host
+/// and port, `direct-streamlocal` for a socket path. When the server prohibits
+/// those and `--exec-fallback` is set, a `session` channel running a command
+/// that connects to `target` is opened instead.
+///
+/// Returns the channel and how it reaches the target (see `Connection::via`).
+async fn open_channel(
+    sess: &Session,
+    target: &Endpoint,
+    originator: Option<SocketAddr>,
+) -> Result<(Channel<Msg>, &'static str)> {
+    let (via, prohibited) = match target {
+        Endpoint::Tcp(_) => ("direct-tcpip", &sess.tcp_prohibited),
+        Endpoint::Unix(_) => ("direct-streamlocal", &sess.streamlocal_prohibited),
+    };
+
+    if !prohibited.load(Ordering::Relaxed) {
+        match open_forwarding_channel(sess, target, originator).await {
+            Ok(channel) => return Ok((channel, via)),
+            Err(e) if sess.exec_fallback && is_prohibited(&e) => {
+                warn!("{via} is prohibited by the server, using exec from now on");
+                prohibited.store(true, Ordering::Relaxed);
+            }
+            Err(e) if is_prohibited(&e) => {
+                return Err(e).with_context(|| {
+                    format!("opening {via} channel to {target} (see --exec-fallback)")
+                });
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("opening {via} channel to {target}"));
+            }
+        }
+    }
+
+    let channel = open_exec_channel(sess, target)
+        .await
+        .with_context(|| format!("opening exec channel to {target}"))?;
+    Ok((channel, VIA_EXEC))
+}
+
+const fn is_prohibited(err: &russh::Error) -> bool {
+    matches!(
+        err,
+        russh::Error::ChannelOpenFailure(ChannelOpenFailure::AdministrativelyProhibited)
+    )
+}
+
+async fn open_forwarding_channel(
+    sess: &Session,
+    target: &Endpoint,
+    originator: Option<SocketAddr>,
+) -> Result<Channel<Msg>, russh::Error> {
+    match target {
+        Endpoint::Tcp(target) => {
+            let (originator, originator_port) = forward::originator(originator);
+            sess.session
+                .channel_open_direct_tcpip(
+                    target.host.as_str(),
+                    target.port.into(),
+                    originator,
+                    originator_port.into(),
+                )
+                .await
+        }
+        Endpoint::Unix(path) => {
+            sess.session
+                .channel_open_direct_streamlocal(path.to_string_lossy())
+                .await
+        }
+    }
+}
+
+/// Open a `session` channel whose command connects its stdio to `target`.
+///
+/// The server answers `exec` before the command can write anything, so
+/// waiting for that reply here loses no data.
+async fn open_exec_channel(sess: &Session, target: &Endpoint) -> Result<Channel<Msg>> {
+    let mut channel = sess.session.channel_open_session().await?;
+    channel
+        .exec(true, exec_fallback::connect_command(target))
+        .await?;
+    loop {
+        match channel.wait().await {
+            Some(ChannelMsg::Success) => return Ok(channel),
+            Some(ChannelMsg::Failure) => bail!("the server refused to run the connect command"),
+            Some(ChannelMsg::Eof | ChannelMsg::Close) | None => {
+                bail!("the server closed the channel before running the connect command")
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+/// Splice stdin/stdout onto one channel to `target`, like `ssh -W`.
+///
+/// Returns once the server closes the channel. EOF on stdin is passed on as
+/// channel EOF, and the server normally closes its side in response.
+pub async fn stdio(sess: &Session, stats: &Arc<Stats>, target: &Endpoint) -> Result<()> {
+    let opened_at = Instant::now();
+    let (channel, via) = open_channel(sess, target, None)
+        .await
+        .inspect_err(|_| stats.record_error(ErrorKind::ChannelOpen))?;
+    let guard =
+        Arc::new(stats.open_connection(format!("-W {target}"), "stdio", via, opened_at.elapsed()));
+    debug!("stdio connected to {target} via {via}");
+    if let Some(proxy) = sess.proxy_protocol {
+        channel
+            .data(&proxy.header(None)[..])
+            .await
+            .inspect_err(|_| guard.record_error(ErrorKind::Transfer))
+            .context("sending the PROXY header")?;
+    }
+
+    let mut stdin = Metered::new(tokio::io::stdin(), Arc::clone(&guard));
+    let mut stdout = Metered::new(tokio::io::stdout(), Arc::clone(&guard));
+    let (mut from_server, mut to_server) = tokio::io::split(channel.into_stream());
+
+    let up = async {
+        tokio::io::copy(&mut stdin, &mut to_server).await?;
+        to_server.shutdown().await
+    };
+    let down = async {
+        tokio::io::copy(&mut from_server, &mut stdout).await?;
+        stdout.flush().await
+    };
+
+    // Only the server closing the channel ends the session; EOF on stdin just
+    // half-closes it while the rest of the reply arrives.
+    let transfer = async {
+        tokio::pin!(down);
+        select! {
+            res = up => res?,
+            res = &mut down => return res,
+        }
+        down.await
+    };
+    transfer
+        .await
+        .inspect_err(|_| guard.record_error(ErrorKind::Transfer))
+        .context("forwarding stdio")
+}
+
+/// The addresses a `forwarded-tcpip` channel reports, as the source and
+/// destination of a PROXY header: who connected to the server, and which of
+/// its addresses they reached. `None` unless both are IP addresses.
+pub fn forwarded_addrs(
+    originator_address: &str,
+    originator_port: u32,
+    connected_address: &str,
+    connected_port: u32,
+) -> Option<(SocketAddr, SocketAddr)> {
+    let source = SocketAddr::new(
+        originator_address.parse().ok()?,
+        originator_port.try_into().ok()?,
+    );
+    let destination = SocketAddr::new(
+        connected_address.parse().ok()?,
+        connected_port.try_into().ok()?,
+    );
+    Some((source, destination))
+}
+
+/// Connect a `forwarded-tcpip` channel from the server to the local target of
+/// its remote forward. The channel is only confirmed once that connection
+/// succeeds, so the server's client sees a refused connection otherwise.
+///
+/// `proxy_header` (see `--proxy-protocol`) is sent to the target first, and
+/// `pending` counts the connection until it is registered with the stats.
+#[instrument(skip(pending, channel, reply, spec, proxy_header, slot), fields(forward = %spec))]
+pub async fn handle_forwarded(
+    pending: PendingGuard,
+    channel: Channel<Msg>,
+    reply: ChannelOpenHandle,
+    spec: ForwardSpec,
+    originator: String,
+    proxy_header: Option<Vec<u8>>,
+    mut slot: Slot,
+) -> Result<()> {
+    let connected = match &spec.target {
+        Endpoint::Tcp(target) => TcpStream::connect((target.host.as_str(), target.port))
+            .await
+            .map(Local::Tcp),
+        Endpoint::Unix(path) => UnixStream::connect(path).await.map(Local::Unix),
+    };
+    let mut stream = match connected {
+        Ok(stream) => stream,
+        Err(e) => {
+            pending.stats().record_error(ErrorKind::Connect);
+            reply.reject(ChannelOpenFailure::ConnectFailed).await;
+            return Err(e).with_context(|| format!("connecting to {}", spec.target));
+        }
+    };
+    reply.accept().await;
+    let guard = Arc::new(pending.open(spec.to_string(), originator, "forwarded-tcpip"));
+    slot.opened();
+    if let Some(header) = proxy_header {
+        stream
+            .write_all(&header)
+            .await
+            .inspect_err(|_| guard.record_error(ErrorKind::Transfer))
+            .context("sending the PROXY header")?;
+    }
+
+    match stream {
+        Local::Tcp(stream) => splice(guard, stream, channel, &[], slot).await,
+        Local::Unix(stream) => splice(guard, stream, channel, &[], slot).await,
+    }
+}
+
+/// The local end of a forwarded connection.
+enum Local {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Local {
+    /// The peer's address and the local address it connected to; Unix-socket
+    /// connections have neither.
+    fn addrs(&self) -> Option<(SocketAddr, SocketAddr)> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().ok().zip(stream.local_addr().ok()),
+            Self::Unix(_) => None,
+        }
+    }
+
+    /// Drop a connection turned away by a limit; TCP clients get a reset.
+    fn reset(self) {
+        if let Self::Tcp(stream) = &self {
+            let _ = limits::reset_on_close(stream);
+        }
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.write_all(buf).await,
+            Self::Unix(stream) => stream.write_all(buf).await,
+        }
+    }
+}
+
+/// Copy between a local stream and a channel until both sides have shut down,
+/// or until nothing has moved for the idle timeout. Reads on either side are
+/// throttled by the rate limits of `slot`. `early` is what the channel
+/// delivered before the splice, for the local stream.
+async fn splice<S>(
+    guard: Arc<ConnectionGuard>,
+    stream: S,
+    channel: Channel<Msg>,
+    early: &[u8],
+    slot: Slot,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let throttles = slot.throttles();
+    let mut stream = Throttled::new(
+        Metered::new(stream, Arc::clone(&guard)),
+        throttles.up.clone(),
+    );
+    let mut channel_stream = Throttled::new(channel.into_stream(), throttles.down.clone());
+    stream
+        .write_all(early)
+        .await
+        .inspect_err(|_| guard.record_error(ErrorKind::Transfer))
+        .context("forwarding data")?;
+
+    let copied = select! {
+        copied = tokio::io::copy_bidirectional(&mut stream, &mut channel_stream) => copied,
+        () = guard.idle(slot.idle_timeout()) => {
+            debug!("connection idle for {:?}, closing", guard.connection().idle_for());
+            // EOF both ways; dropping the stream then closes the channel.
+            let _ = channel_stream.shutdown().await;
+            let _ = stream.shutdown().await;
+            return Ok(());
+        }
+    };
+    let (to_server, to_client) = copied
+        .inspect_err(|_| guard.record_error(ErrorKind::Transfer))
+        .context("forwarding data")?;
+
+    debug!(
+        "connection closed (via {}): {to_server} bytes sent, {to_client} bytes received",
+        guard.connection().via
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use common_port_forward::limits::Limits;
+
+    use super::*;
+
+    fn remote(spec: &str) -> (String, u32, ForwardSpec) {
+        let spec = ForwardSpec::parse_remote(spec).unwrap();
+        let Endpoint::Tcp(listen) = &spec.listen else {
+            unreachable!("remote forwards listen on TCP");
+        };
+        (listen.host.clone(), u32::from(listen.port), spec)
+    }
+
+    fn forwards(specs: &[&str]) -> RemoteForwards {
+        let limiter = Limiter::new(Limits::default());
+        let forwards = RemoteForwards::default();
+        for spec in specs {
+            let (address, port, spec) = remote(spec);
+            forwards.insert(address, port, spec, limiter.forward());
+        }
+        forwards
+    }
+
+    fn target(forward: Option<RemoteForward>) -> Option<String> {
+        forward.map(|(spec, _)| spec.target.to_string())
+    }
+
+    #[test]
+    fn lookup_falls_back_to_a_unique_port() {
+        let forwards = forwards(&["localhost:8080:127.0.0.1:80", "9090:127.0.0.1:90"]);
+        let lookup = |address, port| target(forwards.lookup(address, port));
+        assert_eq!(lookup("localhost", 8080).as_deref(), Some("127.0.0.1:80"));
+        assert_eq!(lookup("127.0.0.1", 8080).as_deref(), Some("127.0.0.1:80"));
+        assert_eq!(lookup("::1", 9090).as_deref(), Some("127.0.0.1:90"));
+        assert_eq!(lookup("localhost", 7070), None);
+    }
+
+    #[test]
+    fn lookup_rejects_an_ambiguous_port() {
+        let forwards = forwards(&["127.0.0.1:8080:127.0.0.1:80", "10.0.0.1:8080:127.0.0.1:81"]);
+        let lookup = |address, port| target(forwards.lookup(address, port));
+        assert_eq!(lookup("127.0.0.1", 8080).as_deref(), Some("127.0.0.1:80"));
+        assert_eq!(lookup("10.0.0.1", 8080).as_deref(), Some("127.0.0.1:81"));
+        assert_eq!(lookup("localhost", 8080), None);
+    }
+}
@@ -1,4 +1,5 @@
-//! Local port forwarding (`ssh -L`) implemented with russh 0.62.
+//! Local and remote port forwarding (`ssh -L` / `ssh -R`) implemented with
+//! russh 0.62.
 //!
 //! Each accepted TCP connection gets its own `direct-tcpip` channel, and the
 //! two are spliced together with [`tokio::io::copy_bidirectional`]. There is no
@@ -10,35 +11,163 @@
 //! `client::Handle` methods take `&self` in russh 0.62, so the session is
 //! shared through an `Arc` without a `Mutex` — opening a channel never blocks
 //! data flowing on the other channels.
+//!
+//! Forwards can be added and cancelled while the session is up through the
+//! control socket (`--control-path`, driven by the `ctl` subcommand).
+//!
+//! UDP (`-U`) is carried in length-prefixed frames to a relay the tunnel runs
+//! on the server; see the `udp` module.
+//!
+//! Servers that prohibit forwarding can still be used with `--exec-fallback`,
+//! which runs `nc` (or `socat`, or bash) on the server for each connection.
+//!
+//! With `--sessions N` local forwards spread their connections over N sessions
+//! to the server; see the `pool` module, which also opens them only once a
+//! client connects with `--lazy`.
+//!
+//! A tunnel run from a `--profile` reloads its forwards from the config file
+//! on SIGHUP; see the `reload` module.
+//!
+//! With `--stdio` there are no listeners: stdin/stdout are spliced onto a
+//! single channel, as with `ssh -W`, so the binary can be a `ProxyCommand`.
 
 use std::{
+    borrow::Cow,
     fmt::Debug,
-    net::{IpAddr, SocketAddr},
-    path::Path,
-    sync::Arc,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
 };
 
-use anyhow::{anyhow, Context, Result};
-use common_port_forward::{expand_home_dir, get_args, setup_tracing};
+use anyhow::{anyhow, bail, Context, Result};
+use common_port_forward::{
+    activation::{self, Activated},
+    control::run_ctl,
+    daemon::{self, Readiness},
+    expand_home_dir,
+    forward::Direction,
+    get_invocation,
+    limits::Limiter,
+    metrics,
+    proxy_protocol::ProxyProtocol,
+    setup_tracing,
+    shutdown::{self, Signals},
+    stats::{ErrorKind, Stats},
+    transport::{ByteSize, Transport},
+    udp_relay, Arguments, Invocation,
+};
 use russh::{
-    client::{self, Handle},
-    keys::{load_secret_key, PrivateKeyWithHashAlg},
-    Disconnect,
+    cipher,
+    client::{self, ChannelOpenHandle, DisconnectReason, Handle, Msg},
+    compression, kex,
+    keys::{load_secret_key, Algorithm, PrivateKeyWithHashAlg},
+    mac, Channel, ChannelOpenFailure, Disconnect,
 };
 use tokio::{
-    net::{TcpListener, TcpStream},
     select,
+    signal::unix::{signal, SignalKind},
+    sync::Notify,
 };
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
+use crate::{
+    forward::{Forwards, RemoteForwards},
+    pool::SessionPool,
+    reload::Configured,
+};
+
+mod control;
+mod forward;
+mod pool;
+mod reload;
 mod scp;
+mod udp;
 
-struct Client;
+struct Client {
+    stats: Arc<Stats>,
+    remote_forwards: RemoteForwards,
+    /// Header sent ahead of each `forwarded-tcpip` connection, if any.
+    proxy_protocol: Option<ProxyProtocol>,
+}
 
 impl client::Handler for Client {
     type Error = russh::Error;
 
+    async fn disconnected(
+        &mut self,
+        reason: DisconnectReason<Self::Error>,
+    ) -> Result<(), Self::Error> {
+        match reason {
+            DisconnectReason::ReceivedDisconnect(_) => Ok(()),
+            DisconnectReason::Error(e) => {
+                if matches!(e, russh::Error::KeepaliveTimeout) {
+                    self.stats.record_keepalive_failure();
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn server_channel_open_forwarded_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        connected_address: &str,
+        connected_port: u32,
+        originator_address: &str,
+        originator_port: u32,
+        reply: ChannelOpenHandle,
+        _session: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        let Some((spec, limits)) = self
+            .remote_forwards
+            .lookup(connected_address, connected_port)
+        else {
+            // Dropping `reply` rejects the channel.
+            warn!(
+                "server opened a channel for unknown forward {connected_address}:{connected_port}"
+            );
+            return Ok(());
+        };
+
+        let Some(slot) = limits.try_acquire() else {
+            debug!("connection limit reached, refusing {originator_address}:{originator_port}");
+            self.stats.record_error(ErrorKind::Limit);
+            reply.reject(ChannelOpenFailure::ResourceShortage).await;
+            return Ok(());
+        };
+
+        // Connecting to the target may take a while; never stall the session
+        // loop that called us.
+        let pending = self.stats.pending_connection();
+        let originator = format!("{originator_address}:{originator_port}");
+        let proxy_header = self.proxy_protocol.map(|proxy| {
+            proxy.header(forward::forwarded_addrs(
+                originator_address,
+                originator_port,
+                connected_address,
+                connected_port,
+            ))
+        });
+        tokio::spawn(async move {
+            if let Err(e) = forward::handle_forwarded(
+                pending,
+                channel,
+                reply,
+                spec,
+                originator.clone(),
+                proxy_header,
+                slot,
+            )
+            .await
+            {
+                error!("connection {originator}: {e:#}");
+            }
+        });
+        Ok(())
+    }
+
     async fn check_server_key(
         &mut self,
         _server_public_key: &russh::keys::PublicKey,
@@ -51,6 +180,16 @@ impl client::Handler for Client {
 
 pub struct Session {
     session: Handle<Client>,
+    /// Reach targets with `exec` when the server prohibits forwarding
+    /// (`--exec-fallback`).
+    exec_fallback: bool,
+    /// Set once the server has refused a `direct-tcpip` channel as
+    /// prohibited, so later connections go straight to `exec`.
+    tcp_prohibited: AtomicBool,
+    /// The same for `direct-streamlocal`, which sshd controls separately.
+    streamlocal_prohibited: AtomicBool,
+    /// Header sent ahead of each connection on a channel we open, if any.
+    proxy_protocol: Option<ProxyProtocol>,
 }
 
 impl Debug for Session {
@@ -60,93 +199,167 @@ impl Debug for Session {
 }
 
 impl Session {
-    #[instrument]
-    async fn connect<P: AsRef<Path> + Debug>(
-        user: impl Into<String> + Debug,
-        addr: SocketAddr,
-        private_key_path: P,
-    ) -> Result<Self> {
-        let key_pair = load_secret_key(private_key_path, None).context("loading private key")?;
-        let config = Arc::new(client::Config::default());
-        let mut session = client::connect(config, addr, Client)
+    #[instrument(skip_all, fields(user = %connector.user, ip = %connector.ip))]
+    async fn connect(connector: &Connector) -> Result<Self> {
+        let key_pair =
+            load_secret_key(&connector.private_key_path, None).context("loading private key")?;
+        let client = Client {
+            stats: Arc::clone(&connector.stats),
+            remote_forwards: connector.remote_forwards.clone(),
+            proxy_protocol: connector.proxy_protocol,
+        };
+        let addr = SocketAddr::new(IpAddr::V4(connector.ip), 22);
+        let mut session = client::connect(Arc::clone(&connector.config), addr, client)
             .await
             .context("connecting to the SSH server")?;
 
         let auth_res = session
-            .authenticate_publickey(user, PrivateKeyWithHashAlg::new(Arc::new(key_pair), None))
+            .authenticate_publickey(
+                &connector.user,
+                PrivateKeyWithHashAlg::new(Arc::new(key_pair), None),
+            )
             .await
             .context("authenticating")?;
 
         anyhow::ensure!(auth_res.success(), "public key authentication failed");
 
-        Ok(Self { session })
+        Ok(Self {
+            session,
+            exec_fallback: connector.exec_fallback,
+            tcp_prohibited: AtomicBool::new(false),
+            streamlocal_prohibited: AtomicBool::new(false),
+            proxy_protocol: connector.proxy_protocol,
+        })
     }
 
     #[instrument]
     async fn close(&self) -> Result<()> {
         self.session
-            .disconnect(Disconnect::ByApplication, "", "en-US")
+            .disconnect(Disconnect::ByApplication, "shutting down", "en-US")
             .await?;
         Ok(())
     }
 }
 
-/// Splice one accepted TCP connection onto its own `direct-tcpip` channel.
-#[instrument(skip(sess))]
-async fn handle_conn(
-    sess: Arc<Session>,
-    mut stream: TcpStream,
-    peer: SocketAddr,
-    remote_port: u32,
-) -> Result<()> {
-    // Forward to the literal loopback address rather than "localhost": sshd
-    // resolves the name, and on hosts where localhost yields ::1 first an
-    // IPv4-only target is reached only via sshd's fallback from the refused
-    // ::1 attempt. Naming the address we mean removes that dependency
-    // (hardening; sshd's fallback does work in practice).
-    let channel = sess
-        .session
-        .channel_open_direct_tcpip(
-            "127.0.0.1",
-            remote_port,
-            &peer.ip().to_string(),
-            peer.port().into(),
-        )
-        .await
-        .context("opening direct-tcpip channel")?;
-
-    let mut channel_stream = channel.into_stream();
-
-    let (to_server, to_client) = tokio::io::copy_bidirectional(&mut stream, &mut channel_stream)
-        .await
-        .context("forwarding data")?;
-
-    debug!("connection closed: {to_server} bytes sent, {to_client} bytes received");
-    Ok(())
+/// What opening the `--sessions` sessions takes, kept for `--lazy`, which
+/// opens them again whenever none are up.
+pub struct Connector {
+    user: String,
+    ip: Ipv4Addr,
+    private_key_path: PathBuf,
+    config: Arc<client::Config>,
+    sessions: NonZeroUsize,
+    exec_fallback: bool,
+    proxy_protocol: Option<ProxyProtocol>,
+    stats: Arc<Stats>,
+    remote_forwards: RemoteForwards,
 }
 
-#[instrument(skip(sess))]
-async fn listen_on_forwarded_port(
-    sess: Arc<Session>,
-    local_port: u16,
-    remote_port: u32,
-) -> Result<()> {
-    let listener = TcpListener::bind(("127.0.0.1", local_port))
-        .await
-        .with_context(|| format!("binding 127.0.0.1:{local_port}"))?;
-    info!("listening on 127.0.0.1:{local_port} -> localhost:{remote_port}");
+impl Connector {
+    /// Open and authenticate every session.
+    ///
+    /// ## Errors
+    /// if any of them cannot be
+    async fn connect(&self) -> Result<Vec<Session>> {
+        let mut sessions = Vec::with_capacity(self.sessions.get());
+        for _ in 0..self.sessions.get() {
+            sessions.push(Session::connect(self).await?);
+        }
+        if sessions.len() > 1 {
+            info!("opened {} sessions", sessions.len());
+        }
+        Ok(sessions)
+    }
+}
 
-    loop {
-        let (stream, peer) = listener.accept().await.context("accepting connection")?;
-        debug!("accepted connection from {peer}");
+/// russh's client configuration with the transport options applied.
+///
+/// ## Errors
+/// if a size is out of range or russh does not know an algorithm
+fn client_config(transport: &Transport) -> Result<client::Config> {
+    let mut config = client::Config::default();
+    if let Some(ByteSize(size)) = transport.window_size {
+        config.window_size =
+            u32::try_from(size).map_err(|_| anyhow!("--window-size must be below 4GiB"))?;
+    }
+    if let Some(ByteSize(size)) = transport.max_packet_size {
+        config.maximum_packet_size =
+            u32::try_from(size).map_err(|_| anyhow!("--max-packet-size must be below 4GiB"))?;
+    }
+    if let Some(size) = transport.channel_buffer_size {
+        config.channel_buffer_size = size;
+    }
+    config.inactivity_timeout = transport.inactivity_timeout;
+    config.keepalive_interval = transport.keepalive_interval;
+    if let Some(max) = transport.keepalive_max {
+        config.keepalive_max = max;
+    }
+    if let Some(ByteSize(bytes)) = transport.rekey_data {
+        // russh's ciphers would reuse nonces past this.
+        anyhow::ensure!(bytes <= 1 << 30, "--rekey-data must be at most 1GiB");
+        config.limits.rekey_read_limit = bytes as usize;
+        config.limits.rekey_write_limit = bytes as usize;
+    }
+    if let Some(time) = transport.rekey_time {
+        config.limits.rekey_time_limit = time;
+    }
 
-        let sess = Arc::clone(&sess);
-        tokio::spawn(async move {
-            if let Err(e) = handle_conn(sess, stream, peer, remote_port).await {
-                error!("connection {peer}: {e:#}");
-            }
-        });
+    if !transport.kex_algorithms.is_empty() {
+        let mut kex = algorithms(&transport.kex_algorithms, "key exchange", |name| {
+            kex::Name::try_from(name).ok()
+        })?;
+        // Not algorithms but markers that turn on extension negotiation and
+        // strict key exchange; russh offers them with its own list.
+        kex.extend([
+            kex::EXTENSION_SUPPORT_AS_CLIENT,
+            kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+        ]);
+        config.preferred.kex = kex.into();
+    }
+    if !transport.ciphers.is_empty() {
+        config.preferred.cipher = algorithms(&transport.ciphers, "cipher", |name| {
+            cipher::Name::try_from(name).ok()
+        })?
+        .into();
     }
+    if !transport.macs.is_empty() {
+        config.preferred.mac = algorithms(&transport.macs, "MAC", |name| {
+            mac::Name::try_from(name).ok()
+        })?
+        .into();
+    }
+    if !transport.host_key_algorithms.is_empty() {
+        config.preferred.key = algorithms(&transport.host_key_algorithms, "host key", |name| {
+            name.parse::<Algorithm>().ok()
+        })?
+        .into();
+    }
+    if transport.compression {
+        // OpenSSH servers only offer the delayed variant by default.
+        config.preferred.compression = Cow::Borrowed(&[
+            compression::ZLIB_LEGACY,
+            compression::ZLIB,
+            compression::NONE,
+        ]);
+    }
+    Ok(config)
+}
+
+/// Look up each of `names` with `parse`.
+///
+/// ## Errors
+/// naming the first that russh does not support
+fn algorithms<T>(
+    names: &[String],
+    kind: &str,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Result<Vec<T>> {
+    names
+        .iter()
+        .map(|name| {
+            parse(name).ok_or_else(|| anyhow!("russh does not support {kind} algorithm {name:?}"))
+        })
+        .collect()
 }
 
 /// Install a subscriber.
@@ -155,8 +368,9 @@ async fn listen_on_forwarded_port(
 /// binds a fixed TCP port) and writes `trace.json` into the current directory,
 /// so it is opt-in via `PORT_FORWARD_TRACE`; otherwise a plain stderr
 /// subscriber is used (`RUST_LOG` still applies, defaulting to `info`).
-fn init_tracing() {
-    if std::env::var_os("PORT_FORWARD_TRACE").is_some() {
+/// `--stdio` always gets the latter, as stdout carries the forwarded data.
+fn init_tracing(stdio: bool) {
+    if !stdio && std::env::var_os("PORT_FORWARD_TRACE").is_some() {
         setup_tracing();
         return;
     }
@@ -168,38 +382,165 @@ fn init_tracing() {
         .init();
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    init_tracing();
-    let args = get_args();
-
-    let ssh = Arc::new(
-        Session::connect(
-            &args.user,
-            SocketAddr::new(IpAddr::V4(args.ip), 22),
-            expand_home_dir(&args.private_key_path).map_err(|e| anyhow!(e))?,
-        )
-        .await?,
-    );
-
-    let listener = tokio::spawn(listen_on_forwarded_port(
-        Arc::clone(&ssh),
-        args.local_port,
-        u32::from(args.remote_port),
-    ));
+fn main() -> Result<()> {
+    let args = match get_invocation() {
+        Invocation::Ctl(ctl) => return run_ctl(&ctl).map_err(|e| anyhow!(e)),
+        Invocation::UdpRelay(relay) => return Ok(udp_relay::run(&relay)?),
+        Invocation::Tunnel(args) => *args,
+    };
+    // Before the runtime's threads, and any the tracing setup starts; systemd
+    // addresses its sockets to the process it started, not a forked one.
+    let activated = Activated::from_env(&args.forwards())?;
+    let readiness = Readiness::start(&args)?;
+    init_tracing(args.stdio.is_some());
+
+    tokio::runtime::Runtime::new()?.block_on(tunnel(args, activated, readiness))
+}
+
+async fn tunnel(args: Arguments, activated: Activated, mut readiness: Readiness) -> Result<()> {
+    let specs = args.forwards();
+    if specs.is_empty()
+        && args.udp_forwards.is_empty()
+        && args.control_path.is_none()
+        && args.stdio.is_none()
+    {
+        bail!(
+            "nothing to forward: pass --local-port/--remote-port, -L, -R, -U, --stdio or \
+             --control-path"
+        );
+    }
 
-    let shutdown = tokio::spawn(async move {
-        tokio::signal::ctrl_c().await.unwrap();
-        info!("shutting down");
-        if let Err(e) = ssh.close().await {
-            error!("error closing session: {e:#}");
+    if !args.priorities.is_empty() {
+        bail!("--priority is only supported by the ssh2-rs backend");
+    }
+    if args.lazy {
+        // Nothing but a local forward's connections leases a session.
+        if args.stdio.is_some()
+            || !args.udp_forwards.is_empty()
+            || specs.iter().any(|spec| spec.direction == Direction::Remote)
+        {
+            bail!("--lazy serves only local forwards, not -R, -U or --stdio");
+        }
+        if args.preopen_channels > 0 {
+            bail!("--preopen-channels would keep the --lazy sessions open");
         }
+    } else if args.idle_disconnect.is_some() {
+        bail!("--idle-disconnect needs --lazy, which connects again");
+    }
+
+    let stats = Stats::new();
+    if let Some(addr) = args.metrics_listen {
+        metrics::spawn(addr, Arc::clone(&stats))
+            .with_context(|| format!("serving metrics on {addr}"))?;
+    }
+
+    let remote_forwards = RemoteForwards::default();
+    let connector = Connector {
+        user: args.user.clone(),
+        ip: args.ip,
+        private_key_path: expand_home_dir(&args.private_key_path)
+            .map_err(|e| anyhow!(e))?
+            .into_owned(),
+        config: Arc::new(client_config(&args.transport())?),
+        sessions: args.sessions,
+        exec_fallback: args.exec_fallback,
+        proxy_protocol: args.proxy_protocol,
+        stats: Arc::clone(&stats),
+        remote_forwards: remote_forwards.clone(),
+    };
+    let pool = Arc::new(if args.lazy {
+        SessionPool::lazy(connector)
+    } else {
+        SessionPool::connect(&connector).await?
     });
+    if let Some(idle) = args.idle_disconnect {
+        pool.disconnect_when_idle(idle);
+    }
 
-    select! {
-        r = listener => r??,
-        _ = shutdown => {},
+    if let Some(target) = &args.stdio {
+        let res = forward::stdio(pool.primary()?, &stats, target).await;
+        pool.close().await;
+        stats.log_summary();
+        // Reading stdin blocks a runtime thread that cannot be interrupted, so
+        // returning would leave runtime shutdown waiting on it; exit directly,
+        // reporting errors the way returning them from `main` would.
+        std::process::exit(match res {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("Error: {e:?}");
+                1
+            }
+        });
     }
 
+    let forwards = Arc::new(Forwards::new(
+        Arc::clone(&pool),
+        Arc::clone(&stats),
+        remote_forwards,
+        args.socket_mode,
+        Limiter::new(args.limits()),
+        args.preopen_channels,
+        activated,
+    ));
+    let mut configured = Configured::start(
+        &args,
+        Arc::clone(&forwards),
+        Arc::clone(&pool),
+        Arc::clone(&stats),
+    )
+    .await?;
+
+    let exit = Arc::new(Notify::new());
+    let _control = match &args.control_path {
+        Some(path) => {
+            let (listener, file) = control::bind(path)?;
+            tokio::spawn(control::serve(
+                listener,
+                Arc::clone(&forwards),
+                Arc::clone(&exit),
+            ));
+            Some(file)
+        }
+        None => None,
+    };
+
+    if let Some(idle) = args.exit_idle_time {
+        let exit = Arc::clone(&exit);
+        activation::exit_when_idle(Arc::clone(&stats), idle, move || exit.notify_one())?;
+    }
+
+    let mut signals = Signals::new()?;
+    // Without a profile there is nothing to reload, and SIGHUP still ends
+    // the tunnel.
+    let mut hangup = match args.profile {
+        Some(_) => Some(signal(SignalKind::hangup())?),
+        None => None,
+    };
+    readiness.ready()?;
+    loop {
+        select! {
+            name = signals.recv() => {
+                info!("{name} received, shutting down");
+                break;
+            }
+            () = exit.notified() => {
+                info!("shutting down");
+                break;
+            }
+            Some(()) = async { hangup.as_mut()?.recv().await }, if hangup.is_some() => {
+                info!("SIGHUP received, reloading the config file");
+                configured.reload().await;
+            }
+        }
+    }
+    signals.exit_on_next();
+    daemon::notify_systemd("STOPPING=1");
+
+    forwards.cancel_all().await;
+    configured.stop_udp();
+    shutdown::drain(&stats, args.drain_timeout).await;
+    pool.close().await;
+
+    stats.log_summary();
     Ok(())
 }
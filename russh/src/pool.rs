@@ -0,0 +1,234 @@
+//! The `--sessions` pool: several SSH sessions to the same server, so
+//! connections on a local forward are not all bound by one TCP connection's
+//! congestion window.
+//!
+//! Each new connection takes a [`Lease`] on the live session with the fewest
+//! connections. A session whose connection to the server has failed is dropped
+//! from the pool the next time anyone asks for one, and the others keep
+//! serving. Remote forwards, UDP forwards and `--stdio` stay on the first
+//! session, which the server addresses its `forwarded-tcpip` channels to.
+//!
+//! With `--lazy` the pool starts empty and the first connection to lease a
+//! session opens them all; with `--idle-disconnect` they are closed again once
+//! nothing has leased one for that long. Only local forwards can be served
+//! this way, as nothing else leases its session.
+
+use std::{
+    mem,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+use crate::{Connector, Session};
+
+/// How often [`SessionPool::disconnect_when_idle`] looks at the leases.
+const IDLE_POLL: Duration = Duration::from_secs(1);
+
+pub struct SessionPool {
+    /// The first session; `None` with `--lazy`, whose sessions come and go.
+    primary: Option<Arc<Session>>,
+    /// The sessions still up, in the order they were opened.
+    sessions: Mutex<Vec<Arc<PooledSession>>>,
+    /// With `--lazy`, what opens the sessions when a connection needs one.
+    connector: Option<Connector>,
+    /// Held while the sessions are opened, so clients arriving meanwhile wait
+    /// for them instead of opening their own.
+    connecting: tokio::sync::Mutex<()>,
+    /// Leases handed out so far, for `--idle-disconnect`.
+    leased: AtomicU64,
+    /// Whether the sessions have been opened before, so opening them again
+    /// counts as a reconnect.
+    opened: AtomicBool,
+}
+
+struct PooledSession {
+    session: Arc<Session>,
+    /// Index in the order the sessions were opened, for logging.
+    index: usize,
+    /// Connections currently using the session.
+    connections: AtomicUsize,
+}
+
+impl SessionPool {
+    /// Open the sessions now.
+    ///
+    /// ## Errors
+    /// if any of them cannot be opened
+    pub async fn connect(connector: &Connector) -> Result<Self> {
+        let sessions = pooled(connector.connect().await?);
+        Ok(Self {
+            primary: sessions.first().map(|pooled| Arc::clone(&pooled.session)),
+            sessions: Mutex::new(sessions),
+            connector: None,
+            connecting: tokio::sync::Mutex::new(()),
+            leased: AtomicU64::new(0),
+            opened: AtomicBool::new(true),
+        })
+    }
+
+    /// Open the sessions when the first connection asks for one (`--lazy`).
+    pub fn lazy(connector: Connector) -> Self {
+        Self {
+            primary: None,
+            sessions: Mutex::new(Vec::new()),
+            connector: Some(connector),
+            connecting: tokio::sync::Mutex::new(()),
+            leased: AtomicU64::new(0),
+            opened: AtomicBool::new(false),
+        }
+    }
+
+    /// The first session, which carries everything but local forwards.
+    ///
+    /// ## Errors
+    /// with `--lazy`, which keeps no session for them
+    pub fn primary(&self) -> Result<&Arc<Session>> {
+        self.primary
+            .as_ref()
+            .ok_or_else(|| anyhow!("only local forwards are served with --lazy"))
+    }
+
+    /// The live session with the fewest connections, for one more. With
+    /// `--lazy`, the sessions are opened first if none are up.
+    ///
+    /// ## Errors
+    /// if every session has failed, or with `--lazy` if they cannot be opened
+    pub async fn lease(&self) -> Result<Lease> {
+        loop {
+            if let Some(lease) = self.lease_live() {
+                return Ok(lease);
+            }
+            let Some(connector) = &self.connector else {
+                bail!("every SSH session has failed");
+            };
+            let _connecting = self.connecting.lock().await;
+            // Another client may have opened them while this one waited.
+            if self.sessions.lock().unwrap().is_empty() {
+                info!("connecting to the server for a new connection");
+                let sessions = pooled(connector.connect().await?);
+                // They failed or --idle-disconnect closed them.
+                if self.opened.swap(true, Ordering::Relaxed) {
+                    connector.stats.record_reconnect();
+                }
+                *self.sessions.lock().unwrap() = sessions;
+            }
+        }
+    }
+
+    fn lease_live(&self) -> Option<Lease> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|pooled| {
+            let closed = pooled.session.session.is_closed();
+            if closed {
+                warn!(
+                    "session {} failed, dropping it with {} connection(s) from the pool",
+                    pooled.index,
+                    pooled.connections.load(Ordering::Relaxed)
+                );
+            }
+            !closed
+        });
+        let pooled = sessions
+            .iter()
+            .min_by_key(|pooled| pooled.connections.load(Ordering::Relaxed))?;
+        pooled.connections.fetch_add(1, Ordering::Relaxed);
+        self.leased.fetch_add(1, Ordering::Relaxed);
+        Some(Lease(Arc::clone(pooled)))
+    }
+
+    /// Close the sessions once nothing has leased one for `idle`
+    /// (`--idle-disconnect`), leaving the next lease to open them again.
+    pub fn disconnect_when_idle(self: &Arc<Self>, idle: Duration) {
+        let pool = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut last_seen = (pool.leased.load(Ordering::Relaxed), Instant::now());
+            loop {
+                sleep(IDLE_POLL).await;
+                // A connection opened and closed between two looks still
+                // counts as activity.
+                let leased = pool.leased.load(Ordering::Relaxed);
+                if leased != last_seen.0 || in_use(&pool.sessions.lock().unwrap()) {
+                    last_seen = (leased, Instant::now());
+                } else if last_seen.1.elapsed() >= idle {
+                    pool.disconnect_idle(idle).await;
+                    last_seen.1 = Instant::now();
+                }
+            }
+        });
+    }
+
+    async fn disconnect_idle(&self, idle: Duration) {
+        let sessions = {
+            let mut sessions = self.sessions.lock().unwrap();
+            // Checked under the lock, so no lease is taken meanwhile.
+            if in_use(&sessions) {
+                return;
+            }
+            mem::take(&mut *sessions)
+        };
+        if sessions.is_empty() {
+            return;
+        }
+        info!("no forwarded connections for {idle:?}, disconnecting");
+        for pooled in sessions {
+            if let Err(e) = pooled.session.close().await {
+                error!("error closing session {}: {e:#}", pooled.index);
+            }
+        }
+    }
+
+    /// Disconnect every session that is still up.
+    pub async fn close(&self) {
+        let sessions = self.sessions.lock().unwrap().clone();
+        for pooled in sessions {
+            if let Err(e) = pooled.session.close().await {
+                error!("error closing session {}: {e:#}", pooled.index);
+            }
+        }
+    }
+}
+
+fn in_use(sessions: &[Arc<PooledSession>]) -> bool {
+    sessions
+        .iter()
+        .any(|pooled| pooled.connections.load(Ordering::Relaxed) > 0)
+}
+
+fn pooled(sessions: Vec<Session>) -> Vec<Arc<PooledSession>> {
+    sessions
+        .into_iter()
+        .enumerate()
+        .map(|(index, session)| {
+            Arc::new(PooledSession {
+                session: Arc::new(session),
+                index,
+                connections: AtomicUsize::new(0),
+            })
+        })
+        .collect()
+}
+
+/// A connection's use of a pooled session, given back when dropped.
+pub struct Lease(Arc<PooledSession>);
+
+impl Deref for Lease {
+    type Target = Session;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0.session
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,144 @@
+//! Reloading the config file on SIGHUP (`--profile`).
+//!
+//! The command line is parsed again against the rewritten file and the
+//! forwards are brought in line with the result: forwards no longer asked for
+//! are cancelled, leaving their connections to finish, new ones are started,
+//! and unchanged ones and the sessions are left alone. Other options keep the
+//! values the tunnel started with, and forwards added over the control socket
+//! are not the config's to remove.
+
+use std::{mem, sync::Arc};
+
+use anyhow::Result;
+use common_port_forward::{
+    forward::{ForwardSpec, UdpSpec},
+    reload_args,
+    stats::Stats,
+    Arguments,
+};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::{forward::Forwards, pool::SessionPool, udp};
+
+/// The forwards started from the command line and config file.
+pub struct Configured {
+    forwards: Arc<Forwards>,
+    pool: Arc<SessionPool>,
+    stats: Arc<Stats>,
+    udp_relay_command: String,
+    /// Each spec as given, with the id [`Forwards::add`] assigned it.
+    tcp: Vec<(ForwardSpec, u64)>,
+    udp: Vec<(UdpSpec, JoinHandle<()>)>,
+}
+
+impl Configured {
+    /// Start every forward `args` asks for.
+    ///
+    /// ## Errors
+    /// if any of them cannot be started
+    pub async fn start(
+        args: &Arguments,
+        forwards: Arc<Forwards>,
+        pool: Arc<SessionPool>,
+        stats: Arc<Stats>,
+    ) -> Result<Self> {
+        let mut configured = Self {
+            forwards,
+            pool,
+            stats,
+            udp_relay_command: args.udp_relay_command.clone(),
+            tcp: Vec::new(),
+            udp: Vec::new(),
+        };
+        for spec in args.forwards() {
+            let id = configured.forwards.add(spec.clone()).await?;
+            configured.tcp.push((spec, id));
+        }
+        for spec in &args.udp_forwards {
+            let task = configured.start_udp(spec).await?;
+            configured.udp.push((spec.clone(), task));
+        }
+        Ok(configured)
+    }
+
+    /// Re-read the config file and start and stop forwards to match it. A
+    /// file that no longer parses changes nothing.
+    pub async fn reload(&mut self) {
+        let args = match reload_args() {
+            Ok(args) => args,
+            Err(e) => {
+                warn!("not reloading: {}", e.trim_end());
+                return;
+            }
+        };
+        let specs = args.forwards();
+        let (mut removed, mut added) = (0, 0);
+
+        // Cancel first, so a forward whose target changed can bind its
+        // address again.
+        for (spec, id) in mem::take(&mut self.tcp) {
+            if specs.contains(&spec) {
+                self.tcp.push((spec, id));
+            } else if let Err(e) = self.forwards.cancel(id).await {
+                warn!("{e:#}");
+            } else {
+                removed += 1;
+            }
+        }
+        for (spec, task) in mem::take(&mut self.udp) {
+            if args.udp_forwards.contains(&spec) {
+                self.udp.push((spec, task));
+            } else {
+                task.abort();
+                info!("cancelled {spec}");
+                removed += 1;
+            }
+        }
+
+        for spec in specs {
+            if self.tcp.iter().any(|(running, _)| *running == spec) {
+                continue;
+            }
+            match self.forwards.add(spec.clone()).await {
+                Ok(id) => {
+                    self.tcp.push((spec, id));
+                    added += 1;
+                }
+                Err(e) => warn!("starting {spec}: {e:#}"),
+            }
+        }
+        for spec in args.udp_forwards {
+            if self.udp.iter().any(|(running, _)| *running == spec) {
+                continue;
+            }
+            match self.start_udp(&spec).await {
+                Ok(task) => {
+                    self.udp.push((spec, task));
+                    added += 1;
+                }
+                Err(e) => warn!("starting {spec}: {e:#}"),
+            }
+        }
+
+        info!("reloaded config: {added} forward(s) started, {removed} stopped");
+    }
+
+    /// Stop the UDP forwards, as for shutdown; [`Forwards::cancel_all`] stops
+    /// the rest.
+    pub fn stop_udp(self) {
+        for (_, task) in self.udp {
+            task.abort();
+        }
+    }
+
+    async fn start_udp(&self, spec: &UdpSpec) -> Result<JoinHandle<()>> {
+        udp::start(
+            Arc::clone(self.pool.primary()?),
+            Arc::clone(&self.stats),
+            spec.clone(),
+            &self.udp_relay_command,
+        )
+        .await
+    }
+}
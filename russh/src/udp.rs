@@ -0,0 +1,273 @@
+//! UDP forwarding (`-U`).
+//!
+//! Each forward runs a relay on the server (`--udp-relay-command`, see
+//! `common_port_forward::udp_relay` for the protocol) and binds a local UDP
+//! socket. Every local peer gets its own `direct-tcpip` channel to the relay,
+//! and so its own UDP socket on the server, which is what routes replies back
+//! to the peer that sent the request. A peer's channel is closed once it has
+//! been idle in both directions for [`IDLE_TIMEOUT`].
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use common_port_forward::{
+    forward::UdpSpec,
+    shell_quote,
+    stats::{ErrorKind, Stats},
+    udp_relay::MAX_DATAGRAM,
+};
+use russh::{client::Msg, Channel, ChannelMsg};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    select,
+    sync::mpsc,
+    task::JoinHandle,
+    time::sleep,
+};
+use tracing::{debug, info, instrument, warn};
+
+use crate::Session;
+
+/// How long a peer may stay silent, with no replies either, before its channel
+/// is closed. Like a NAT mapping, the next datagram opens a new one.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Datagrams queued per peer while its channel opens or is slow; more are
+/// dropped, as a congested UDP path would.
+const PEER_QUEUE: usize = 64;
+
+/// Where the relay listens on the server, and the token it expects.
+struct Relay {
+    port: u16,
+    token: String,
+}
+
+/// Start the relay for `spec` on the server, bind the local socket and serve
+/// it in the background, returning the task that does; aborting it stops
+/// taking new peers.
+///
+/// ## Errors
+/// if the relay cannot be started or the local socket cannot be bound
+pub async fn start(
+    sess: Arc<Session>,
+    stats: Arc<Stats>,
+    spec: UdpSpec,
+    relay_command: &str,
+) -> Result<JoinHandle<()>> {
+    let command = format!("{relay_command} {}", shell_quote(&spec.target.to_string()));
+    let (relay, channel) = start_relay(&sess, &command).await?;
+    debug!(
+        "UDP relay for {spec} listening on server port {}",
+        relay.port
+    );
+
+    let socket = UdpSocket::bind((spec.listen.host.as_str(), spec.listen.port))
+        .await
+        .with_context(|| format!("binding UDP {}", spec.listen))?;
+    info!(
+        "listening on UDP {} -> {}",
+        socket.local_addr()?,
+        spec.target
+    );
+
+    tokio::spawn(watch_relay(channel, spec.clone()));
+    Ok(tokio::spawn(serve(
+        sess,
+        stats,
+        Arc::new(socket),
+        Arc::new(spec),
+        Arc::new(relay),
+    )))
+}
+
+/// Run `command` and read the `<port> <token>` line it prints.
+async fn start_relay(sess: &Session, command: &str) -> Result<(Relay, Channel<Msg>)> {
+    let mut channel = sess
+        .session
+        .channel_open_session()
+        .await
+        .context("opening a session for the UDP relay")?;
+    channel.exec(true, command).await?;
+
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let line = loop {
+        if let Some(end) = stdout.iter().position(|&b| b == b'\n') {
+            break String::from_utf8_lossy(&stdout[..end]).into_owned();
+        }
+        match channel.wait().await {
+            Some(ChannelMsg::Data { data }) => stdout.extend_from_slice(&data),
+            Some(ChannelMsg::ExtendedData { data, .. }) => stderr.extend_from_slice(&data),
+            Some(ChannelMsg::Failure) => bail!("the server refused to run `{command}`"),
+            Some(ChannelMsg::Eof | ChannelMsg::Close | ChannelMsg::ExitStatus { .. }) | None => {
+                let stderr = String::from_utf8_lossy(&stderr);
+                if stderr.trim().is_empty() {
+                    bail!("UDP relay `{command}` exited without reporting its port");
+                }
+                bail!("UDP relay `{command}` exited: {}", stderr.trim())
+            }
+            Some(_) => {}
+        }
+    };
+
+    let relay = line
+        .split_once(' ')
+        .and_then(|(port, token)| {
+            Some(Relay {
+                port: port.parse().ok()?,
+                token: token.to_owned(),
+            })
+        })
+        .with_context(|| format!("UDP relay `{command}` printed {line:?}, not `<port> <token>`"))?;
+    Ok((relay, channel))
+}
+
+/// Hold the relay's `exec` channel open for as long as the tunnel runs, and
+/// surface anything it reports.
+async fn watch_relay(mut channel: Channel<Msg>, spec: UdpSpec) {
+    while let Some(msg) = channel.wait().await {
+        match msg {
+            ChannelMsg::ExtendedData { data, .. } => {
+                warn!(
+                    "UDP relay for {spec}: {}",
+                    String::from_utf8_lossy(&data).trim()
+                );
+            }
+            ChannelMsg::ExitStatus { exit_status } => {
+                warn!("UDP relay for {spec} exited with status {exit_status}");
+            }
+            _ => {}
+        }
+    }
+}
+
+#[instrument(skip_all, fields(forward = %spec))]
+async fn serve(
+    sess: Arc<Session>,
+    stats: Arc<Stats>,
+    socket: Arc<UdpSocket>,
+    spec: Arc<UdpSpec>,
+    relay: Arc<Relay>,
+) {
+    let mut peers: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                // e.g. ECONNREFUSED left over from replying to a closed port.
+                debug!("receiving on {}: {e}", spec.listen);
+                continue;
+            }
+        };
+
+        if peers.get(&peer).is_none_or(mpsc::Sender::is_closed) {
+            peers.retain(|_, datagrams| !datagrams.is_closed());
+            let (datagrams, queued) = mpsc::channel(PEER_QUEUE);
+            peers.insert(peer, datagrams);
+
+            let sess = Arc::clone(&sess);
+            let stats = Arc::clone(&stats);
+            let socket = Arc::clone(&socket);
+            let spec = Arc::clone(&spec);
+            let relay = Arc::clone(&relay);
+            tokio::spawn(async move {
+                if let Err(e) = handle_peer(sess, stats, socket, &spec, &relay, peer, queued).await
+                {
+                    warn!("UDP peer {peer}: {e:#}");
+                }
+            });
+        }
+
+        if peers[&peer].try_send(buf[..len].to_vec()).is_err() {
+            debug!("dropping datagram from {peer}: queue full");
+        }
+    }
+}
+
+/// Carry one peer's datagrams over its own channel until it goes idle.
+async fn handle_peer(
+    sess: Arc<Session>,
+    stats: Arc<Stats>,
+    socket: Arc<UdpSocket>,
+    spec: &UdpSpec,
+    relay: &Relay,
+    peer: SocketAddr,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+) -> Result<()> {
+    let opened_at = Instant::now();
+    let channel = sess
+        .session
+        .channel_open_direct_tcpip(
+            "127.0.0.1",
+            relay.port.into(),
+            peer.ip().to_string(),
+            peer.port().into(),
+        )
+        .await
+        .inspect_err(|_| stats.record_error(ErrorKind::ChannelOpen))
+        .context("opening channel to the UDP relay")?;
+    let guard = stats.open_connection(
+        spec.to_string(),
+        peer.to_string(),
+        "direct-tcpip",
+        opened_at.elapsed(),
+    );
+
+    let (mut from_relay, mut to_relay) = tokio::io::split(channel.into_stream());
+    to_relay
+        .write_all(format!("{}\n", relay.token).as_bytes())
+        .await?;
+
+    // Frames are decoded on a task of their own: a read cut short by `select!`
+    // would lose its place in the stream, while `mpsc::Receiver::recv` is safe
+    // to cancel.
+    let (reply_tx, mut replies) = mpsc::channel::<Vec<u8>>(PEER_QUEUE);
+    let reader = tokio::spawn(async move {
+        loop {
+            let len = from_relay.read_u16().await?;
+            let mut reply = vec![0u8; len.into()];
+            from_relay.read_exact(&mut reply).await?;
+            if reply_tx.send(reply).await.is_err() {
+                return Ok::<_, std::io::Error>(());
+            }
+        }
+    });
+
+    let res = async {
+        loop {
+            select! {
+                datagram = datagrams.recv() => {
+                    let Some(datagram) = datagram else { break };
+                    let len = u16::try_from(datagram.len()).context("datagram too large")?;
+                    to_relay.write_all(&len.to_be_bytes()).await?;
+                    to_relay.write_all(&datagram).await?;
+                    guard.record_up(datagram.len());
+                }
+                reply = replies.recv() => {
+                    // The relay side closed; the next datagram reconnects.
+                    let Some(reply) = reply else { break };
+                    socket.send_to(&reply, peer).await?;
+                    guard.record_down(reply.len());
+                }
+                () = sleep(IDLE_TIMEOUT) => {
+                    debug!("UDP peer {peer} idle, closing its channel");
+                    break;
+                }
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await
+    .inspect_err(|_| guard.record_error(ErrorKind::Transfer));
+
+    reader.abort();
+    let _ = to_relay.shutdown().await;
+    res
+}
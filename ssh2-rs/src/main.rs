@@ -13,44 +13,99 @@
 //!
 //! * the session is put in **non-blocking** mode after authentication, so every
 //!   channel call returns [`std::io::ErrorKind::WouldBlock`] instead of parking,
-//! * a **single** thread owns the session, the listener and every connection and
+//! * a **single** thread owns the session, the listeners and every connection and
 //!   pumps all of them in one loop, so the session lock is never contended and
 //!   channel-open state can never be interleaved,
-//! * each accepted TCP connection gets **its own** `channel_direct_tcpip`
-//!   channel (libssh2 stream ids select stdout/stderr of one channel, they are
-//!   *not* independent streams),
+//! * each accepted TCP or Unix-socket connection gets **its own**
+//!   `channel_direct_tcpip` channel (libssh2 stream ids select stdout/stderr of
+//!   one channel, they are *not* independent streams), or with `--exec-fallback`
+//!   on a server that prohibits those, a `session` channel running `nc` (or
+//!   `socat`, or bash) towards the target,
 //! * when a full pass over every connection moves zero bytes the loop blocks in
-//!   `poll(2)` on the SSH socket, the listener and the client sockets instead of
-//!   spinning, so an idle (or network-bound) tunnel costs ~0% CPU.
+//!   epoll (through `mio`) on the SSH socket, the listeners and the client
+//!   sockets instead of spinning, so an idle (or network-bound) tunnel costs ~0%
+//!   CPU. Each socket is registered once, edge-triggered, and the loop only
+//!   retries what an event (or a deadline) may have unblocked; Ctrl-C wakes it
+//!   through an eventfd.
+//!
+//! With `--sessions N` there are N sessions, each with its own thread and loop
+//! as above. Every loop watches every listener, and the one whose session has
+//! the fewest connections accepts the next client.
+//!
+//! With `--preopen-channels K` each loop also opens K channels per forward
+//! whenever it has nothing else to open, and hands them to new clients without
+//! a round trip.
+
+mod open_queue;
 
 use std::{
     collections::VecDeque,
     io::{ErrorKind, Read, Write},
-    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
-    os::fd::{AsRawFd, RawFd},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
+    thread,
     time::{Duration, Instant},
 };
 
 use anyhow::anyhow;
-use common_port_forward::{expand_home_dir, get_args, setup_tracing};
-use ssh2::{BlockDirections, Channel, ErrorCode, Session};
+use common_port_forward::{
+    activation::{self, Activated, Inherited},
+    daemon::{self, Readiness},
+    exec_fallback::{self, VIA_EXEC},
+    expand_home_dir,
+    forward::{self, Direction, Endpoint, ForwardSpec, Priority},
+    get_args,
+    limits::{self, LimitAction, Limits},
+    metrics,
+    preopen::REFILL_BACKOFF,
+    rate_limit::{Throttle, Throttles},
+    setup_tracing, shutdown,
+    stats::{self, ConnectionGuard, Stats},
+    transport::Transport,
+    unix_socket::{self, SocketFile},
+    Arguments,
+};
+use mio::{unix::SourceFd, Events, Interest, Poll, Registry, Token, Waker};
+use open_queue::{Expired, Finished, OpenQueue, Owner, Request, Waiting};
+use ssh2::{Channel, DisconnectCode, ErrorCode, ExtendedData, MethodType, Session};
 use tracing::{debug, error, info, trace, warn};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 /// Per-direction, per-connection buffer size.
 const BUFFER_SIZE: usize = 32 * 1024;
-/// Upper bound on how long the loop blocks in `poll(2)` with nothing to do.
-/// Also bounds how quickly Ctrl-C is noticed.
-const POLL_TIMEOUT_MS: libc::c_int = 100;
-/// How long a channel open may stay pending before the client is dropped.
+/// How much a connection reads per direction per pass of the event loop, by
+/// `--priority`: a high connection gets twice the share of a normal one, and a
+/// low one half.
+const HIGH_PRIORITY_QUOTA: usize = BUFFER_SIZE;
+const NORMAL_PRIORITY_QUOTA: usize = 16 * 1024;
+const LOW_PRIORITY_QUOTA: usize = 8 * 1024;
+/// Events handled per wakeup of the event loop; more are picked up by the
+/// next one.
+const EVENT_CAPACITY: usize = 1024;
+/// Wakes the event loop when a signal arrives.
+const WAKER: Token = Token(0);
+const SSH: Token = Token(1);
+/// Listeners use the tokens from here on, one per forward, and connections
+/// those after that.
+const FIRST_LISTENER: usize = 2;
+/// How long a client may wait for its channel, from when it was accepted,
+/// before it is dropped. An open already in flight for it is still seen
+/// through; see `open_queue`.
 const OPEN_TIMEOUT: Duration = Duration::from_secs(30);
 /// How long we keep retrying a non-blocking `channel.close()` before giving up.
 const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Seconds between SSH keepalives. libssh2 only sends them when
+/// `keepalive_send` is called, which the event loop does once they are due.
+const KEEPALIVE_INTERVAL: u32 = 30;
+/// How soon to retry a keepalive that could not be sent yet.
+const KEEPALIVE_RETRY: Duration = Duration::from_secs(1);
 
 /// Is this error libssh2's (or the socket's) "try again later"?
 fn would_block(err: &std::io::Error) -> bool {
@@ -65,15 +120,6 @@ fn ssh_would_block(err: &ssh2::Error) -> bool {
     err.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN)
 }
 
-fn ssh_poll_events(session: &Session) -> libc::c_short {
-    match session.block_directions() {
-        BlockDirections::Inbound => libc::POLLIN,
-        BlockDirections::Outbound => libc::POLLOUT,
-        BlockDirections::Both => libc::POLLIN | libc::POLLOUT,
-        BlockDirections::None => libc::POLLIN,
-    }
-}
-
 /// A single-producer/single-consumer byte buffer for one direction of a
 /// connection. Refilled only once fully drained, which keeps ordering trivial
 /// and bounds memory to `BUFFER_SIZE` per direction.
@@ -110,6 +156,11 @@ impl Buffer {
         self.end = n;
     }
 
+    fn clear(&mut self) {
+        self.start = 0;
+        self.end = 0;
+    }
+
     fn consume(&mut self, n: usize) {
         self.start += n;
         if self.start == self.end {
@@ -119,12 +170,97 @@ impl Buffer {
     }
 }
 
-/// One forwarded TCP connection: a client socket paired with its own
+/// An accepted client socket.
+enum Client {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Client {
+    /// Switch to non-blocking mode for the event loop; TCP clients also get
+    /// `TCP_NODELAY` so small writes are not held back.
+    fn configure(&self) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => {
+                let _ = stream.set_nodelay(true);
+                stream.set_nonblocking(true)
+            }
+            Self::Unix(stream) => stream.set_nonblocking(true),
+        }
+    }
+
+    /// The client's address and the local address it connected to;
+    /// Unix-socket clients have neither.
+    fn addrs(&self) -> Option<(SocketAddr, SocketAddr)> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().ok().zip(stream.local_addr().ok()),
+            Self::Unix(_) => None,
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.shutdown(how),
+            Self::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Client {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Client {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Client {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(stream) => stream.as_raw_fd(),
+            Self::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+/// One forwarded connection: a client socket paired with its own
 /// `direct-tcpip` channel.
 struct Connection {
     id: u64,
-    stream: TcpStream,
+    /// Identifies the client socket's events.
+    token: Token,
+    /// Counts the connection against its session and forward.
+    _share: Share,
+    /// Live byte counters; unregisters the connection from [`Stats`] on drop.
+    stats: ConnectionGuard,
+    stream: Client,
     channel: Channel,
+    /// The client socket may be readable: set by an event, cleared once a
+    /// read would block (registrations are edge-triggered).
+    client_readable: bool,
+    /// As `client_readable`, for writes.
+    client_writable: bool,
+    /// A channel read would block until the SSH socket has an event.
+    read_blocked: bool,
+    /// As `read_blocked`, for channel writes.
+    write_blocked: bool,
     /// Bytes read from the client, waiting to be written to the channel.
     to_remote: Buffer,
     /// Bytes read from the channel, waiting to be written to the client.
@@ -141,22 +277,57 @@ struct Connection {
     closing_since: Option<Instant>,
     /// Ready to be reaped by the event loop.
     finished: bool,
+    /// `--idle-timeout`.
+    idle_timeout: Option<Duration>,
+    /// The forward's `--priority`.
+    priority: Priority,
+    throttles: Throttles,
+    /// Until when reading from the client is held back by `throttles.up`.
+    up_throttled_until: Option<Instant>,
+    /// Until when reading from the channel is held back by `throttles.down`.
+    down_throttled_until: Option<Instant>,
 }
 
 impl Connection {
-    fn new(id: u64, stream: TcpStream, channel: Channel) -> Self {
+    /// `header` (see `--proxy-protocol`) is sent on the channel ahead of the
+    /// client's data.
+    fn new(
+        open: PendingOpen,
+        stats: ConnectionGuard,
+        opened: Opened,
+        header: &[u8],
+        forward: &Forward,
+        limits: &Limits,
+    ) -> Self {
+        let mut to_remote = Buffer::new();
+        to_remote.spare()[..header.len()].copy_from_slice(header);
+        to_remote.filled(header.len());
+        let mut share = open.share;
+        share.opened();
         Self {
-            id,
-            stream,
-            channel,
-            to_remote: Buffer::new(),
-            to_local: Buffer::new(),
+            id: stats.id(),
+            token: open.token,
+            _share: share,
+            stats,
+            stream: open.stream,
+            channel: opened.channel,
+            client_readable: true,
+            client_writable: true,
+            read_blocked: false,
+            write_blocked: false,
+            to_remote,
+            to_local: opened.early,
             local_eof: false,
             eof_sent: false,
             remote_eof: false,
             local_shutdown: false,
             closing_since: None,
             finished: false,
+            idle_timeout: limits.idle_timeout,
+            priority: forward.priority,
+            throttles: forward.throttles.with(limits.connection_rate_limit),
+            up_throttled_until: None,
+            down_throttled_until: None,
         }
     }
 
@@ -165,33 +336,63 @@ impl Connection {
             "connection {}: {} failed ({}), tearing down",
             self.id, direction, err
         );
+        self.stats.record_error(stats::ErrorKind::Transfer);
         let _ = self.stream.shutdown(Shutdown::Both);
         self.finished = true;
     }
 
-    /// Move as much data as possible in both directions without blocking.
+    /// Move data in both directions without blocking, reading at most one
+    /// quota (see [`Connection::quota`]) each way.
     ///
-    /// Returns `true` if anything at all happened; the event loop only sleeps
-    /// in `poll(2)` once every connection reports `false`, which guarantees we
+    /// Returns `true` if anything at all happened; the event loop only waits
+    /// for events once every connection reports `false`, which guarantees we
     /// never park while libssh2 still has buffered data for us.
     fn pump(&mut self) -> bool {
         let mut progress = false;
 
-        if !self.local_eof && self.to_remote.is_empty() {
-            match self.stream.read(self.to_remote.spare()) {
+        if !(self.local_eof && self.remote_eof)
+            && self
+                .idle_timeout
+                .is_some_and(|timeout| self.stats.connection().idle_for() >= timeout)
+        {
+            debug!("connection {}: idle, closing", self.id);
+            // Drop whatever is stuck in the buffers and go straight to
+            // sending EOF and closing the channel below.
+            self.to_remote.clear();
+            self.to_local.clear();
+            self.local_eof = true;
+            self.remote_eof = true;
+            let _ = self.stream.shutdown(Shutdown::Both);
+            self.local_shutdown = true;
+            progress = true;
+        }
+
+        self.up_throttled_until = None;
+        let up_allowed = if self.client_readable && !self.local_eof && self.to_remote.is_empty() {
+            allowance(
+                &self.throttles.up,
+                self.quota(),
+                &mut self.up_throttled_until,
+            )
+        } else {
+            None
+        };
+        if let Some(allowed) = up_allowed {
+            match self.stream.read(&mut self.to_remote.spare()[..allowed]) {
                 Ok(0) => {
                     trace!("connection {}: client sent EOF", self.id);
                     self.local_eof = true;
                     progress = true;
                 }
                 Ok(n) => {
+                    self.throttles.up.consume(n);
                     self.to_remote.filled(n);
                     progress = true;
                 }
                 Err(ref e) if e.kind() == ErrorKind::Interrupted => {
                     progress = true;
                 }
-                Err(ref e) if would_block(e) => {}
+                Err(ref e) if would_block(e) => self.client_readable = false,
                 Err(e) => {
                     self.fail("client read", &e);
                     return true;
@@ -199,7 +400,7 @@ impl Connection {
             }
         }
 
-        if !self.to_remote.is_empty() {
+        if !self.write_blocked && !self.to_remote.is_empty() {
             match self.channel.write(self.to_remote.pending()) {
                 Ok(0) => {
                     self.fail("channel write", &std::io::Error::from(ErrorKind::WriteZero));
@@ -207,12 +408,13 @@ impl Connection {
                 }
                 Ok(n) => {
                     self.to_remote.consume(n);
+                    self.stats.record_up(n);
                     progress = true;
                 }
                 Err(ref e) if e.kind() == ErrorKind::Interrupted => {
                     progress = true;
                 }
-                Err(ref e) if would_block(e) => {}
+                Err(ref e) if would_block(e) => self.write_blocked = true,
                 Err(e) => {
                     self.fail("channel write", &e);
                     return true;
@@ -234,8 +436,18 @@ impl Connection {
             }
         }
 
-        if !self.remote_eof && self.to_local.is_empty() {
-            match self.channel.read(self.to_local.spare()) {
+        self.down_throttled_until = None;
+        let down_allowed = if !self.read_blocked && !self.remote_eof && self.to_local.is_empty() {
+            allowance(
+                &self.throttles.down,
+                self.quota(),
+                &mut self.down_throttled_until,
+            )
+        } else {
+            None
+        };
+        if let Some(allowed) = down_allowed {
+            match self.channel.read(&mut self.to_local.spare()[..allowed]) {
                 // A zero-byte libssh2 read can mean no payload arrived, so
                 // confirm the remote sent EOF before half-closing the client.
                 Ok(0) => {
@@ -246,13 +458,16 @@ impl Connection {
                     }
                 }
                 Ok(n) => {
+                    self.throttles.down.consume(n);
                     self.to_local.filled(n);
                     progress = true;
                 }
                 Err(ref e) if e.kind() == ErrorKind::Interrupted => {
                     progress = true;
                 }
-                Err(ref e) if would_block(e) => {}
+                // libssh2 drained the SSH socket before giving up, so nothing
+                // more arrives for this channel without a new event.
+                Err(ref e) if would_block(e) => self.read_blocked = true,
                 Err(e) => {
                     self.fail("channel read", &e);
                     return true;
@@ -260,7 +475,7 @@ impl Connection {
             }
         }
 
-        if !self.to_local.is_empty() {
+        if self.client_writable && !self.to_local.is_empty() {
             match self.stream.write(self.to_local.pending()) {
                 Ok(0) => {
                     self.fail("client write", &std::io::Error::from(ErrorKind::WriteZero));
@@ -268,12 +483,13 @@ impl Connection {
                 }
                 Ok(n) => {
                     self.to_local.consume(n);
+                    self.stats.record_down(n);
                     progress = true;
                 }
                 Err(ref e) if e.kind() == ErrorKind::Interrupted => {
                     progress = true;
                 }
-                Err(ref e) if would_block(e) => {}
+                Err(ref e) if would_block(e) => self.client_writable = false,
                 Err(e) => {
                     self.fail("client write", &e);
                     return true;
@@ -312,155 +528,1089 @@ impl Connection {
         progress
     }
 
-    /// Poll flags this connection currently cares about on its client socket.
-    fn poll_events(&self) -> libc::c_short {
-        let mut events = 0;
-        if !self.local_eof && self.to_remote.is_empty() {
-            events |= libc::POLLIN;
+    /// How many bytes [`Connection::pump`] reads per direction per pass.
+    const fn quota(&self) -> usize {
+        match self.priority {
+            Priority::High => HIGH_PRIORITY_QUOTA,
+            Priority::Normal => NORMAL_PRIORITY_QUOTA,
+            Priority::Low => LOW_PRIORITY_QUOTA,
         }
-        if !self.to_local.is_empty() {
-            events |= libc::POLLOUT;
+    }
+
+    /// Note an event on the client socket.
+    fn ready(&mut self, event: &mio::event::Event) {
+        if event.is_readable() || event.is_read_closed() || event.is_error() {
+            self.client_readable = true;
+        }
+        if event.is_writable() || event.is_write_closed() || event.is_error() {
+            self.client_writable = true;
         }
-        events
+    }
+
+    /// The next time this connection needs a pass without any event: when a
+    /// rate limit lifts, the idle timeout fires or closing gives up.
+    fn deadline(&self) -> Option<Instant> {
+        let idle = self
+            .idle_timeout
+            .filter(|_| !(self.local_eof && self.remote_eof))
+            .map(|timeout| {
+                Instant::now() + timeout.saturating_sub(self.stats.connection().idle_for())
+            });
+        let closing = self.closing_since.map(|since| since + CLOSE_TIMEOUT);
+        [
+            self.up_throttled_until,
+            self.down_throttled_until,
+            idle,
+            closing,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
     }
 }
 
-/// Accept loop + pump loop for the whole tunnel. Runs on the calling thread
-/// until `should_exit` is set.
-fn run_tunnel(
+/// How many of `quota` bytes `throttle` lets a connection read now, or `None`
+/// after noting in `throttled_until` when that will change.
+fn allowance(
+    throttle: &Throttle,
+    quota: usize,
+    throttled_until: &mut Option<Instant>,
+) -> Option<usize> {
+    match throttle.allowance(quota) {
+        Ok(allowed) => Some(allowed),
+        Err(wait) => {
+            *throttled_until = Some(Instant::now() + wait);
+            None
+        }
+    }
+}
+
+/// What a local forward listens on. A Unix socket's file is removed when the
+/// listener is dropped.
+enum Listener {
+    Tcp(TcpListener),
+    Unix {
+        listener: UnixListener,
+        /// `None` for a socket systemd passed in, whose file is its own.
+        _file: Option<SocketFile>,
+    },
+}
+
+impl Listener {
+    /// Accept one client, named by its address or, for Unix-socket clients,
+    /// which have none, by the socket path.
+    fn accept(&self, spec: &ForwardSpec) -> std::io::Result<(Client, String)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept()?;
+                Ok((Client::Tcp(stream), peer.to_string()))
+            }
+            Self::Unix { listener, .. } => {
+                let (stream, _) = listener.accept()?;
+                Ok((Client::Unix(stream), spec.listen.to_string()))
+            }
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(listener) => listener.as_raw_fd(),
+            Self::Unix { listener, .. } => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// One local (`-L`) forward: where we listen and where its connections go.
+/// Each event loop has a clone; they share the listener and the rate-limit
+/// buckets.
+#[derive(Clone)]
+struct Forward {
+    spec: ForwardSpec,
+    /// `None` once the tunnel is draining.
+    listener: Option<Arc<Listener>>,
+    /// The listener may have clients waiting: set by an event, cleared once
+    /// accepting would block.
+    acceptable: bool,
+    /// The `--rate-limit` buckets shared by the forward's connections.
+    throttles: Throttles,
+    priority: Priority,
+}
+
+impl Forward {
+    /// Take the socket systemd passed in for `spec`, or bind one.
+    fn bind(
+        spec: &ForwardSpec,
+        args: &Arguments,
+        limits: &Limits,
+        activated: &mut Activated,
+    ) -> std::io::Result<Self> {
+        let listener = match (activated.take(&spec.listen), &spec.listen) {
+            (Some(Inherited::Tcp(listener)), _) => Listener::Tcp(listener),
+            (Some(Inherited::Unix(listener)), _) => Listener::Unix {
+                listener,
+                _file: None,
+            },
+            (None, Endpoint::Tcp(listen)) => {
+                let listener = TcpListener::bind((listen.host.as_str(), listen.port))?;
+                listener.set_nonblocking(true)?;
+                Listener::Tcp(listener)
+            }
+            (None, Endpoint::Unix(path)) => {
+                let (listener, file) = unix_socket::bind(path, args.socket_mode)?;
+                listener.set_nonblocking(true)?;
+                Listener::Unix {
+                    listener,
+                    _file: Some(file),
+                }
+            }
+        };
+        info!("forwarding {} -> {} over ssh", spec.listen, spec.target);
+        Ok(Self {
+            spec: spec.clone(),
+            listener: Some(Arc::new(listener)),
+            acceptable: true,
+            throttles: Throttles::default().with(limits.rate_limit),
+            priority: args.priority(spec),
+        })
+    }
+}
+
+/// An accepted client waiting for its channel to open.
+struct PendingOpen {
+    /// Index into the tunnel's forwards.
+    forward: usize,
+    share: Share,
+    /// Handed out at accept, so the connections are in token order.
+    token: Token,
+    stream: Client,
+    peer: String,
+    /// See [`Client::addrs`]; the originator of the `direct-tcpip` request.
+    addrs: Option<(SocketAddr, SocketAddr)>,
+    queued_at: Instant,
+    /// With the exec fallback: the `session` channel, once open, while the
+    /// connect command is being started on it.
+    channel: Option<Channel>,
+    /// Whether that channel's stderr is ignored yet.
+    stderr_ignored: bool,
+}
+
+impl Waiting for PendingOpen {
+    fn token(&self) -> Token {
+        self.token
+    }
+
+    fn forward(&self) -> usize {
+        self.forward
+    }
+
+    fn queued_at(&self) -> Instant {
+        self.queued_at
+    }
+
+    fn holds_channel(&self) -> bool {
+        self.channel.is_some()
+    }
+}
+
+/// A channel ready for a client: opened for it, or pre-opened with whatever
+/// the target has sent since.
+struct Opened {
+    channel: Channel,
+    via: &'static str,
+    early: Buffer,
+}
+
+/// A pre-opened channel waiting for a client (`--preopen-channels`).
+struct Idle {
+    channel: Channel,
+    /// What the target sent meanwhile. Read only while empty, so at most one
+    /// buffer's worth; the rest waits in the SSH window.
+    early: Buffer,
+    /// A channel read would block until the SSH socket has an event.
+    read_blocked: bool,
+    /// Set once the target has hung up; we then retry `close()`.
+    closing_since: Option<Instant>,
+    /// Ready to be reaped by the event loop.
+    finished: bool,
+}
+
+impl Idle {
+    fn new(channel: Channel) -> Self {
+        Self {
+            channel,
+            early: Buffer::new(),
+            read_blocked: false,
+            closing_since: None,
+            finished: false,
+        }
+    }
+
+    /// Can a client still have this channel?
+    fn is_open(&self) -> bool {
+        self.closing_since.is_none() && !self.finished && !self.channel.eof()
+    }
+
+    /// Read what the target sends without blocking, and close the channel
+    /// once the target hangs up. Returns `true` if anything happened.
+    fn watch(&mut self) -> bool {
+        if self.closing_since.is_none() {
+            if self.read_blocked || !self.early.is_empty() {
+                return false;
+            }
+            match self.channel.read(self.early.spare()) {
+                Ok(0) if self.channel.eof() => {
+                    debug!("pre-opened channel closed while idle");
+                    self.closing_since = Some(Instant::now());
+                }
+                Ok(0) => return false,
+                Ok(n) => {
+                    self.early.filled(n);
+                    return true;
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => return true,
+                Err(ref e) if would_block(e) => {
+                    self.read_blocked = true;
+                    return false;
+                }
+                Err(e) => {
+                    debug!("pre-opened channel failed: {}", e);
+                    self.finished = true;
+                    return true;
+                }
+            }
+        }
+        let started = self.closing_since.expect("set above");
+        match self.channel.close() {
+            Err(ref e) if ssh_would_block(e) && started.elapsed() <= CLOSE_TIMEOUT => false,
+            _ => {
+                self.finished = true;
+                true
+            }
+        }
+    }
+}
+
+impl From<Idle> for Opened {
+    fn from(idle: Idle) -> Self {
+        Self {
+            channel: idle.channel,
+            via: "direct-tcpip",
+            early: idle.early,
+        }
+    }
+}
+
+/// Turn `open` into a connection over `opened`, watching its client socket.
+fn start_connection(
+    open: PendingOpen,
+    opened: Opened,
+    forward: &Forward,
+    stats: &Arc<Stats>,
+    args: &Arguments,
+    limits: &Limits,
+    registry: &Registry,
+) -> Option<Connection> {
+    if let Err(e) = open.stream.configure() {
+        error!("failed to set client socket non-blocking: {}", e);
+        return None;
+    }
+    let guard = stats.open_connection(
+        forward.spec.to_string(),
+        open.peer.clone(),
+        opened.via,
+        open.queued_at.elapsed(),
+    );
+    debug!("connection {}: channel open via {}", guard.id(), opened.via);
+    let header = args
+        .proxy_protocol
+        .map(|proxy| proxy.header(open.addrs))
+        .unwrap_or_default();
+    let connection = Connection::new(open, guard, opened, &header, forward, limits);
+    // The registration ends when the socket is closed.
+    match registry.register(
+        &mut SourceFd(&connection.stream.as_raw_fd()),
+        connection.token,
+        Interest::READABLE | Interest::WRITABLE,
+    ) {
+        Ok(()) => Some(connection),
+        Err(e) => {
+            error!("failed to register client socket: {}", e);
+            None
+        }
+    }
+}
+
+/// Add `connection` to `connections`, which stay sorted by token.
+fn insert_connection(connections: &mut Vec<Connection>, connection: Connection) {
+    let at = connections.partition_point(|c| c.token < connection.token);
+    connections.insert(at, connection);
+}
+
+/// libssh2 only tells why a channel open failed in the error message.
+fn is_prohibited(err: &ssh2::Error) -> bool {
+    err.message().ends_with("(administratively prohibited)")
+}
+
+/// Advance the exec fallback for `open`: open its `session` channel, ignore
+/// its stderr, then run the connect command on it. Like
+/// `channel_direct_tcpip`, each step may report `WouldBlock`; the next pass
+/// then retries that step alone, as the ones before it are done.
+fn open_exec_channel(
     session: &Session,
+    open: &mut PendingOpen,
+    target: &Endpoint,
+) -> Result<Channel, ssh2::Error> {
+    if open.channel.is_none() {
+        open.channel = Some(session.channel_session()?);
+    }
+    let channel = open.channel.as_mut().expect("opened above");
+    if !open.stderr_ignored {
+        // Nothing reads the command's stderr; unread, it would stall the
+        // channel.
+        channel.handle_extended_data(ExtendedData::Ignore)?;
+        open.stderr_ignored = true;
+    }
+    channel.exec(&exec_fallback::connect_command(target))?;
+    Ok(open.channel.take().expect("opened above"))
+}
+
+/// State shared by the event loops, one per `--sessions` session.
+struct Shared {
+    /// Set on the first SIGINT or SIGTERM, or when a loop fails; every loop
+    /// then drains and stops.
+    should_exit: AtomicBool,
+    /// One per loop, indexed like the sessions.
+    wakers: Vec<Waker>,
+    /// Connections, open or waiting for their channel, on each session.
+    loads: Vec<AtomicUsize>,
+    /// The same, on each forward.
+    forwards: Vec<AtomicUsize>,
+    /// Connections on any session.
+    connections: AtomicUsize,
+    /// Connections waiting for their channel, on any session.
+    pending: AtomicUsize,
+    /// Set when a loop could not reserve a slot; the next slot given back
+    /// wakes the loops.
+    refused: AtomicBool,
+}
+
+impl Shared {
+    fn wake_all(&self) {
+        for waker in &self.wakers {
+            let _ = waker.wake();
+        }
+    }
+
+    /// Make every loop drain and stop.
+    fn stop(&self) {
+        self.should_exit.store(true, Ordering::SeqCst);
+        self.wake_all();
+    }
+
+    /// Does `session` have no more connections than any other?
+    fn least_loaded(&self, session: usize) -> bool {
+        let load = self.loads[session].load(Ordering::Relaxed);
+        self.loads
+            .iter()
+            .all(|other| load <= other.load(Ordering::Relaxed))
+    }
+}
+
+/// A connection's place in the [`Shared`] counts, given back when dropped.
+struct Share {
+    shared: Arc<Shared>,
+    session: usize,
+    forward: usize,
+    /// Still waiting for the channel.
+    pending: bool,
+}
+
+impl Share {
+    /// Reserve a place on `session` for a connection to the forward at
+    /// `forward`, or `None` if that would exceed one of the
+    /// `--max-connections*` limits.
+    ///
+    /// Each count is checked and incremented in one step, so loops reserving
+    /// at once cannot exceed a limit between them.
+    fn reserve(
+        shared: &Arc<Shared>,
+        limits: &Limits,
+        session: usize,
+        forward: usize,
+    ) -> Option<Self> {
+        let counts = [
+            (
+                &shared.forwards[forward],
+                limits.max_connections_per_forward,
+            ),
+            (&shared.connections, limits.max_connections),
+            (&shared.pending, limits.max_pending_opens),
+        ];
+        for (taken, &(count, limit)) in counts.iter().enumerate() {
+            let incremented = count.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                limit.is_none_or(|limit| n < limit).then_some(n + 1)
+            });
+            if incremented.is_err() {
+                for (count, _) in &counts[..taken] {
+                    count.fetch_sub(1, Ordering::AcqRel);
+                }
+                shared.refused.store(true, Ordering::Release);
+                return None;
+            }
+        }
+        shared.loads[session].fetch_add(1, Ordering::Relaxed);
+        Some(Self {
+            shared: Arc::clone(shared),
+            session,
+            forward,
+            pending: true,
+        })
+    }
+
+    /// The channel is open.
+    fn opened(&mut self) {
+        if std::mem::take(&mut self.pending) {
+            self.shared.pending.fetch_sub(1, Ordering::AcqRel);
+            self.wake_refused();
+        }
+    }
+
+    /// Wake the loops if one was held back since, by a slot this gives back.
+    fn wake_refused(&self) {
+        if self.shared.refused.swap(false, Ordering::AcqRel) {
+            self.shared.wake_all();
+        }
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        if std::mem::take(&mut self.pending) {
+            self.shared.pending.fetch_sub(1, Ordering::AcqRel);
+        }
+        self.shared.loads[self.session].fetch_sub(1, Ordering::Relaxed);
+        self.shared.forwards[self.forward].fetch_sub(1, Ordering::AcqRel);
+        self.shared.connections.fetch_sub(1, Ordering::AcqRel);
+        self.wake_refused();
+    }
+}
+
+/// One session of the `--sessions` pool, and the `poll` its event loop
+/// waits on.
+struct Worker {
+    /// Index into the pool's sessions.
+    index: usize,
+    session: Session,
+    /// The session's socket, owned by libssh2.
     ssh_fd: RawFd,
-    should_exit: &AtomicBool,
-    local_port: u16,
-    remote_host: &str,
-    remote_port: u16,
+    poll: Poll,
+}
+
+/// Accept loop + pump loop for one session. Runs on the calling thread until
+/// `should_exit` is set and the open connections have drained (or
+/// `--drain-timeout` has passed); whoever sets it wakes the loop through its
+/// waker.
+///
+/// Every loop listens on every forward, but only the one whose session has
+/// the fewest connections accepts.
+fn run_tunnel(
+    worker: &mut Worker,
+    mut forwards: Vec<Forward>,
+    shared: &Arc<Shared>,
+    stats: &Arc<Stats>,
+    args: &Arguments,
 ) -> anyhow::Result<()> {
-    let listener = TcpListener::bind((LOCALHOST, local_port))?;
-    listener.set_nonblocking(true)?;
-    let listener_fd = listener.as_raw_fd();
-    info!(
-        "forwarding 127.0.0.1:{} -> {}:{} over ssh",
-        local_port, remote_host, remote_port
-    );
+    let session = &worker.session;
+    let ssh_fd = worker.ssh_fd;
+    let poll = &mut worker.poll;
+    let limits = args.limits();
+    for (index, forward) in forwards.iter().enumerate() {
+        if let Some(listener) = &forward.listener {
+            poll.registry().register(
+                &mut SourceFd(&listener.as_raw_fd()),
+                Token(FIRST_LISTENER + index),
+                Interest::READABLE,
+            )?;
+        }
+    }
+    poll.registry().register(
+        &mut SourceFd(&ssh_fd),
+        SSH,
+        Interest::READABLE | Interest::WRITABLE,
+    )?;
+    let mut events = Events::with_capacity(EVENT_CAPACITY);
+    let mut next_token = FIRST_LISTENER + forwards.len();
 
     // Every channel call must be non-blocking, otherwise a single stalled
     // connection would park the thread while holding the session mutex.
     session.set_blocking(false);
 
     let mut connections: Vec<Connection> = Vec::new();
-    // libssh2 keeps the direct-tcpip handshake in *session*-global state, so
-    // only one open may be in flight at a time; the rest queue up here and are
-    // retried, front first, with identical arguments (as libssh2 requires).
-    let mut pending: VecDeque<(TcpStream, Instant)> = VecDeque::new();
-    let mut next_id: u64 = 0;
-    let mut poll_fds: Vec<libc::pollfd> = Vec::new();
-
-    while !should_exit.load(Ordering::SeqCst) {
+    // libssh2 keeps a channel open's progress in the session, so only one may
+    // be in flight at a time; clients queue up here for theirs.
+    let mut pending: OpenQueue<PendingOpen> = OpenQueue::new(OPEN_TIMEOUT);
+    let mut next_keepalive = Instant::now();
+    // Set once the server has refused a `direct-tcpip` channel as prohibited
+    // and `--exec-fallback` is on; every later open goes straight to `exec`.
+    let mut tcp_prohibited = false;
+    // `--preopen-channels`: for each forward, the channels waiting for a
+    // client. They are opened through `pending` too, when no client waits.
+    let mut idle: Vec<VecDeque<Idle>> = forwards.iter().map(|_| VecDeque::new()).collect();
+    // No pre-opening before this, after one failed.
+    let mut preopen_after = Instant::now();
+    // When the open connections must be done by, once `should_exit` is set.
+    let mut drain_deadline: Option<Instant> = None;
+    // Which connection each priority class starts its pass with; see below.
+    let mut turn: usize = 0;
+
+    loop {
+        if drain_deadline.is_none() && shared.should_exit.load(Ordering::SeqCst) {
+            // Close the listeners; whatever was already accepted gets
+            // `--drain-timeout` to finish. The other loops may still hold
+            // them for a moment, so stop watching them explicitly.
+            for forward in &mut forwards {
+                if let Some(listener) = forward.listener.take() {
+                    let _ = poll
+                        .registry()
+                        .deregister(&mut SourceFd(&listener.as_raw_fd()));
+                }
+            }
+            for idle in &mut idle {
+                idle.clear();
+            }
+            let active = connections.len() + pending.len();
+            if active > 0 {
+                info!(
+                    "draining {} connection(s) for up to {:?}",
+                    active, args.drain_timeout
+                );
+            }
+            drain_deadline = Some(Instant::now() + args.drain_timeout);
+        }
+        if let Some(deadline) = drain_deadline {
+            let active = connections.len() + pending.len();
+            if active == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                warn!("drain timed out, {} connection(s) dropped", active);
+                break;
+            }
+        }
+
         let mut progress = false;
 
-        loop {
-            match listener.accept() {
-                Ok((stream, peer)) => {
-                    trace!("accepted connection from {}", peer);
-                    pending.push_back((stream, Instant::now()));
-                    progress = true;
+        if Instant::now() >= next_keepalive {
+            match session.keepalive_send() {
+                Ok(secs) => {
+                    next_keepalive = Instant::now() + Duration::from_secs(secs.max(1).into());
+                }
+                Err(ref e) if ssh_would_block(e) => {
+                    next_keepalive = Instant::now() + KEEPALIVE_RETRY;
                 }
-                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
-                Err(ref e) if would_block(e) => break,
                 Err(e) => {
-                    error!("accept failed: {}", e);
+                    warn!("sending keepalive failed: {}", e);
+                    stats.record_keepalive_failure();
+                    next_keepalive =
+                        Instant::now() + Duration::from_secs(KEEPALIVE_INTERVAL.into());
+                }
+            }
+        }
+
+        for (index, forward) in forwards.iter_mut().enumerate() {
+            let Some(listener) = &forward.listener else {
+                continue;
+            };
+            let mut accepted = false;
+            while forward.acceptable {
+                let least_loaded = shared.least_loaded(worker.index);
+                // Reserved before accepting, and given back if nobody comes.
+                let share = Share::reserve(shared, &limits, worker.index, index);
+                if share.is_none() && limits.on_limit == LimitAction::Backlog {
+                    // Leave new clients in the listen backlog until a slot
+                    // frees up; the listener stays marked acceptable.
                     break;
                 }
+                if share.is_some() && !least_loaded {
+                    // Every loop heard of the client; the least loaded one
+                    // takes it. If that was us until a moment ago, tell the
+                    // others there may be more.
+                    if accepted {
+                        shared.wake_all();
+                    }
+                    break;
+                }
+                match listener.accept(&forward.spec) {
+                    Ok((stream, peer)) => {
+                        let Some(share) = share else {
+                            debug!("connection limit reached, resetting {}", peer);
+                            stats.record_error(stats::ErrorKind::Limit);
+                            if let Client::Tcp(stream) = &stream {
+                                let _ = limits::reset_on_close(stream);
+                            }
+                            progress = true;
+                            continue;
+                        };
+                        trace!("accepted connection from {}", peer);
+                        let open = PendingOpen {
+                            forward: index,
+                            share,
+                            token: Token(next_token),
+                            addrs: stream.addrs(),
+                            stream,
+                            peer,
+                            queued_at: Instant::now(),
+                            channel: None,
+                            stderr_ignored: false,
+                        };
+                        if let Some(at) = idle[index].iter().position(Idle::is_open) {
+                            let preopened = idle[index].remove(at).expect("found above");
+                            if let Some(connection) = start_connection(
+                                open,
+                                preopened.into(),
+                                forward,
+                                stats,
+                                args,
+                                &limits,
+                                poll.registry(),
+                            ) {
+                                insert_connection(&mut connections, connection);
+                            }
+                        } else {
+                            pending.push(open);
+                        }
+                        next_token += 1;
+                        accepted = true;
+                        progress = true;
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(ref e) if would_block(e) => forward.acceptable = false,
+                    Err(e) => {
+                        error!("accept failed: {}", e);
+                        stats.record_error(stats::ErrorKind::Accept);
+                        break;
+                    }
+                }
             }
         }
 
-        if let Some((_, queued_at)) = pending.front() {
-            match session.channel_direct_tcpip(remote_host, remote_port, None) {
+        let queued = pending.len();
+        if pending.in_flight().is_none() {
+            if let Some(front) = pending.front() {
+                let request = if tcp_prohibited {
+                    Request::Session
+                } else {
+                    let target = forwards[front.forward]
+                        .spec
+                        .target
+                        .as_tcp()
+                        .expect("Unix targets are rejected in main");
+                    let (originator, originator_port) =
+                        forward::originator(front.addrs.map(|(peer, _)| peer));
+                    Request::DirectTcpip {
+                        host: target.host.clone(),
+                        port: target.port,
+                        originator,
+                        originator_port,
+                    }
+                };
+                pending.start_front(request, Instant::now());
+            } else if drain_deadline.is_none() && !tcp_prohibited && Instant::now() >= preopen_after
+            {
+                // Clients come first: a channel is only pre-opened while none
+                // is waiting for one.
+                let short = idle.iter().position(|idle| {
+                    idle.iter().filter(|c| c.is_open()).count() < args.preopen_channels
+                });
+                if let Some(index) = short {
+                    let target = forwards[index]
+                        .spec
+                        .target
+                        .as_tcp()
+                        .expect("Unix targets are rejected in main");
+                    // There is no client yet, so the server is told the
+                    // placeholder originator.
+                    let (originator, originator_port) = forward::originator(None);
+                    let request = Request::DirectTcpip {
+                        host: target.host.clone(),
+                        port: target.port,
+                        originator,
+                        originator_port,
+                    };
+                    pending.start_preopen(index, request, Instant::now());
+                }
+            }
+        }
+        if let Some(in_flight) = pending.in_flight() {
+            let (request, owner) = (in_flight.request.clone(), in_flight.owner);
+            let via = match request {
+                Request::DirectTcpip { .. } => "direct-tcpip",
+                Request::Session => VIA_EXEC,
+            };
+            let opened = match (&request, owner, pending.front_mut()) {
+                (
+                    Request::DirectTcpip {
+                        host,
+                        port,
+                        originator,
+                        originator_port,
+                    },
+                    _,
+                    _,
+                ) => {
+                    session.channel_direct_tcpip(host, *port, Some((originator, *originator_port)))
+                }
+                (Request::Session, Owner::Client(_), Some(front)) => {
+                    let target = &forwards[front.forward].spec.target;
+                    open_exec_channel(session, front, target)
+                }
+                // Its client timed out before the channel opened.
+                (Request::Session, _, _) => session.channel_session(),
+            };
+            match opened {
                 Ok(channel) => {
-                    let (stream, _) = pending.pop_front().expect("front exists");
-                    if let Err(e) = stream.set_nonblocking(true) {
-                        error!("failed to set client socket non-blocking: {}", e);
-                    } else {
-                        let _ = stream.set_nodelay(true);
-                        let id = next_id;
-                        next_id += 1;
-                        debug!("connection {}: channel open", id);
-                        connections.push(Connection::new(id, stream, channel));
+                    match pending.finish() {
+                        Finished::Client(open) => {
+                            let forward = &forwards[open.forward];
+                            let opened = Opened {
+                                channel,
+                                via,
+                                early: Buffer::new(),
+                            };
+                            if let Some(connection) = start_connection(
+                                open,
+                                opened,
+                                forward,
+                                stats,
+                                args,
+                                &limits,
+                                poll.registry(),
+                            ) {
+                                insert_connection(&mut connections, connection);
+                            }
+                        }
+                        Finished::Preopen(index) => {
+                            let preopened = Idle::new(channel);
+                            // A client that queued meanwhile waited for this
+                            // open; if it is for the same forward, it takes
+                            // the channel instead of opening another.
+                            if let Some(open) = pending.take_for(index) {
+                                if let Some(connection) = start_connection(
+                                    open,
+                                    preopened.into(),
+                                    &forwards[index],
+                                    stats,
+                                    args,
+                                    &limits,
+                                    poll.registry(),
+                                ) {
+                                    insert_connection(&mut connections, connection);
+                                }
+                            } else if drain_deadline.is_none() {
+                                idle[index].push_back(preopened);
+                            }
+                        }
+                        Finished::Abandoned => {
+                            debug!("closing a channel whose client timed out waiting for it");
+                        }
                     }
                     progress = true;
                 }
-                Err(ref e) if ssh_would_block(e) => {
-                    if queued_at.elapsed() > OPEN_TIMEOUT {
-                        warn!("timed out opening channel for a pending connection");
-                        pending.pop_front();
+                Err(ref e) if ssh_would_block(e) => match pending.expire(Instant::now()) {
+                    Some(Expired::Client(open)) => {
+                        warn!("timed out opening a channel for {}, closing it", open.peer);
+                        stats.record_error(stats::ErrorKind::ChannelOpenTimeout);
                         progress = true;
                     }
-                }
+                    Some(Expired::Preopen(index)) => {
+                        warn!(
+                            "pre-opening a channel to {} is taking over {:?}, other opens wait \
+                             for it",
+                            forwards[index].spec.target, OPEN_TIMEOUT
+                        );
+                        stats.record_error(stats::ErrorKind::ChannelOpenTimeout);
+                    }
+                    None => {}
+                },
                 Err(e) => {
-                    error!("failed to open direct-tcpip channel: {}", e);
-                    pending.pop_front();
+                    match pending.finish() {
+                        Finished::Client(open)
+                            if via != VIA_EXEC && args.exec_fallback && is_prohibited(&e) =>
+                        {
+                            // The client goes back to the front and is retried
+                            // via exec.
+                            warn!(
+                                "direct-tcpip is prohibited by the server, using exec from now on"
+                            );
+                            tcp_prohibited = true;
+                            pending.push_front(open);
+                        }
+                        Finished::Client(_) => {
+                            if is_prohibited(&e) && !args.exec_fallback {
+                                error!(
+                                    "failed to open {} channel: {} (see --exec-fallback)",
+                                    via, e
+                                );
+                            } else {
+                                error!("failed to open {} channel: {}", via, e);
+                            }
+                            stats.record_error(stats::ErrorKind::ChannelOpen);
+                        }
+                        Finished::Preopen(index) => {
+                            warn!(
+                                "pre-opening direct-tcpip channel to {}: {}",
+                                forwards[index].spec.target, e
+                            );
+                            stats.record_error(stats::ErrorKind::ChannelOpen);
+                            if args.exec_fallback && is_prohibited(&e) {
+                                warn!(
+                                    "direct-tcpip is prohibited by the server, using exec from \
+                                     now on"
+                                );
+                                tcp_prohibited = true;
+                            }
+                            preopen_after = Instant::now() + REFILL_BACKOFF;
+                        }
+                        Finished::Abandoned => {
+                            debug!("a channel open whose client timed out failed: {}", e);
+                        }
+                    }
                     progress = true;
                 }
             }
         }
+        for open in pending.expire_waiting(Instant::now()) {
+            warn!(
+                "timed out waiting to open a channel for {}, closing it",
+                open.peer
+            );
+            stats.record_error(stats::ErrorKind::ChannelOpenTimeout);
+            progress = true;
+        }
 
-        for connection in &mut connections {
-            if connection.pump() {
+        // Each connection reads at most its class's quota per pass, so while
+        // they all have data the session is shared out by those quotas and a
+        // bulk transfer cannot crowd out the rest; higher classes also go
+        // first, and within a class the starting connection rotates, so no
+        // one connection always gets first claim on the session's send
+        // buffer and on the data libssh2 has already read.
+        let count = connections.len();
+        for priority in Priority::ALL {
+            for offset in 0..count {
+                let connection = &mut connections[(turn + offset) % count];
+                if connection.priority == priority && connection.pump() {
+                    progress = true;
+                }
+            }
+        }
+        turn = turn.wrapping_add(1);
+        for channel in idle.iter_mut().flatten() {
+            if channel.watch() {
                 progress = true;
             }
         }
+        for idle in &mut idle {
+            idle.retain(|channel| !channel.finished);
+        }
+        let open = connections.len();
         connections.retain(|c| {
             if c.finished {
                 debug!("connection {}: closed", c.id);
             }
             !c.finished
         });
-
-        if progress {
-            // Something moved, so libssh2 may still hold buffered data: do
-            // another pass instead of parking in poll().
-            continue;
-        }
-
-        poll_fds.clear();
-        poll_fds.push(libc::pollfd {
-            fd: listener_fd,
-            events: libc::POLLIN,
-            revents: 0,
-        });
-        poll_fds.push(libc::pollfd {
-            fd: ssh_fd,
-            events: ssh_poll_events(session),
-            revents: 0,
-        });
-        for connection in &connections {
-            let events = connection.poll_events();
-            if events != 0 {
-                poll_fds.push(libc::pollfd {
-                    fd: connection.stream.as_raw_fd(),
-                    events,
-                    revents: 0,
-                });
-            }
+        if (connections.len() < open || pending.len() < queued) && shared.wakers.len() > 1 {
+            // A connection or pending open slot freed up: another loop may be
+            // holding clients back.
+            shared.wake_all();
         }
 
-        let rc = unsafe {
-            libc::poll(
-                poll_fds.as_mut_ptr(),
-                poll_fds.len() as libc::nfds_t,
-                POLL_TIMEOUT_MS,
-            )
+        // Something moved, so libssh2 may still hold buffered data: only
+        // collect the events that came in meanwhile, then do another pass.
+        let timeout = if progress {
+            Some(Duration::ZERO)
+        } else {
+            let now = Instant::now();
+            connections
+                .iter()
+                .filter_map(Connection::deadline)
+                .chain(pending.deadline())
+                .chain(
+                    idle.iter()
+                        .flatten()
+                        .filter_map(|channel| channel.closing_since)
+                        .map(|since| since + CLOSE_TIMEOUT),
+                )
+                .chain(Some(preopen_after).filter(|after| *after > now))
+                .chain(drain_deadline)
+                .chain([next_keepalive])
+                .min()
+                .map(|deadline| deadline.saturating_duration_since(now))
         };
-        if rc < 0 {
-            let err = std::io::Error::last_os_error();
+        if let Err(err) = poll.poll(&mut events, timeout) {
             if err.kind() == ErrorKind::Interrupted {
                 continue;
             }
             return Err(err.into());
         }
+        for event in &events {
+            match event.token() {
+                // `should_exit` is checked at the top of the loop.
+                WAKER => {}
+                // Anything libssh2 was waiting for may have arrived (or
+                // left), on any channel.
+                SSH => {
+                    for connection in &mut connections {
+                        connection.read_blocked = false;
+                        connection.write_blocked = false;
+                    }
+                    for channel in idle.iter_mut().flatten() {
+                        channel.read_blocked = false;
+                    }
+                }
+                Token(token) if token < FIRST_LISTENER + forwards.len() => {
+                    forwards[token - FIRST_LISTENER].acceptable = true;
+                }
+                token => {
+                    if let Ok(index) = connections.binary_search_by_key(&token, |c| c.token) {
+                        connections[index].ready(event);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Connect and authenticate one session, in blocking mode. Returns it with
+/// its socket, which the session owns but which stays valid for the
+/// session's lifetime.
+fn connect(args: &Arguments) -> anyhow::Result<(Session, RawFd)> {
+    let tcp = TcpStream::connect(SocketAddr::new(IpAddr::V4(args.ip), 22))?;
+    let ssh_fd = tcp.as_raw_fd();
+
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    let transport = args.transport();
+    prefer_methods(&session, &transport)?;
+    session.set_compress(transport.compression);
+    // Handshake and auth run in blocking mode; the tunnel switches the session
+    // to non-blocking before pumping any data.
+    session.handshake()?;
+    log_methods(&session);
+    session.userauth_pubkey_file(
+        &args.user,
+        None,
+        &expand_home_dir(&args.private_key_path).map_err(|e| anyhow!(e))?,
+        None,
+    )?;
+    if !session.authenticated() {
+        return Err(anyhow!("failed to authenticate with public key"));
+    }
+    session.set_keepalive(true, KEEPALIVE_INTERVAL);
+    Ok((session, ssh_fd))
+}
+
+/// Apply `--kex-algorithms`, `--host-key-algorithms`, `--ciphers` and
+/// `--macs`; libssh2 takes the latter two once per direction.
+///
+/// ## Errors
+/// if libssh2 supports none of the algorithms in a list
+fn prefer_methods(session: &Session, transport: &Transport) -> anyhow::Result<()> {
+    for (option, names, method_types) in [
+        (
+            "--kex-algorithms",
+            &transport.kex_algorithms,
+            &[MethodType::Kex][..],
+        ),
+        (
+            "--host-key-algorithms",
+            &transport.host_key_algorithms,
+            &[MethodType::HostKey],
+        ),
+        (
+            "--ciphers",
+            &transport.ciphers,
+            &[MethodType::CryptCs, MethodType::CryptSc],
+        ),
+        (
+            "--macs",
+            &transport.macs,
+            &[MethodType::MacCs, MethodType::MacSc],
+        ),
+    ] {
+        if names.is_empty() {
+            continue;
+        }
+        let prefs = names.join(",");
+        for &method_type in method_types {
+            session
+                .method_pref(method_type, &prefs)
+                .map_err(|e| anyhow!("{option} {prefs}: {e}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Log what the handshake settled on, so benchmark runs record it.
+fn log_methods(session: &Session) {
+    let method = |method_type| session.methods(method_type).unwrap_or("?");
+    info!(
+        "negotiated kex {}, host key {}, ciphers {}/{}, MACs {}/{}, compression {}/{}",
+        method(MethodType::Kex),
+        method(MethodType::HostKey),
+        method(MethodType::CryptCs),
+        method(MethodType::CryptSc),
+        method(MethodType::MacCs),
+        method(MethodType::MacSc),
+        method(MethodType::CompCs),
+        method(MethodType::CompSc),
+    );
+}
+
+/// With a `--profile`, SIGHUP asks for the config file to be read again,
+/// which only the russh backend does, and `ctrlc` would take it for a request
+/// to shut down. Block it in this thread, and so in every thread started from
+/// here on, to be taken by [`ignore_hangup`] instead. Call this before any
+/// other thread is started.
+///
+/// ## Errors
+/// if the signal mask cannot be changed
+fn block_hangup() -> std::io::Result<libc::sigset_t> {
+    // SAFETY: `sigemptyset` initialises the set, which then names only SIGHUP.
+    let set = unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        set
+    };
+    // SAFETY: `set` is initialised, and the old mask is not asked for.
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) } {
+        0 => Ok(set),
+        errno => Err(std::io::Error::from_raw_os_error(errno)),
     }
+}
 
-    info!("tunnel stopped, {} connection(s) dropped", connections.len());
+/// Take the SIGHUPs [`block_hangup`] held back, and say they were ignored.
+///
+/// ## Errors
+/// if the thread cannot be started
+fn ignore_hangup(set: libc::sigset_t) -> std::io::Result<()> {
+    thread::Builder::new()
+        .name("sighup".into())
+        .spawn(move || loop {
+            let mut signal = 0;
+            // SAFETY: `set` only names SIGHUP, which every thread blocks.
+            if unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+                warn!(
+                    "SIGHUP ignored: reloading the config file is only supported by the russh \
+                     backend"
+                );
+            }
+        })?;
     Ok(())
 }
 
@@ -485,55 +1635,252 @@ fn init_tracing() {
 }
 
 fn main() -> anyhow::Result<()> {
-    init_tracing();
     let args = get_args();
 
-    let exit_signal = Arc::new(AtomicBool::new(false));
-    let ctrlc_flag = Arc::clone(&exit_signal);
+    if args.stdio.is_some() {
+        return Err(anyhow!("--stdio is only supported by the russh backend"));
+    }
+    if !args.udp_forwards.is_empty() {
+        return Err(anyhow!(
+            "UDP forwards (-U) are only supported by the russh backend"
+        ));
+    }
+    let specs = args.forwards();
+    if specs.is_empty() {
+        return Err(anyhow!(
+            "nothing to forward: pass --local-port/--remote-port or -L"
+        ));
+    }
+    if specs.iter().any(|spec| spec.direction == Direction::Remote) {
+        return Err(anyhow!(
+            "remote forwards (-R) are only supported by the russh backend"
+        ));
+    }
+    if specs.iter().any(|spec| spec.target.as_tcp().is_none()) {
+        return Err(anyhow!(
+            "Unix socket targets are only supported by the russh backend"
+        ));
+    }
+    if args.control_path.is_some() {
+        return Err(anyhow!(
+            "the control socket is only supported by the russh backend"
+        ));
+    }
+    if let Some(option) = args.transport().russh_only() {
+        return Err(anyhow!("{option} is only supported by the russh backend"));
+    }
+    if args.lazy || args.idle_disconnect.is_some() {
+        return Err(anyhow!("--lazy is only supported by the russh backend"));
+    }
+    if let Some(unmatched) = args
+        .priorities
+        .iter()
+        .find(|priority| !specs.iter().any(|spec| priority.matches(spec)))
+    {
+        return Err(anyhow!(
+            "--priority {}: no forward listens there",
+            unmatched.listen
+        ));
+    }
+
+    // Before the metrics and signal threads, and any the tracing setup starts;
+    // systemd addresses its sockets to the process it started, not a forked
+    // one.
+    let hangup = args.profile.is_some().then(block_hangup).transpose()?;
+    let mut activated = Activated::from_env(&specs)?;
+    let mut readiness = Readiness::start(&args)?;
+    init_tracing();
+    if let Some(set) = hangup {
+        ignore_hangup(set)?;
+    }
+
+    let stats = Stats::new();
+    if let Some(addr) = args.metrics_listen {
+        metrics::spawn(addr, Arc::clone(&stats))?;
+    }
+
+    let sessions = args.sessions.get();
+    let polls = (0..sessions)
+        .map(|_| Poll::new())
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let shared = Arc::new(Shared {
+        should_exit: AtomicBool::new(false),
+        wakers: polls
+            .iter()
+            .map(|poll| Waker::new(poll.registry(), WAKER))
+            .collect::<std::io::Result<_>>()?,
+        loads: (0..sessions).map(|_| AtomicUsize::new(0)).collect(),
+        forwards: specs.iter().map(|_| AtomicUsize::new(0)).collect(),
+        connections: AtomicUsize::new(0),
+        pending: AtomicUsize::new(0),
+        refused: AtomicBool::new(false),
+    });
+    let signalled = Arc::clone(&shared);
+    // With the `termination` feature this also runs on SIGTERM (and SIGHUP,
+    // unless blocked above).
     ctrlc::set_handler(move || {
-        info!("received Ctrl-C, shutting down");
-        ctrlc_flag.store(true, Ordering::SeqCst);
+        if signalled.should_exit.swap(true, Ordering::SeqCst) {
+            warn!("signal received while draining, exiting now");
+            daemon::remove_pidfile();
+            std::process::exit(shutdown::FORCED_EXIT_STATUS);
+        }
+        info!("signal received, shutting down");
+        daemon::notify_systemd("STOPPING=1");
+        signalled.wake_all();
     })
     .expect("Error setting Ctrl-C handler");
 
-    let tcp = TcpStream::connect(SocketAddr::new(IpAddr::V4(args.ip), 22))?;
-    // The session owns the socket from here on, but the descriptor stays valid
-    // for the lifetime of the session and is what we poll for readiness.
-    let ssh_fd = tcp.as_raw_fd();
-
-    let mut session = Session::new()?;
-    session.set_tcp_stream(tcp);
-    // Handshake and auth run in blocking mode; the tunnel switches the session
-    // to non-blocking before pumping any data.
-    session.handshake()?;
-    session.userauth_pubkey_file(
-        &args.user,
-        None,
-        &expand_home_dir(&args.private_key_path).map_err(|e| anyhow!(e))?,
-        None,
-    )?;
-    if !session.authenticated() {
-        return Err(anyhow!("failed to authenticate with public key"));
+    let mut workers = Vec::with_capacity(sessions);
+    for (index, poll) in polls.into_iter().enumerate() {
+        let (session, ssh_fd) = connect(&args)?;
+        workers.push(Worker {
+            index,
+            session,
+            ssh_fd,
+            poll,
+        });
     }
     info!("authenticated as {}", args.user);
-    session.set_keepalive(true, 30);
-
-    run_tunnel(
-        &session,
-        ssh_fd,
-        &exit_signal,
-        args.local_port,
-        // The literal address, not "localhost": sshd resolves the forward
-        // target itself, and "localhost" yields ::1 first on hosts with an
-        // IPv6 loopback, leaving IPv4-only targets reachable only via sshd's
-        // fallback from the refused ::1 attempt. Naming the address we mean
-        // removes that dependency (hardening; sshd's fallback does work).
-        "127.0.0.1",
-        args.remote_port,
-    )?;
+    if sessions > 1 {
+        info!("opened {} sessions", sessions);
+    }
 
-    session.set_blocking(true);
-    let _ = session.disconnect(None, "tunnel closed", None);
+    let limits = args.limits();
+    let forwards = specs
+        .iter()
+        .map(|spec| Forward::bind(spec, &args, &limits, &mut activated))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    if let Some(idle) = args.exit_idle_time {
+        let shared = Arc::clone(&shared);
+        activation::exit_when_idle(Arc::clone(&stats), idle, move || shared.stop())?;
+    }
+    readiness.ready()?;
+    let results = thread::scope(|scope| {
+        let loops = workers
+            .into_iter()
+            .map(|mut worker| {
+                let forwards = forwards.clone();
+                let (shared, stats, args) = (&shared, &stats, &args);
+                scope.spawn(move || {
+                    let result = run_tunnel(&mut worker, forwards, shared, stats, args);
+                    if result.is_err() {
+                        // Without this session the tunnel is incomplete.
+                        shared.stop();
+                    }
+                    worker.session.set_blocking(true);
+                    let _ = worker.session.disconnect(
+                        Some(DisconnectCode::ByApplication),
+                        "shutting down",
+                        None,
+                    );
+                    result
+                })
+            })
+            .collect::<Vec<_>>();
+        // The loops hold the only other references to the listeners, so they
+        // close once every loop has let go of them.
+        drop(forwards);
+        loops
+            .into_iter()
+            .map(|handle| handle.join().expect("event loop panicked"))
+            .collect::<Vec<_>>()
+    });
+    results.into_iter().collect::<anyhow::Result<()>>()?;
+    stats.log_summary();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+
+    use super::*;
+
+    fn shared(sessions: usize, forwards: usize) -> (Vec<Poll>, Arc<Shared>) {
+        let polls = (0..sessions)
+            .map(|_| Poll::new().unwrap())
+            .collect::<Vec<_>>();
+        let shared = Arc::new(Shared {
+            should_exit: AtomicBool::new(false),
+            wakers: polls
+                .iter()
+                .map(|poll| Waker::new(poll.registry(), WAKER).unwrap())
+                .collect(),
+            loads: (0..sessions).map(|_| AtomicUsize::new(0)).collect(),
+            forwards: (0..forwards).map(|_| AtomicUsize::new(0)).collect(),
+            connections: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+            refused: AtomicBool::new(false),
+        });
+        (polls, shared)
+    }
+
+    #[test]
+    fn loops_reserving_at_once_keep_to_the_limits() {
+        const SESSIONS: usize = 8;
+        let (_polls, shared) = shared(SESSIONS, 2);
+        let limits = Limits {
+            max_connections: Some(5),
+            max_pending_opens: Some(3),
+            ..Limits::default()
+        };
+        let barrier = Barrier::new(SESSIONS);
+        let reserved = thread::scope(|scope| {
+            let loops = (0..SESSIONS)
+                .map(|session| {
+                    let (shared, limits, barrier) = (&shared, &limits, &barrier);
+                    scope.spawn(move || {
+                        barrier.wait();
+                        let mut shares = Vec::new();
+                        for _ in 0..4 {
+                            if let Some(mut share) =
+                                Share::reserve(shared, limits, session, session % 2)
+                            {
+                                share.opened();
+                                shares.push(share);
+                            }
+                        }
+                        shares
+                    })
+                })
+                .collect::<Vec<_>>();
+            loops
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(reserved.len(), 5);
+        assert_eq!(shared.connections.load(Ordering::SeqCst), 5);
+        assert_eq!(shared.pending.load(Ordering::SeqCst), 0);
+
+        drop(reserved);
+        assert_eq!(shared.connections.load(Ordering::SeqCst), 0);
+        assert!(shared
+            .forwards
+            .iter()
+            .all(|n| n.load(Ordering::SeqCst) == 0));
+        assert!(shared.loads.iter().all(|n| n.load(Ordering::SeqCst) == 0));
+    }
+
+    #[test]
+    fn a_refused_reservation_takes_nothing() {
+        let (_polls, shared) = shared(1, 1);
+        let limits = Limits {
+            max_connections: Some(2),
+            max_pending_opens: Some(1),
+            ..Limits::default()
+        };
+        let mut first = Share::reserve(&shared, &limits, 0, 0).unwrap();
+        // Refused on the pending count, after taking the others.
+        assert!(Share::reserve(&shared, &limits, 0, 0).is_none());
+        assert_eq!(shared.connections.load(Ordering::SeqCst), 1);
+        assert_eq!(shared.forwards[0].load(Ordering::SeqCst), 1);
+        assert!(shared.refused.load(Ordering::SeqCst));
+
+        // Giving a slot back clears the refusal, having woken the loops.
+        first.opened();
+        assert!(!shared.refused.load(Ordering::SeqCst));
+        assert!(Share::reserve(&shared, &limits, 0, 0).is_some());
+    }
+}
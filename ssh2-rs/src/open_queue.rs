@@ -0,0 +1,453 @@
+//! The clients waiting for a channel, and the one channel open libssh2 has
+//! in flight.
+//!
+//! libssh2 keeps the progress of a channel open (`direct-tcpip` or `session`)
+//! in the session, not in the channel. Only one open can be in flight per
+//! session, and a call made while one is continues *that* open, whatever
+//! arguments it is given. So:
+//!
+//! * an open is started only when none is in flight, for the client at the
+//!   front of the queue or to pre-open a channel, and is continued with the
+//!   same [`Request`] until it completes;
+//! * its channel goes to whoever it was started for and to nobody else;
+//! * a client waits at most `timeout` for its channel, counted from when it
+//!   was queued. One that gives up while its open is in flight is dropped,
+//!   but the open stays in flight, [`Owner::Abandoned`], and its channel is
+//!   closed once it opens. Clients behind it time out all the same, so a
+//!   server that never answers an open does not hold them forever.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use mio::Token;
+
+/// A client waiting in the queue.
+pub trait Waiting {
+    fn token(&self) -> Token;
+    /// Index of the forward it came in on.
+    fn forward(&self) -> usize;
+    /// When it joined the queue.
+    fn queued_at(&self) -> Instant;
+    /// Whether it already holds the channel its open started, and so has no
+    /// open in flight any more (the exec fallback's `session` channel, while
+    /// the connect command starts on it).
+    fn holds_channel(&self) -> bool;
+}
+
+/// What an open in flight is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Owner {
+    /// The client with this token, at the front of the queue.
+    Client(Token),
+    /// A channel to keep ready for this forward (`--preopen-channels`).
+    Preopen(usize),
+    /// A client that timed out waiting for it.
+    Abandoned,
+}
+
+/// How an open was started; continuing it repeats the same call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    DirectTcpip {
+        host: String,
+        port: u16,
+        originator: String,
+        originator_port: u16,
+    },
+    /// The exec fallback's `session` channel, then its connect command.
+    Session,
+}
+
+#[derive(Debug)]
+pub struct InFlight {
+    pub owner: Owner,
+    pub request: Request,
+    started: Instant,
+    /// Whether its owner has outlived the timeout, which is then not checked
+    /// again.
+    overdue: bool,
+}
+
+/// Who an open that completed, with a channel or an error, was for.
+pub enum Finished<T> {
+    /// This client, now off the queue.
+    Client(T),
+    Preopen(usize),
+    Abandoned,
+}
+
+/// An open that outlived the timeout, and is still in flight.
+pub enum Expired<T> {
+    /// This client gave up on it and is off the queue.
+    Client(T),
+    Preopen(usize),
+}
+
+pub struct OpenQueue<T> {
+    waiting: VecDeque<T>,
+    in_flight: Option<InFlight>,
+    timeout: Duration,
+}
+
+impl<T: Waiting> OpenQueue<T> {
+    /// Give up on a client once it has waited `timeout` for its channel.
+    pub const fn new(timeout: Duration) -> Self {
+        Self {
+            waiting: VecDeque::new(),
+            in_flight: None,
+            timeout,
+        }
+    }
+
+    /// Clients waiting, including the one whose open is in flight.
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn push(&mut self, client: T) {
+        self.waiting.push_back(client);
+    }
+
+    /// Queue `client` to start its open again, ahead of everyone else.
+    pub fn push_front(&mut self, client: T) {
+        self.waiting.push_front(client);
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.waiting.front()
+    }
+
+    pub fn front_mut(&mut self) -> Option<&mut T> {
+        self.waiting.front_mut()
+    }
+
+    pub const fn in_flight(&self) -> Option<&InFlight> {
+        self.in_flight.as_ref()
+    }
+
+    /// Start an open for the front client.
+    ///
+    /// ## Panics
+    /// if an open is in flight already, or no client is waiting
+    pub fn start_front(&mut self, request: Request, now: Instant) {
+        let token = self.waiting.front().expect("a client is waiting").token();
+        self.start(Owner::Client(token), request, now);
+    }
+
+    /// Start pre-opening a channel for `forward`.
+    ///
+    /// ## Panics
+    /// if an open is in flight already
+    pub fn start_preopen(&mut self, forward: usize, request: Request, now: Instant) {
+        self.start(Owner::Preopen(forward), request, now);
+    }
+
+    fn start(&mut self, owner: Owner, request: Request, now: Instant) {
+        assert!(
+            self.in_flight.is_none(),
+            "libssh2 has a channel open in flight already"
+        );
+        self.in_flight = Some(InFlight {
+            owner,
+            request,
+            started: now,
+            overdue: false,
+        });
+    }
+
+    /// The open in flight has completed.
+    ///
+    /// ## Panics
+    /// if no open is in flight
+    pub fn finish(&mut self) -> Finished<T> {
+        let in_flight = self.in_flight.take().expect("an open is in flight");
+        match in_flight.owner {
+            Owner::Client(token) => Finished::Client(self.take_front(token)),
+            Owner::Preopen(forward) => Finished::Preopen(forward),
+            Owner::Abandoned => Finished::Abandoned,
+        }
+    }
+
+    /// The open in flight, if its owner has outlived the timeout by `now`:
+    /// a client's is abandoned, and the client taken off the queue. A
+    /// pre-open's is reported once, `timeout` after it started.
+    pub fn expire(&mut self, now: Instant) -> Option<Expired<T>> {
+        let deadline = self.in_flight_deadline()?;
+        let in_flight = self.in_flight.as_mut()?;
+        if now < deadline {
+            return None;
+        }
+        in_flight.overdue = true;
+        match in_flight.owner {
+            Owner::Client(token) => {
+                in_flight.owner = Owner::Abandoned;
+                let client = self.take_front(token);
+                if client.holds_channel() {
+                    // Its open completed; the channel goes with it.
+                    self.in_flight = None;
+                }
+                Some(Expired::Client(client))
+            }
+            Owner::Preopen(forward) => Some(Expired::Preopen(forward)),
+            Owner::Abandoned => None,
+        }
+    }
+
+    /// A client for the channel just pre-opened for `forward`, if one is
+    /// waiting. It queued while the pre-open was in flight, so its own open
+    /// has not started.
+    ///
+    /// ## Panics
+    /// if an open is in flight
+    pub fn take_for(&mut self, forward: usize) -> Option<T> {
+        assert!(self.in_flight.is_none(), "an open is in flight");
+        let at = self
+            .waiting
+            .iter()
+            .position(|client| client.forward() == forward)?;
+        self.waiting.remove(at)
+    }
+
+    /// The clients whose open has not started, and who have waited
+    /// `timeout` by `now`, taken off the queue.
+    pub fn expire_waiting(&mut self, now: Instant) -> Vec<T> {
+        let started = self.started_for();
+        let mut expired = Vec::new();
+        let mut kept = VecDeque::with_capacity(self.waiting.len());
+        for client in self.waiting.drain(..) {
+            if Some(client.token()) != started && now >= client.queued_at() + self.timeout {
+                expired.push(client);
+            } else {
+                kept.push_back(client);
+            }
+        }
+        self.waiting = kept;
+        expired
+    }
+
+    /// When the next client or open in flight times out.
+    pub fn deadline(&self) -> Option<Instant> {
+        let started = self.started_for();
+        self.waiting
+            .iter()
+            .filter(|client| Some(client.token()) != started)
+            .map(|client| client.queued_at() + self.timeout)
+            .chain(self.in_flight_deadline())
+            .min()
+    }
+
+    /// When the owner of the open in flight times out, unless it has
+    /// already.
+    fn in_flight_deadline(&self) -> Option<Instant> {
+        let in_flight = self
+            .in_flight
+            .as_ref()
+            .filter(|in_flight| !in_flight.overdue)?;
+        match in_flight.owner {
+            Owner::Client(_) => Some(self.waiting.front()?.queued_at() + self.timeout),
+            Owner::Preopen(_) => Some(in_flight.started + self.timeout),
+            Owner::Abandoned => None,
+        }
+    }
+
+    /// The client the open in flight was started for, if any.
+    fn started_for(&self) -> Option<Token> {
+        match self.in_flight.as_ref()?.owner {
+            Owner::Client(token) => Some(token),
+            Owner::Preopen(_) | Owner::Abandoned => None,
+        }
+    }
+
+    fn take_front(&mut self, token: Token) -> T {
+        let client = self.waiting.pop_front().expect("the open's client waits");
+        assert_eq!(client.token(), token, "the open's client is at the front");
+        client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::LazyLock;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// When the tests' clients are queued, unless they say otherwise.
+    static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Client {
+        token: usize,
+        forward: usize,
+        queued_at: Instant,
+        holds_channel: bool,
+    }
+
+    impl Waiting for Client {
+        fn token(&self) -> Token {
+            Token(self.token)
+        }
+
+        fn forward(&self) -> usize {
+            self.forward
+        }
+
+        fn queued_at(&self) -> Instant {
+            self.queued_at
+        }
+
+        fn holds_channel(&self) -> bool {
+            self.holds_channel
+        }
+    }
+
+    fn client(token: usize, forward: usize) -> Client {
+        queued_at(token, forward, *START)
+    }
+
+    fn queued_at(token: usize, forward: usize, queued_at: Instant) -> Client {
+        Client {
+            token,
+            forward,
+            queued_at,
+            holds_channel: false,
+        }
+    }
+
+    fn request(host: &str) -> Request {
+        Request::DirectTcpip {
+            host: host.to_owned(),
+            port: 80,
+            originator: "192.0.2.1".to_owned(),
+            originator_port: 4000,
+        }
+    }
+
+    fn finished_client(finished: Finished<Client>) -> Option<usize> {
+        match finished {
+            Finished::Client(client) => Some(client.token),
+            Finished::Preopen(_) | Finished::Abandoned => None,
+        }
+    }
+
+    #[test]
+    fn an_open_goes_to_the_client_it_was_started_for() {
+        let now = *START;
+        let mut queue = OpenQueue::new(TIMEOUT);
+        queue.push(client(10, 0));
+        queue.start_front(request("a"), now);
+        queue.push(client(11, 1));
+        assert_eq!(finished_client(queue.finish()), Some(10));
+        assert_eq!(queue.front(), Some(&client(11, 1)));
+        assert!(queue.in_flight().is_none());
+    }
+
+    #[test]
+    fn an_abandoned_open_is_never_handed_to_another_client() {
+        let now = *START;
+        let later = now + TIMEOUT / 2;
+        let mut queue = OpenQueue::new(TIMEOUT);
+        queue.push(client(10, 0));
+        queue.push(queued_at(11, 1, later));
+        queue.start_front(request("a"), now);
+
+        assert!(queue.expire(now + TIMEOUT / 2).is_none());
+        let Some(Expired::Client(gone)) = queue.expire(now + TIMEOUT) else {
+            panic!("the front client should time out");
+        };
+        assert_eq!(gone.token, 10);
+
+        // The open is still in flight, for nobody, with its own request; the
+        // next client has not started one and must not.
+        let in_flight = queue.in_flight().unwrap();
+        assert_eq!(in_flight.owner, Owner::Abandoned);
+        assert_eq!(in_flight.request, request("a"));
+        assert!(queue.expire(now + TIMEOUT).is_none());
+        assert_eq!(queue.deadline(), Some(later + TIMEOUT));
+
+        assert!(matches!(queue.finish(), Finished::Abandoned));
+        assert_eq!(queue.front(), Some(&queued_at(11, 1, later)));
+
+        // Only now does the next client get an open of its own.
+        queue.start_front(request("b"), now + TIMEOUT);
+        assert_eq!(queue.in_flight().unwrap().owner, Owner::Client(Token(11)));
+        assert_eq!(finished_client(queue.finish()), Some(11));
+    }
+
+    #[test]
+    fn a_client_holding_its_channel_leaves_no_open_in_flight() {
+        let now = *START;
+        let mut queue = OpenQueue::new(TIMEOUT);
+        queue.push(client(10, 0));
+        queue.start_front(Request::Session, now);
+        queue.front_mut().unwrap().holds_channel = true;
+        assert!(matches!(
+            queue.expire(now + TIMEOUT),
+            Some(Expired::Client(Client { token: 10, .. }))
+        ));
+        assert!(queue.in_flight().is_none());
+    }
+
+    #[test]
+    fn queued_clients_wait_out_an_open_in_flight() {
+        let now = *START;
+        let later = now + TIMEOUT / 2;
+        let mut queue = OpenQueue::new(TIMEOUT);
+        queue.start_preopen(1, request("a"), now);
+        queue.push(queued_at(10, 0, later));
+        queue.push(queued_at(11, 1, later));
+        assert_eq!(queue.deadline(), Some(now + TIMEOUT));
+
+        // A slow pre-open is reported once, and keeps its place.
+        assert!(matches!(
+            queue.expire(now + TIMEOUT),
+            Some(Expired::Preopen(1))
+        ));
+        assert!(queue.expire(later + TIMEOUT).is_none());
+        assert!(queue.expire_waiting(now + TIMEOUT).is_empty());
+        assert_eq!(queue.deadline(), Some(later + TIMEOUT));
+
+        // Its channel may go to a client for the same forward.
+        assert!(matches!(queue.finish(), Finished::Preopen(1)));
+        assert_eq!(queue.take_for(1), Some(queued_at(11, 1, later)));
+        assert_eq!(queue.take_for(1), None);
+        assert_eq!(queue.front(), Some(&queued_at(10, 0, later)));
+    }
+
+    #[test]
+    fn queued_clients_time_out_behind_an_open_that_never_completes() {
+        let now = *START;
+        let later = now + TIMEOUT / 2;
+        let mut queue = OpenQueue::new(TIMEOUT);
+        queue.push(client(10, 0));
+        queue.push(queued_at(11, 0, later));
+        queue.start_front(request("a"), now);
+
+        // The front client's open is in flight, so only `expire` drops it.
+        assert!(queue.expire_waiting(now + TIMEOUT).is_empty());
+        assert!(matches!(
+            queue.expire(now + TIMEOUT),
+            Some(Expired::Client(Client { token: 10, .. }))
+        ));
+
+        // The server never answers: the client behind it still gives up.
+        let gone = queue.expire_waiting(later + TIMEOUT);
+        assert_eq!(gone, [queued_at(11, 0, later)]);
+        assert_eq!(queue.len(), 0);
+        assert_eq!(queue.deadline(), None);
+        assert_eq!(queue.in_flight().unwrap().owner, Owner::Abandoned);
+    }
+
+    #[test]
+    #[should_panic(expected = "in flight already")]
+    fn a_second_open_cannot_start() {
+        let now = *START;
+        let mut queue = OpenQueue::new(TIMEOUT);
+        queue.push(client(10, 0));
+        queue.start_preopen(0, request("a"), now);
+        queue.start_front(request("b"), now);
+    }
+}